@@ -0,0 +1,127 @@
+//! Reading and writing COBS frames over `std::io` streams.
+//!
+//! Enabled with the `std` feature. Each frame is encoded and followed by a
+//! zero byte, as with `Writer::encode_packet`.
+//!
+//! A stream that ends after a delimiter returns `Ok(None)`, while one that
+//! ends partway through a frame fails with `UnexpectedEof`. Frames that
+//! don't decode fail with `InvalidData`; the reader can carry on with the
+//! next frame after either error.
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::vec::Vec;
+
+pub struct Reader<R: Read> {
+    inner: BufReader<R>,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Reader { inner: BufReader::new(inner), buf: Vec::new() }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    /// Returns the inner reader. Anything read ahead of the last frame is
+    /// lost.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+
+    /// Reads and decodes the next frame. Empty frames, such as runs of
+    /// delimiters, are skipped.
+    pub fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            self.buf.clear();
+            if self.inner.read_until(0, &mut self.buf)? == 0 {
+                return Ok(None)
+            }
+            if self.buf.pop() != Some(0) {
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated COBS frame"))
+            }
+            if self.buf.is_empty() {
+                continue
+            }
+            let mut packet = vec![0u8; self.buf.len()];
+            let n = ::decode(&self.buf, &mut packet)?;
+            packet.truncate(n);
+            return Ok(Some(packet))
+        }
+    }
+}
+
+pub struct Writer<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Writer { inner, buf: Vec::new() }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Encodes and writes `src` followed by a delimiter, returning the
+    /// number of bytes written.
+    pub fn write_packet(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.buf.resize(src.len() + src.len() / 254 + 2, 0);
+        let n = ::Writer::new(&mut self.buf).encode_packet(src)?;
+        self.inner.write_all(&self.buf[..n])?;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let long: Vec<u8> = (0..600).map(|i| (i % 255) as u8 + 1).collect();
+        let mut w = Writer::new(Vec::new());
+        assert_eq!(w.write_packet(&[0x11, 0x22, 0x00, 0x33]).unwrap(), 6);
+        w.write_packet(&[]).unwrap();
+        w.write_packet(&long).unwrap();
+        let mut buf = w.into_inner();
+        assert_eq!(&buf[..6], &[0x03, 0x11, 0x22, 0x02, 0x33, 0x00]);
+        buf.splice(6..6, [0x00, 0x00].iter().cloned());
+
+        let mut r = Reader::new(&buf[..]);
+        assert_eq!(r.read_packet().unwrap(), Some(vec![0x11, 0x22, 0x00, 0x33]));
+        assert_eq!(r.read_packet().unwrap(), Some(vec![]));
+        assert_eq!(r.read_packet().unwrap(), Some(long));
+        assert_eq!(r.read_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let buf = [0x03, 0x11, 0x00, 0x02, 0x33, 0x00, 0x03, 0x11];
+        let mut r = Reader::new(&buf[..]);
+        assert_eq!(r.read_packet().unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(r.read_packet().unwrap(), Some(vec![0x33]));
+        assert_eq!(r.read_packet().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(r.read_packet().unwrap(), None);
+    }
+}
@@ -5,9 +5,21 @@
 //! Wikipedia: https://en.wikipedia.org/wiki/Consistent_Overhead_Byte_Stuffing
 //! See https://bitbucket.org/cmcqueen1975/cobs-c/wiki/Home
 
+#[cfg(feature = "embedded-io")]
+extern crate embedded_io;
+#[cfg(any(test, feature = "std"))]
+#[macro_use]
+extern crate std;
+
+use core::fmt;
+
 mod buffer;
 pub use buffer::Buffer;
 
+#[cfg(feature = "std")]
+pub mod io;
+#[cfg(feature = "embedded-io")]
+pub mod serial;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -17,6 +29,27 @@ pub enum Error {
     UnexpectedNull,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::InvalidEncoding => write!(f, "invalid encoding"),
+            Error::SourceTooShort => write!(f, "source too short"),
+            Error::DestTooShort => write!(f, "destination too short"),
+            Error::UnexpectedNull => write!(f, "unexpected null"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(other: Error) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, other)
+    }
+}
+
 /// Encodes a slice into dst, returning the number of dst bytes used. dst must be at least one byte longer than src.
 pub fn encode(src: &[u8], dst: &mut[u8]) -> Result<usize, Error> {
     let mut p = 0;
@@ -101,11 +134,14 @@ pub fn decode(src: &[u8], dst: &mut[u8]) -> Result<usize, Error> {
 pub struct Writer<'a> {
     buf: &'a mut [u8],
     pos: usize,
+    // Bytes already written out by `poll_flush`, see the serial module.
+    #[cfg_attr(not(feature = "embedded-io"), allow(dead_code))]
+    sent: usize,
 }
 
 impl<'a> Writer<'a> {
     pub fn new(buf: &'a mut [u8]) -> Self {
-        Writer { buf: buf, pos: 0 }
+        Writer { buf: buf, pos: 0, sent: 0 }
     }
 
     pub fn pos(&self) -> usize {
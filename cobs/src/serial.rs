@@ -0,0 +1,304 @@
+//! Writing and reading frames over `embedded_io` serial ports.
+//!
+//! Enabled with the `embedded-io` feature. `write_packet` encodes a frame
+//! straight to a port without a buffer, and `Writer` can hand its encoded
+//! frames to a port with `flush_to`, blocking until they're written, or
+//! with `poll_flush`, which only writes while `WriteReady` says the port
+//! can take more and picks up where it left off on the next call.
+//!
+//! `FrameReader` collects bytes from a port and returns each frame once its
+//! delimiter arrives. Its `poll` only reads while `ReadReady` says bytes are
+//! waiting, so it can be called from a main loop:
+//!
+//! ```ignore
+//! let mut frames = FrameReader::new(&mut rx_buf);
+//! loop {
+//!     while let Some(frame) = frames.poll(&mut uart, &mut frame_buf)? {
+//!         handle(frame);
+//!     }
+//!     tx.poll_flush(&mut uart)?;
+//!     // ... other work
+//! }
+//! ```
+//!
+//! As with a byte stream over a noisy link, frames that fail to decode or
+//! don't fit are skipped and counted rather than returned as errors.
+
+use embedded_io::{Read, ReadReady, Write, WriteReady};
+
+use {decode, Writer};
+
+// The longest run of data bytes in one block.
+const MAX_RUN: usize = 254;
+
+/// Encodes `src` as a frame directly to `serial`, followed by a delimiter,
+/// returning the number of bytes written. The output is the same as from
+/// `Writer::encode_packet`.
+pub fn write_packet<W: Write>(serial: &mut W, src: &[u8]) -> Result<usize, W::Error> {
+    let mut rest = src;
+    let mut len = 0;
+    loop {
+        let run = rest.iter().take(MAX_RUN).position(|&b| b == 0).unwrap_or_else(|| rest.len().min(MAX_RUN));
+        serial.write_all(&[run as u8 + 1])?;
+        serial.write_all(&rest[..run])?;
+        len += 1 + run;
+        if run == MAX_RUN {
+            // A full block isn't followed by a zero.
+            rest = &rest[run..];
+        } else if run < rest.len() {
+            rest = &rest[run + 1..];
+        } else {
+            break
+        }
+    }
+    serial.write_all(&[0])?;
+    Ok(len + 1)
+}
+
+impl<'a> Writer<'a> {
+    /// Writes all encoded frames to `serial` and starts over, returning the
+    /// number of bytes written.
+    pub fn flush_to<W: Write>(&mut self, serial: &mut W) -> Result<usize, W::Error> {
+        let len = self.pos - self.sent;
+        serial.write_all(&self.buf[self.sent..self.pos])?;
+        self.pos = 0;
+        self.sent = 0;
+        Ok(len)
+    }
+
+    /// Writes as much of the encoded frames to `serial` as it is ready for
+    /// without blocking. Returns `true`, and starts over, once everything
+    /// has been written. Frames encoded in the meantime are sent after the
+    /// ones before them.
+    pub fn poll_flush<W: Write + WriteReady>(&mut self, serial: &mut W) -> Result<bool, W::Error> {
+        while self.sent < self.pos {
+            if !serial.write_ready()? {
+                return Ok(false)
+            }
+            self.sent += serial.write(&self.buf[self.sent..self.pos])?;
+        }
+        self.pos = 0;
+        self.sent = 0;
+        Ok(true)
+    }
+}
+
+pub struct FrameReader<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    discarding: bool,
+    errors: usize,
+}
+
+impl<'a> FrameReader<'a> {
+    /// Creates a reader that collects bytes in `buf`. Encoded frames longer
+    /// than `buf` are skipped.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        FrameReader { buf, len: 0, discarding: false, errors: 0 }
+    }
+
+    /// Returns the number of frames skipped because they were too long or
+    /// failed to decode.
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    // Decodes the first complete frame in the buffer into `dst`, returning
+    // its length.
+    fn next_frame(&mut self, dst: &mut [u8]) -> Option<usize> {
+        loop {
+            let end = match self.buf[..self.len].iter().position(|&b| b == 0) {
+                Some(end) => end,
+                None => {
+                    // Drop an overlong frame as it arrives, up to its end.
+                    if self.len == self.buf.len() {
+                        if !self.discarding {
+                            self.errors += 1;
+                            self.discarding = true;
+                        }
+                        self.len = 0;
+                    }
+                    return None
+                }
+            };
+            let frame = if self.discarding {
+                self.discarding = false;
+                None
+            } else if end == 0 {
+                None
+            } else {
+                match decode(&self.buf[..end], dst) {
+                    Ok(n) => Some(n),
+                    Err(_) => {
+                        self.errors += 1;
+                        None
+                    }
+                }
+            };
+            self.buf.copy_within(end + 1..self.len, 0);
+            self.len -= end + 1;
+            if frame.is_some() {
+                return frame
+            }
+        }
+    }
+
+    /// Returns the next frame, decoded into `dst`, reading from `serial`
+    /// only while it has bytes ready. Returns `None` once it has none and
+    /// no complete frame is buffered.
+    pub fn poll<'b, R: Read + ReadReady>(&mut self, serial: &mut R, dst: &'b mut [u8]) -> Result<Option<&'b [u8]>, R::Error> {
+        loop {
+            if let Some(n) = self.next_frame(dst) {
+                return Ok(Some(&dst[..n]))
+            }
+            if !serial.read_ready()? {
+                return Ok(None)
+            }
+            let n = serial.read(&mut self.buf[self.len..])?;
+            if n == 0 {
+                return Ok(None)
+            }
+            self.len += n;
+        }
+    }
+
+    /// Returns the next frame, decoded into `dst`, blocking on `serial`
+    /// until one arrives. Returns `None` if `serial` reaches end of file.
+    pub fn read<'b, R: Read>(&mut self, serial: &mut R, dst: &'b mut [u8]) -> Result<Option<&'b [u8]>, R::Error> {
+        loop {
+            if let Some(n) = self.next_frame(dst) {
+                return Ok(Some(&dst[..n]))
+            }
+            let n = serial.read(&mut self.buf[self.len..])?;
+            if n == 0 {
+                return Ok(None)
+            }
+            self.len += n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+    use embedded_io::ErrorType;
+    use encode;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    // A serial port that delivers at most `chunk` bytes per read and
+    // accepts at most `room` bytes until more is made.
+    struct Serial {
+        rx: VecDeque<u8>,
+        tx: Vec<u8>,
+        chunk: usize,
+        room: usize,
+    }
+
+    impl Serial {
+        fn new(rx: &[u8]) -> Self {
+            Serial { rx: rx.iter().cloned().collect(), tx: Vec::new(), chunk: 3, room: usize::MAX }
+        }
+    }
+
+    impl ErrorType for Serial {
+        type Error = Infallible;
+    }
+
+    impl Read for Serial {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            let n = buf.len().min(self.chunk).min(self.rx.len());
+            for b in buf[..n].iter_mut() {
+                *b = self.rx.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    impl ReadReady for Serial {
+        fn read_ready(&mut self) -> Result<bool, Infallible> {
+            Ok(!self.rx.is_empty())
+        }
+    }
+
+    impl Write for Serial {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            let n = buf.len().min(self.room);
+            assert!(n > 0 || buf.is_empty(), "write would block");
+            self.tx.extend_from_slice(&buf[..n]);
+            self.room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    impl WriteReady for Serial {
+        fn write_ready(&mut self) -> Result<bool, Infallible> {
+            Ok(self.room > 0)
+        }
+    }
+
+    #[test]
+    fn test_write_packet() {
+        let mut src = [0x11u8; 600];
+        for i in (0..600).step_by(97) {
+            src[i] = 0x00;
+        }
+        for &len in [0, 1, 97, 98, 253, 254, 255, 508, 600].iter() {
+            for src in [&src[..len], &[0x22; 600][..len], &[0x00; 600][..len]].iter() {
+                let mut expected = [0u8; 610];
+                let n = encode(src, &mut expected).unwrap();
+                let mut serial = Serial::new(&[]);
+                assert_eq!(write_packet(&mut serial, src).unwrap(), n + 1);
+                assert_eq!(&serial.tx[..n], &expected[..n], "length {}", len);
+                assert_eq!(serial.tx[n], 0x00);
+            }
+        }
+    }
+
+    #[test]
+    fn test_poll_flush() {
+        let mut buf = [0u8; 64];
+        let mut w = Writer::new(&mut buf);
+        w.encode_packet(&[0x11, 0x22, 0x00, 0x33]).unwrap();
+        let mut serial = Serial::new(&[]);
+        serial.room = 4;
+        assert_eq!(w.poll_flush(&mut serial), Ok(false));
+        assert_eq!(w.poll_flush(&mut serial), Ok(false));
+        w.encode_packet(&[0x44]).unwrap();
+        serial.room = 5;
+        assert_eq!(w.poll_flush(&mut serial), Ok(true));
+        assert_eq!(w.pos(), 0);
+        assert_eq!(serial.tx, [0x03, 0x11, 0x22, 0x02, 0x33, 0x00, 0x02, 0x44, 0x00]);
+
+        serial.room = usize::MAX;
+        w.encode_packet(&[0x55]).unwrap();
+        assert_eq!(w.flush_to(&mut serial), Ok(3));
+        assert_eq!(&serial.tx[9..], &[0x02, 0x55, 0x00]);
+    }
+
+    #[test]
+    fn test_poll() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&[0x00, 0x03, 0x11, 0x22, 0x02, 0x33, 0x00]);
+        stream.extend_from_slice(&[0x05; 20]);
+        stream.extend_from_slice(&[0x00, 0x03, 0x11, 0x00, 0x02, 0x44, 0x00, 0x02]);
+        let mut serial = Serial::new(&stream);
+        let mut buf = [0u8; 16];
+        let mut frames = FrameReader::new(&mut buf);
+        let mut dst = [0u8; 16];
+        assert_eq!(frames.poll(&mut serial, &mut dst), Ok(Some(&[0x11, 0x22, 0x00, 0x33][..])));
+        // The overlong and the broken frame are skipped.
+        assert_eq!(frames.poll(&mut serial, &mut dst), Ok(Some(&[0x44][..])));
+        assert_eq!(frames.errors(), 2);
+        // Nothing waiting, and half a frame buffered.
+        assert_eq!(frames.poll(&mut serial, &mut dst), Ok(None));
+        serial.rx.extend([0x55, 0x00].iter());
+        assert_eq!(frames.read(&mut serial, &mut dst), Ok(Some(&[0x55][..])));
+        assert_eq!(frames.read(&mut serial, &mut dst), Ok(None));
+    }
+}
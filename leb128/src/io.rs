@@ -0,0 +1,168 @@
+//! Reading and writing LEB128 values over `std::io` streams.
+//!
+//! Enabled with the `std` feature. These read a value a byte at a time, so
+//! wrap unbuffered sources such as files in a `BufReader`.
+//!
+//! A stream that ends before a value starts returns `Ok(None)`, while one
+//! that ends partway through a value fails with `UnexpectedEof`. Values too
+//! large for their type fail with `InvalidData`.
+
+use std::io::{self, ErrorKind, Read, Write};
+
+use Error;
+
+// The longest encoding of a 32 bit value.
+const MAX_LEN: usize = 5;
+
+pub struct Reader<R: Read> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Reader { inner }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut b = [0u8; 1];
+        loop {
+            match self.inner.read(&mut b) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(b[0])),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Reads the bytes of one value into `buf`, returning its length.
+    fn read_value(&mut self, buf: &mut [u8; MAX_LEN]) -> io::Result<Option<usize>> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            match self.read_byte()? {
+                Some(b) => {
+                    *byte = b;
+                    if b & 0b1000_0000 == 0 {
+                        return Ok(Some(i + 1))
+                    }
+                }
+                None if i == 0 => return Ok(None),
+                None => return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated LEB128 value")),
+            }
+        }
+        Err(Error::OutOfRange.into())
+    }
+
+    pub fn read_u32(&mut self) -> io::Result<Option<u32>> {
+        let mut buf = [0u8; MAX_LEN];
+        match self.read_value(&mut buf)? {
+            Some(n) => Ok(::Reader::new(&buf[..n]).read_u32()?),
+            None => Ok(None),
+        }
+    }
+
+    pub fn read_i32(&mut self) -> io::Result<Option<i32>> {
+        let mut buf = [0u8; MAX_LEN];
+        match self.read_value(&mut buf)? {
+            Some(n) => Ok(::Reader::new(&buf[..n]).read_i32()?),
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct Writer<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Writer { inner }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    /// Writes `value`, returning the number of bytes written.
+    pub fn write_u32(&mut self, value: u32) -> io::Result<usize> {
+        let mut buf = [0u8; MAX_LEN];
+        let len = {
+            let mut w = ::Writer::new(&mut buf);
+            w.write_u32(value)?;
+            w.pos()
+        };
+        self.inner.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    /// Writes `value`, returning the number of bytes written.
+    pub fn write_i32(&mut self, value: i32) -> io::Result<usize> {
+        let mut buf = [0u8; MAX_LEN];
+        let len = {
+            let mut w = ::Writer::new(&mut buf);
+            w.write_i32(value)?;
+            w.pos()
+        };
+        self.inner.write_all(&buf[..len])?;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_round_trip() {
+        let mut w = Writer::new(Vec::new());
+        assert_eq!(w.write_u32(0).unwrap(), 1);
+        assert_eq!(w.write_u32(624485).unwrap(), 3);
+        assert_eq!(w.write_u32(u32::MAX).unwrap(), 5);
+        assert_eq!(w.write_i32(-123456).unwrap(), 3);
+        assert_eq!(w.write_i32(i32::MIN).unwrap(), 5);
+        let buf = w.into_inner();
+        assert_eq!(&buf[1..4], &[0xe5, 0x8e, 0x26]);
+
+        let mut r = Reader::new(&buf[..]);
+        assert_eq!(r.read_u32().unwrap(), Some(0));
+        assert_eq!(r.read_u32().unwrap(), Some(624485));
+        assert_eq!(r.read_u32().unwrap(), Some(u32::MAX));
+        assert_eq!(r.read_i32().unwrap(), Some(-123456));
+        assert_eq!(r.read_i32().unwrap(), Some(i32::MIN));
+        assert_eq!(r.read_u32().unwrap(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut r = Reader::new(&[0xe5, 0x8e][..]);
+        assert_eq!(r.read_u32().unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        let mut r = Reader::new(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x01][..]);
+        assert_eq!(r.read_u32().unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut r = Reader::new(&[0xff, 0xff, 0xff, 0xff, 0x1f][..]);
+        assert_eq!(r.read_u32().unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
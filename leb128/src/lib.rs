@@ -1,14 +1,40 @@
 #![no_std]
 #![feature(core_intrinsics)]
 
+#[cfg(feature = "std")]
+extern crate std;
+
+use core::fmt;
 use core::intrinsics::ctlz;
 
+#[cfg(feature = "std")]
+pub mod io;
+
 #[derive(Debug, PartialEq)]
 pub enum Error {
     BufferTooShort,
     OutOfRange,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BufferTooShort => write!(f, "buffer too short"),
+            Error::OutOfRange => write!(f, "value out of range"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(other: Error) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, other)
+    }
+}
+
 pub struct Reader<'a> {
     buf: &'a [u8],
     pos: usize,
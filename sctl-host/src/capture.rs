@@ -0,0 +1,413 @@
+//! Recording and replaying sctl sessions.
+//!
+//! A capture file starts with a 24 byte header:
+//!
+//! | offset | size | contents                                        |
+//! |--------|------|-------------------------------------------------|
+//! | 0      | 8    | magic, `SCTLCAP\0`                              |
+//! | 8      | 2    | format version, currently 1                     |
+//! | 10     | 6    | reserved, zero                                  |
+//! | 16     | 8    | start of the capture, microseconds since the    |
+//! |        |      | Unix epoch                                      |
+//!
+//! followed by one record per frame:
+//!
+//! | size | contents                                               |
+//! |------|--------------------------------------------------------|
+//! | 1    | direction, 0 from the device, 1 to the device          |
+//! | 8    | microseconds since the start of the capture            |
+//! | 4    | length of the frame                                    |
+//! | n    | the frame as sent on the wire, COBS encoded with its   |
+//! |      | trailing delimiter                                     |
+//!
+//! All integers are little-endian.
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use cobs;
+use sctl::{self, Message};
+
+use frame::{self, MessageReader};
+use time;
+
+pub const MAGIC: &[u8; 8] = b"SCTLCAP\0";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    FromDevice = 0,
+    ToDevice = 1,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub direction: Direction,
+    /// Time since the start of the capture.
+    pub time: Duration,
+    pub frame: Vec<u8>,
+}
+
+impl Record {
+    /// Returns the decoded contents of the frame, decompressed if need be.
+    pub fn decode(&self) -> Result<Vec<u8>, frame::Error> {
+        let src = match self.frame.split_last() {
+            Some((&0, src)) => src,
+            _ => &self.frame[..],
+        };
+        let mut out = vec![0u8; src.len()];
+        let n = cobs::decode(src, &mut out).map_err(sctl::Error::from)?;
+        out.truncate(n);
+        frame::unpack(out)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + d.subsec_micros() as u64
+}
+
+pub struct CaptureWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(mut out: W, start: SystemTime) -> io::Result<Self> {
+        out.write_all(MAGIC)?;
+        out.write_all(&VERSION.to_le_bytes())?;
+        out.write_all(&[0; 6])?;
+        let start = micros(start.duration_since(UNIX_EPOCH).unwrap_or_default());
+        out.write_all(&start.to_le_bytes())?;
+        Ok(CaptureWriter { out })
+    }
+
+    pub fn write_record(&mut self, direction: Direction, time: Duration, frame: &[u8]) -> io::Result<()> {
+        self.out.write_all(&[direction as u8])?;
+        self.out.write_all(&micros(time).to_le_bytes())?;
+        self.out.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.out.write_all(frame)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+pub struct CaptureReader<R: Read> {
+    inner: R,
+    start: SystemTime,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; 24];
+        inner.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not an sctl capture"))
+        }
+        if u16::from_le_bytes([header[8], header[9]]) != VERSION {
+            return Err(invalid("unsupported capture version"))
+        }
+        let mut start = [0u8; 8];
+        start.copy_from_slice(&header[16..]);
+        let start = UNIX_EPOCH + Duration::from_micros(u64::from_le_bytes(start));
+        Ok(CaptureReader { inner, start })
+    }
+
+    /// Returns the wall-clock time the capture started.
+    pub fn start(&self) -> SystemTime {
+        self.start
+    }
+
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut head = [0u8; 13];
+        match self.inner.read(&mut head[..1])? {
+            0 => return Ok(None),
+            _ => self.inner.read_exact(&mut head[1..])?,
+        }
+        let direction = match head[0] {
+            0 => Direction::FromDevice,
+            1 => Direction::ToDevice,
+            _ => return Err(invalid("bad direction")),
+        };
+        let mut time = [0u8; 8];
+        time.copy_from_slice(&head[1..9]);
+        let mut len = [0u8; 4];
+        len.copy_from_slice(&head[9..]);
+        // The length comes from the file, so read what is there rather than
+        // allocating it up front.
+        let len = u32::from_le_bytes(len) as usize;
+        let mut frame = Vec::new();
+        self.inner.by_ref().take(len as u64).read_to_end(&mut frame)?;
+        if frame.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated capture record"))
+        }
+        Ok(Some(Record { direction, time: Duration::from_micros(u64::from_le_bytes(time)), frame }))
+    }
+}
+
+/// Wraps a byte source, sink or both, such as a serial port, and records
+/// every frame passing through it. Bytes read are recorded as coming from
+/// the device and bytes written as going to it.
+pub struct Recorder<T, W: Write> {
+    inner: T,
+    capture: CaptureWriter<W>,
+    start: Instant,
+    pending: [Vec<u8>; 2],
+}
+
+impl<T, W: Write> Recorder<T, W> {
+    pub fn new(inner: T, capture: CaptureWriter<W>) -> Self {
+        Recorder { inner, capture, start: Instant::now(), pending: [Vec::new(), Vec::new()] }
+    }
+
+    pub fn into_inner(self) -> (T, CaptureWriter<W>) {
+        (self.inner, self.capture)
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let time = self.start.elapsed();
+        for &b in data {
+            let pending = &mut self.pending[direction as usize];
+            pending.push(b);
+            if b == 0 {
+                self.capture.write_record(direction, time, pending)?;
+                pending.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Read, W: Write> Read for Recorder<T, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.record(Direction::FromDevice, &buf[..n])?;
+        Ok(n)
+    }
+}
+
+impl<T: Write, W: Write> Write for Recorder<T, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record(Direction::ToDevice, &buf[..n])?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.capture.flush()
+    }
+}
+
+/// Feeds the frames of a capture through the sctl decoder.
+pub struct Replayer<R: Read> {
+    reader: CaptureReader<R>,
+    speed: Option<f64>,
+    sleep: fn(Duration),
+    report: fn(&Record, frame::Error),
+    errors: usize,
+}
+
+// Reports a frame or message that failed to decode on stderr.
+fn report(record: &Record, e: frame::Error) {
+    eprintln!("sctl: {:?} frame at {}: {}", record.direction, time::format_secs(record.time), e);
+}
+
+impl<R: Read> Replayer<R> {
+    /// Creates a replayer that runs `speed` times faster than the original
+    /// session, or as fast as possible without a speed. `speed` must be
+    /// finite and greater than zero.
+    pub fn new(reader: CaptureReader<R>, speed: Option<f64>) -> Self {
+        Replayer { reader, speed, sleep: thread::sleep, report, errors: 0 }
+    }
+
+    /// Replaces `thread::sleep` for pacing the replay.
+    pub fn with_sleep(self, sleep: fn(Duration)) -> Self {
+        Replayer { sleep, ..self }
+    }
+
+    /// Replaces reporting frames and messages that fail to decode on
+    /// stderr.
+    pub fn with_report(self, report: fn(&Record, frame::Error)) -> Self {
+        Replayer { report, ..self }
+    }
+
+    pub fn start(&self) -> SystemTime {
+        self.reader.start()
+    }
+
+    /// Returns the number of frames and messages that failed to decode.
+    /// Each is reported as it is skipped.
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+
+    /// Calls `f` with every message in the capture, along with its record
+    /// and the device timestamp attached to it.
+    pub fn run<F>(&mut self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&Record, &Message, Option<u32>) -> io::Result<()>,
+    {
+        let mut last = Duration::from_secs(0);
+        let mut messages = MessageReader::new();
+        while let Some(record) = self.reader.next_record()? {
+            if let Some(speed) = self.speed {
+                if record.time > last {
+                    (self.sleep)((record.time - last).div_f64(speed));
+                }
+            }
+            last = record.time;
+            let frame = match record.decode() {
+                Ok(frame) => frame,
+                Err(e) => {
+                    (self.report)(&record, e);
+                    self.errors += 1;
+                    continue
+                }
+            };
+            if let Err(e) = messages.read(&frame, |msg, _, ticks| f(&record, msg, ticks))? {
+                (self.report)(&record, e);
+                self.errors += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sctl::Writer;
+    use std::cell::RefCell;
+
+    // A device that sends `rx` and swallows everything written to it.
+    struct Device {
+        rx: io::Cursor<Vec<u8>>,
+        tx: Vec<u8>,
+    }
+
+    impl Read for Device {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.rx.read(buf)
+        }
+    }
+
+    impl Write for Device {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(f: fn(&mut Writer)) -> Vec<u8> {
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        f(&mut w);
+        let mut out = [0u8; 80];
+        w.encode(&mut out).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_recorder() {
+        let device: Vec<u8> = [frame(|w| { w.boot(b"").unwrap(); }), frame(|w| { w.stdout(b"hi").unwrap(); })].concat();
+        let start = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let device_io = Device { rx: io::Cursor::new(device.clone()), tx: Vec::new() };
+        let mut rec = Recorder::new(device_io, CaptureWriter::new(Vec::new(), start).unwrap());
+        let stdin = frame(|w| { w.stdin(b"k").unwrap(); });
+        let mut stdin = stdin.iter().cycle();
+        let mut buf = [0u8; 3];
+        let mut read = Vec::new();
+        loop {
+            let n = rec.read(&mut buf).unwrap();
+            if n == 0 {
+                break
+            }
+            read.extend_from_slice(&buf[..n]);
+            let chunk: Vec<u8> = stdin.by_ref().take(2).cloned().collect();
+            rec.write_all(&chunk).unwrap();
+        }
+        assert_eq!(read, device);
+        let (device_io, capture) = rec.into_inner();
+        assert_eq!(device_io.tx.len(), 8);
+        let capture = capture.into_inner();
+
+        let mut r = CaptureReader::new(&capture[..]).unwrap();
+        assert_eq!(r.start(), start);
+        let records: Vec<Record> = ::std::iter::from_fn(|| r.next_record().unwrap()).collect();
+        let summary: Vec<(Direction, usize)> = records.iter().map(|r| (r.direction, r.frame.len())).collect();
+        // Stdin frames are five bytes, written two at a time.
+        assert_eq!(summary[..2], [(Direction::FromDevice, 4), (Direction::ToDevice, 5)]);
+        assert_eq!(records.iter().filter(|r| r.direction == Direction::FromDevice).count(), 2);
+        assert!(records.windows(2).all(|w| w[0].time <= w[1].time));
+
+        // A record claiming more than the file holds.
+        let mut capture = CaptureWriter::new(Vec::new(), start).unwrap().into_inner();
+        capture.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0x01, 0x00]);
+        let mut r = CaptureReader::new(&capture[..]).unwrap();
+        assert_eq!(r.next_record().unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert!(CaptureReader::new(&b"SCTLCAP\0\x02\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0"[..]).is_err());
+    }
+
+    thread_local!(static SLEPT: RefCell<Vec<Duration>> = const { RefCell::new(Vec::new()) });
+    thread_local!(static REPORTED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) });
+
+    fn fake_sleep(d: Duration) {
+        SLEPT.with(|s| s.borrow_mut().push(d));
+    }
+
+    fn fake_report(record: &Record, e: frame::Error) {
+        REPORTED.with(|r| r.borrow_mut().push(format!("{:?} {}", record.time, e)));
+    }
+
+    #[test]
+    fn test_replayer() {
+        let mut capture = CaptureWriter::new(Vec::new(), UNIX_EPOCH).unwrap();
+        capture.write_record(Direction::FromDevice, Duration::from_millis(0), &frame(|w| { w.boot(b"").unwrap(); })).unwrap();
+        capture.write_record(Direction::ToDevice, Duration::from_millis(100), &frame(|w| { w.stdin(b"x").unwrap(); })).unwrap();
+        capture.write_record(Direction::FromDevice, Duration::from_millis(300), &[0x05, 0x01, 0x00]).unwrap();
+        capture.write_record(Direction::FromDevice, Duration::from_millis(300), &[0x03, 0x7e, 0x01, 0x00]).unwrap();
+        capture.write_record(Direction::FromDevice, Duration::from_millis(400), &[0x02, 0x03, 0x01, 0x00]).unwrap();
+        capture.write_record(Direction::FromDevice, Duration::from_millis(500), &frame(|w| {
+            w.time(42).unwrap();
+            w.info(b"a").unwrap();
+            w.exit(0).unwrap();
+        })).unwrap();
+        let capture = capture.into_inner();
+
+        let mut replayer = Replayer::new(CaptureReader::new(&capture[..]).unwrap(), Some(10.0)).with_sleep(fake_sleep).with_report(fake_report);
+        let mut seen = Vec::new();
+        replayer.run(|record, msg, ticks| {
+            seen.push(format!("{:?} {:?} {:?} {:?}", record.time, record.direction, msg, ticks));
+            Ok(())
+        }).unwrap();
+        assert_eq!(seen, vec![
+            "0ns FromDevice Boot([]) None",
+            "100ms ToDevice Stdin([120]) None",
+            "500ms FromDevice Info([97]) Some(42)",
+            "500ms FromDevice Exit(0) None",
+        ]);
+        assert_eq!(replayer.errors(), 3);
+        REPORTED.with(|r| {
+            assert_eq!(*r.borrow(), vec![
+                "300ms bad COBS frame: SourceTooShort",
+                "300ms sealed frame, the link is secured",
+                "400ms invalid record",
+            ]);
+        });
+        SLEPT.with(|s| {
+            assert_eq!(*s.borrow(), vec![Duration::from_millis(10), Duration::from_millis(20), Duration::from_millis(10), Duration::from_millis(10)]);
+        });
+    }
+}
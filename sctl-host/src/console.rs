@@ -0,0 +1,242 @@
+//! Human-readable rendering of sctl messages.
+//!
+//! Log messages with a device timestamp are prefixed with the time since
+//! boot and the corresponding wall-clock time, e.g.
+//!
+//! ```text
+//! [     1.500000 2026-10-18 11:24:06.500000] INFO  sensor ready
+//! ```
+//!
+//! Wall-clock times are derived from when the host received `Boot`, so
+//! they are only as accurate as the link latency at boot.
+//!
+//! `Deferred` log messages are expanded with the console's `StringTable`.
+//!
+//! The `Hello` in a device's `Boot` is negotiated against `host_hello`, and
+//! the resulting session parameters are shown after it.
+
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use sctl::hello::{self, Features};
+use sctl::{deferred, ExceptionRecord, Hello, Message, PanicRecord, Params};
+
+use strings::StringTable;
+use time::{self, Timeline};
+
+/// Describes what the host tools support.
+pub fn host_hello() -> Hello<'static> {
+    Hello {
+        version: hello::VERSION,
+        device_id: &[],
+        firmware: env!("CARGO_PKG_VERSION"),
+        build: &[],
+        max_frame: hello::MAX_FRAME,
+        features: Features::SEQ.union(Features::COMPRESS),
+        tick_rate: 0,
+        nonce: &[],
+    }
+}
+
+pub struct Console<W: Write> {
+    out: W,
+    timeline: Timeline,
+    strings: StringTable,
+    params: Option<Params>,
+}
+
+impl<W: Write> Console<W> {
+    pub fn new(out: W) -> Self {
+        Console::with_strings(out, StringTable::new())
+    }
+
+    pub fn with_strings(out: W, strings: StringTable) -> Self {
+        Console { out, timeline: Timeline::new(), strings, params: None }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Returns the parameters negotiated with the device at its last boot.
+    pub fn params(&self) -> Option<Params> {
+        self.params
+    }
+
+    /// Renders a message read at `now`, along with the timestamp the
+    /// `Reader` returned for it.
+    pub fn message(&mut self, msg: &Message, ticks: Option<u32>, now: SystemTime) -> io::Result<()> {
+        match *msg {
+            Message::Boot(value) => {
+                let hello = Hello::decode(value).ok();
+                let tick_rate = hello.as_ref().map_or(0, |h| h.tick_rate);
+                self.timeline.boot(tick_rate, ticks, now);
+                self.params = None;
+                match hello {
+                    Some(hello) => {
+                        writeln!(self.out, "boot: {}", hello)?;
+                        match host_hello().negotiate(&hello) {
+                            Ok(params) => {
+                                self.params = Some(params);
+                                writeln!(self.out, "session: {}", params)
+                            }
+                            Err(_) => writeln!(self.out, "session: incompatible"),
+                        }
+                    }
+                    None => writeln!(self.out, "boot"),
+                }
+            }
+            Message::Run(value) => writeln!(self.out, "run: {}", String::from_utf8_lossy(value)),
+            Message::Exit(code) => writeln!(self.out, "exit: {}", code),
+            Message::Stdout(value) | Message::Stderr(value) => self.out.write_all(value),
+            Message::Panic(value) => match PanicRecord::decode(value) {
+                Ok(rec) => writeln!(self.out, "{}", rec),
+                Err(_) => writeln!(self.out, "panic: {}", String::from_utf8_lossy(value)),
+            },
+            Message::Exception(value) => match ExceptionRecord::decode(value) {
+                Ok(rec) => write!(self.out, "{}", rec),
+                Err(_) => writeln!(self.out, "exception: {:?}", value),
+            },
+            Message::Error(value) => self.log(ticks, "ERROR", value),
+            Message::Warn(value) => self.log(ticks, "WARN", value),
+            Message::Info(value) => self.log(ticks, "INFO", value),
+            Message::Debug(value) => self.log(ticks, "DEBUG", value),
+            Message::Trace(value) => self.log(ticks, "TRACE", value),
+            Message::Deferred(value) => self.deferred(ticks, value),
+            _ => Ok(()),
+        }
+    }
+
+    fn deferred(&mut self, ticks: Option<u32>, value: &[u8]) -> io::Result<()> {
+        let (level, text) = match deferred::decode(value) {
+            Ok((level, id, mut args)) => {
+                let text = match self.strings.format(id, &mut args) {
+                    Ok(text) => text,
+                    Err(e) => format!("<format 0x{:08x}: {:?}>", id, e),
+                };
+                (level, text)
+            }
+            Err(e) => (0, format!("<deferred: {:?}>", e)),
+        };
+        let level = match level {
+            0x20 => "ERROR",
+            0x21 => "WARN",
+            0x22 => "INFO",
+            0x23 => "DEBUG",
+            0x24 => "TRACE",
+            _ => "?",
+        };
+        self.log(ticks, level, text.as_bytes())
+    }
+
+    fn log(&mut self, ticks: Option<u32>, level: &str, text: &[u8]) -> io::Result<()> {
+        if let Some(stamp) = ticks.and_then(|t| self.timeline.stamp(t)) {
+            write!(self.out, "[{:>13} {}] ", time::format_secs(stamp.since_boot), time::format_utc(stamp.wall))?;
+        }
+        writeln!(self.out, "{:<5} {}", level, String::from_utf8_lossy(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sctl::deferred::{Arg, Encoder};
+    use sctl::Tag;
+    use frame::MessageReader;
+    use sctl::{Reader, Writer};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_console() {
+        let hello = Hello {
+            version: 1,
+            device_id: &[0x42],
+            firmware: "0.1.0",
+            build: &[0xab],
+            max_frame: 256,
+            features: Features::ARQ.union(Features::SEQ),
+            tick_rate: 1000,
+            nonce: &[],
+        };
+        let mut hbuf = [0u8; 64];
+        let mut wbuf = [0u8; 256];
+        let mut w = Writer::new(&mut wbuf);
+        w.time(5000).unwrap();
+        w.boot(hello.encode(&mut hbuf).unwrap()).unwrap();
+        w.run(b"blinky").unwrap();
+        w.time(6500).unwrap();
+        w.info(b"sensor ready").unwrap();
+        w.warn(b"no timestamp").unwrap();
+        w.stdout(b"raw output\n").unwrap();
+        w.time(0x1_0000).unwrap();
+        w.error(b"overheat").unwrap();
+        w.exit(1).unwrap();
+
+        let boot = UNIX_EPOCH + Duration::new(1_792_322_645, 0);
+        let mut console = Console::new(Vec::new());
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 256];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            console.message(&msg, r.time(), boot).unwrap();
+        }
+        assert_eq!(console.params().map(|p| p.features), Some(Features::SEQ));
+        assert_eq!(String::from_utf8(console.into_inner()).unwrap(), "\
+boot: sctl v1 device 42 firmware 0.1.0 build ab max frame 256 features arq,seq
+session: sctl v1 max frame 256 features seq
+run: blinky
+[     1.500000 2026-10-18 11:24:06.500000] INFO  sensor ready
+WARN  no timestamp
+raw output
+[    60.536000 2026-10-18 11:25:05.536000] ERROR overheat
+exit: 1
+");
+    }
+
+    #[test]
+    fn test_console_deferred() {
+        let mut strings = StringTable::new();
+        strings.insert(0x10, "adc {=u32} = {=i32}");
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        for &(id, level) in &[(0x10, Tag::Debug), (0x11, Tag::Info)] {
+            let mut buf = [0u8; 16];
+            let mut e = Encoder::new(&mut buf, level, id).unwrap();
+            3u8.encode(&mut e).unwrap();
+            (-12i32).encode(&mut e).unwrap();
+            w.deferred(e.finish()).unwrap();
+        }
+
+        let mut console = Console::with_strings(Vec::new(), strings);
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 64];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            console.message(&msg, r.time(), UNIX_EPOCH).unwrap();
+        }
+        assert_eq!(String::from_utf8(console.into_inner()).unwrap(), "\
+DEBUG adc 3 = -12
+INFO  <format 0x00000011: UnknownId(17)>
+");
+    }
+
+    #[test]
+    fn test_console_long() {
+        // Values over 255 bytes arrive fragmented and are shown whole.
+        let line = format!("{}\n", "x".repeat(399));
+        let file = format!("src/{}.rs", "f".repeat(100));
+        let rec = PanicRecord { file: &file, line: 3, column: 9, message: &"y".repeat(200) };
+        let mut pbuf = [0u8; 512];
+        let mut wbuf = [0u8; 2048];
+        let mut w = Writer::new(&mut wbuf);
+        w.time(1500).unwrap();
+        w.info("z".repeat(280).as_bytes()).unwrap();
+        w.stdout(line.as_bytes()).unwrap();
+        w.panic(rec.encode(&mut pbuf).unwrap()).unwrap();
+
+        let mut console = Console::new(Vec::new());
+        let mut messages = MessageReader::new();
+        let result = messages.read(w.as_ref(), |msg, _, ticks| console.message(msg, ticks, UNIX_EPOCH)).unwrap();
+        assert_eq!(result, Ok(()));
+        assert_eq!(String::from_utf8(console.into_inner()).unwrap(),
+            format!("INFO  {}\n{}panicked at {}:3:9:\n{}\n", "z".repeat(280), line, file, "y".repeat(200)));
+    }
+}
@@ -0,0 +1,260 @@
+//! Splitting a byte stream into COBS frames, and frames into messages.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use cobs;
+use sctl::fragment::{self, Reassembler};
+use sctl::{self, compress, Message, Reader, Tag};
+
+/// Why a frame or a message in it couldn't be read.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Decode(sctl::Error),
+    /// The frame is sealed, see `sctl::secure`, and the host has no key.
+    Sealed,
+}
+
+impl From<sctl::Error> for Error {
+    fn from(other: sctl::Error) -> Error {
+        Error::Decode(other)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Decode(ref e) => write!(f, "{}", e),
+            Error::Sealed => write!(f, "sealed frame, the link is secured"),
+        }
+    }
+}
+
+/// Decompresses a decoded frame if it is compressed, see `sctl::compress`.
+/// Sealed frames are rejected.
+pub fn unpack(frame: Vec<u8>) -> Result<Vec<u8>, Error> {
+    match frame.first() {
+        Some(&b) if b == Tag::Sealed as u8 => return Err(Error::Sealed),
+        Some(&b) if b == Tag::Compressed as u8 => {}
+        _ => return Ok(frame),
+    }
+    let mut buf = vec![0u8; compress::max_unpacked(frame.len())];
+    let n = compress::unpack(&frame, &mut buf)?.len();
+    buf.truncate(n);
+    Ok(buf)
+}
+
+pub struct FrameReader<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    pos: usize,
+    eof: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub fn new(inner: R) -> Self {
+        FrameReader { inner, buf: Vec::new(), pos: 0, eof: false }
+    }
+
+    /// Returns the underlying stream, e.g. to write requests to a device.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Returns the next decoded frame, decompressed if need be, or `None` at
+    /// the end of the stream. A frame that fails to decode is returned as an
+    /// error without ending the stream; bytes after the last delimiter are
+    /// discarded.
+    pub fn next_frame(&mut self) -> io::Result<Option<Result<Vec<u8>, Error>>> {
+        loop {
+            if let Some(end) = self.buf[self.pos..].iter().position(|&b| b == 0) {
+                let src = &self.buf[self.pos..self.pos + end];
+                self.pos += end + 1;
+                if src.is_empty() {
+                    continue
+                }
+                let mut frame = vec![0u8; src.len()];
+                return Ok(Some(match cobs::decode(src, &mut frame) {
+                    Ok(n) => {
+                        frame.truncate(n);
+                        unpack(frame)
+                    }
+                    Err(e) => Err(sctl::Error::from(e).into()),
+                }))
+            }
+            if self.eof {
+                return Ok(None)
+            }
+            self.buf.drain(..self.pos);
+            self.pos = 0;
+            let mut tmp = [0u8; 1024];
+            match self.inner.read(&mut tmp) {
+                Ok(0) => self.eof = true,
+                Ok(n) => self.buf.extend_from_slice(&tmp[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Reads frames from `input` on a thread of its own, so that the caller can
+/// wait for them with a timeout. The channel closes at the end of the stream
+/// or on a read error.
+pub fn spawn_reader<R: Read + Send + 'static>(input: R) -> Receiver<Result<Vec<u8>, Error>> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut frames = FrameReader::new(input);
+        while let Ok(Some(frame)) = frames.next_frame() {
+            if tx.send(frame).is_err() {
+                break
+            }
+        }
+    });
+    rx
+}
+
+/// Reads the messages in decoded frames, reassembling fragmented ones.
+///
+/// `Writer` puts every fragment of a message in the same frame, so each
+/// frame is reassembled on its own. A rebuilt message carries the sequence
+/// number and timestamp attached to its first fragment.
+pub struct MessageReader {
+    buf: Vec<u8>,
+}
+
+impl MessageReader {
+    pub fn new() -> Self {
+        MessageReader { buf: vec![0u8; fragment::MAX_VALUE] }
+    }
+
+    /// Calls `f` with each message in `frame`, along with the sequence
+    /// number and timestamp attached to it. A message that fails to decode
+    /// or reassemble is returned as an error, skipping the rest of the
+    /// frame.
+    pub fn read<F>(&mut self, frame: &[u8], mut f: F) -> io::Result<Result<(), Error>>
+    where
+        F: FnMut(&Message, Option<u32>, Option<u32>) -> io::Result<()>,
+    {
+        let mut reassembler = Reassembler::new(&mut self.buf);
+        let mut first = (None, None);
+        let mut r = Reader::new(frame);
+        let mut tmp = [0u8; 256];
+        loop {
+            match r.read(&mut tmp) {
+                Ok(Some(Message::Fragment(value))) => {
+                    if !reassembler.is_pending() {
+                        first = (r.seq(), r.time());
+                    }
+                    match reassembler.push(value) {
+                        Ok(Some(msg)) => f(&msg, first.0, first.1)?,
+                        Ok(None) => {}
+                        Err(e) => return Ok(Err(e.into())),
+                    }
+                }
+                Ok(Some(msg)) => f(&msg, r.seq(), r.time())?,
+                Ok(None) => return Ok(Ok(())),
+                Err(e) => return Ok(Err(e.into())),
+            }
+        }
+    }
+}
+
+impl Default for MessageReader {
+    fn default() -> Self {
+        MessageReader::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_reader() {
+        let mut stream = vec![0x00];
+        let mut tmp = [0u8; 16];
+        let n = cobs::encode(&[0x11, 0x00, 0x22], &mut tmp).unwrap();
+        stream.extend_from_slice(&tmp[..n]);
+        stream.extend_from_slice(&[0x00, 0x05, 0x01, 0x00]);
+        stream.extend_from_slice(&tmp[..n]);
+        stream.extend_from_slice(&[0x00, 0x03]);
+
+        let mut r = FrameReader::new(&stream[..]);
+        assert_eq!(r.next_frame().unwrap(), Some(Ok(vec![0x11, 0x00, 0x22])));
+        assert_eq!(r.next_frame().unwrap(), Some(Err(Error::Decode(sctl::Error::CobsError(cobs::Error::SourceTooShort)))));
+        assert_eq!(r.next_frame().unwrap(), Some(Ok(vec![0x11, 0x00, 0x22])));
+        assert_eq!(r.next_frame().unwrap(), None);
+
+        // The same frames arrive over a channel from the reader thread.
+        let rx = spawn_reader(io::Cursor::new(stream));
+        assert_eq!(rx.iter().map(|frame| frame.is_ok()).collect::<Vec<bool>>(), [true, false, true]);
+    }
+
+    #[test]
+    fn test_compressed() {
+        let text = b"sensor: temperature 21.5 C\nsensor: temperature 21.6 C\n";
+        let mut wbuf = [0u8; 256];
+        let mut w = sctl::Writer::new(&mut wbuf);
+        w.stdout(text).unwrap();
+        let plain = w.as_ref().to_vec();
+        let mut scratch = [0u8; 256];
+        let mut out = [0u8; 300];
+        let stream = compress::encode(&mut w, &mut scratch, &mut out).unwrap().to_vec();
+        assert!(stream.len() < plain.len());
+
+        let mut r = FrameReader::new(&stream[..]);
+        assert_eq!(r.next_frame().unwrap(), Some(Ok(plain)));
+        assert_eq!(unpack(vec![Tag::Compressed as u8, 0x00, 0x80, 0x00]), Err(Error::Decode(sctl::Error::InvalidRecord)));
+    }
+
+    #[test]
+    fn test_sealed() {
+        let stream = [0x03, 0x7e, 0x01, 0x01, 0x00, 0x02, 0x11, 0x00];
+        let mut r = FrameReader::new(&stream[..]);
+        let e = r.next_frame().unwrap().unwrap().unwrap_err();
+        assert_eq!(e, Error::Sealed);
+        assert_eq!(e.to_string(), "sealed frame, the link is secured");
+        assert_eq!(r.next_frame().unwrap(), Some(Ok(vec![0x11])));
+    }
+
+    #[test]
+    fn test_message_reader() {
+        let long: Vec<u8> = (0..600).map(|i| i as u8).collect();
+        let mut wbuf = [0u8; 1024];
+        let mut w = sctl::Writer::new(&mut wbuf);
+        w.seq(3).unwrap();
+        w.time(100).unwrap();
+        w.stdout(&long).unwrap();
+        w.time(200).unwrap();
+        w.info(b"short").unwrap();
+        w.stdout(&long[..300]).unwrap();
+
+        let mut messages = MessageReader::new();
+        let mut read = Vec::new();
+        let result = messages.read(w.as_ref(), |msg, seq, time| {
+            read.push(format!("{:?} {:?} {:?}", msg, seq, time));
+            Ok(())
+        });
+        assert_eq!(result.unwrap(), Ok(()));
+        assert_eq!(read, [
+            format!("{:?} Some(3) Some(100)", Message::Stdout(&long)),
+            String::from("Info([115, 104, 111, 114, 116]) None Some(200)"),
+            format!("{:?} None None", Message::Stdout(&long[..300])),
+        ]);
+
+        // A lost fragment fails the frame once the next fragmented message
+        // starts. Each fragment is 257 bytes, after 6 bytes of stamps.
+        let frame = w.as_ref();
+        let mut cut = frame[..6 + 257].to_vec();
+        cut.extend_from_slice(&frame[6 + 2 * 257..]);
+        let result = messages.read(&cut, |_, _, _| Ok(()));
+        assert_eq!(result.unwrap(), Err(Error::Decode(sctl::Error::MissingFragment)));
+    }
+}
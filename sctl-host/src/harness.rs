@@ -0,0 +1,324 @@
+//! Host side of the on-target test runner.
+//!
+//! `Harness` turns the `Test` messages of a device running
+//! `sctl::harness::run` into the same output as libtest, so that CI and
+//! tools that parse `cargo test` output work unchanged. Anything the device
+//! writes to stdout or stderr while a test runs is shown if the test fails.
+//! A panic or exception ends the run and fails the test that was running.
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use sctl::harness::{Event, Outcome};
+use sctl::{ExceptionRecord, Message, PanicRecord};
+
+use frame::{FrameReader, MessageReader};
+
+/// The exit status of a run with failures, as used by libtest.
+pub const FAILED: i32 = 101;
+
+pub struct Harness<W: Write> {
+    out: W,
+    names: Vec<String>,
+    current: Option<usize>,
+    output: String,
+    failures: Vec<(String, String)>,
+    passed: u32,
+    failed: u32,
+    ignored: u32,
+    exit: Option<u8>,
+}
+
+impl<W: Write> Harness<W> {
+    pub fn new(out: W) -> Self {
+        Harness {
+            out,
+            names: Vec::new(),
+            current: None,
+            output: String::new(),
+            failures: Vec::new(),
+            passed: 0,
+            failed: 0,
+            ignored: 0,
+            exit: None,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Returns `true` once the device has exited.
+    pub fn is_done(&self) -> bool {
+        self.exit.is_some()
+    }
+
+    fn name(&self, index: usize) -> String {
+        match self.names.get(index) {
+            Some(name) if !name.is_empty() => name.clone(),
+            _ => format!("#{}", index),
+        }
+    }
+
+    // Fails the running test, if any, with `reason`.
+    fn abort(&mut self, reason: &str) -> io::Result<()> {
+        if let Some(index) = self.current.take() {
+            let name = self.name(index);
+            writeln!(self.out, "test {} ... FAILED", name)?;
+            let output = format!("{}{}", self.output, reason);
+            self.failures.push((name, output));
+            self.failed += 1;
+        }
+        Ok(())
+    }
+
+    pub fn message(&mut self, msg: &Message) -> io::Result<()> {
+        if let Ok(Some(event)) = Event::from_message(msg) {
+            return self.event(event)
+        }
+        match *msg {
+            Message::Stdout(value) | Message::Stderr(value) => {
+                if self.current.is_some() {
+                    self.output.push_str(&String::from_utf8_lossy(value));
+                }
+                Ok(())
+            }
+            Message::Panic(value) => {
+                let reason = match PanicRecord::decode(value) {
+                    Ok(rec) => format!("device {}", rec),
+                    Err(_) => String::from("device panicked"),
+                };
+                self.abort(&reason)
+            }
+            Message::Exception(value) => {
+                let reason = match ExceptionRecord::decode(value) {
+                    Ok(rec) => format!("device fault:\n{}", rec),
+                    Err(_) => String::from("device fault"),
+                };
+                self.abort(&reason)
+            }
+            Message::Exit(code) => {
+                self.abort("device exited during the test")?;
+                self.exit = Some(code);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn event(&mut self, event: Event) -> io::Result<()> {
+        match event {
+            Event::List { count } => {
+                self.names = vec![String::new(); count as usize];
+                let plural = if count == 1 { "" } else { "s" };
+                writeln!(self.out, "\nrunning {} test{}", count, plural)
+            }
+            Event::Entry { index, name, .. } => {
+                if let Some(slot) = self.names.get_mut(index as usize) {
+                    *slot = name.to_string();
+                }
+                Ok(())
+            }
+            Event::Start { index } => {
+                self.current = Some(index as usize);
+                self.output.clear();
+                Ok(())
+            }
+            Event::Result { index, outcome, message } => {
+                self.current = None;
+                let name = self.name(index as usize);
+                match outcome {
+                    Outcome::Pass => {
+                        self.passed += 1;
+                        writeln!(self.out, "test {} ... ok", name)
+                    }
+                    Outcome::Fail => {
+                        self.failed += 1;
+                        let output = format!("{}{}", self.output, message);
+                        self.failures.push((name.clone(), output));
+                        writeln!(self.out, "test {} ... FAILED", name)
+                    }
+                    Outcome::Ignored => {
+                        self.ignored += 1;
+                        writeln!(self.out, "test {} ... ignored", name)
+                    }
+                }
+            }
+            Event::Summary { passed, failed, ignored } => {
+                self.passed = passed;
+                self.failed = failed;
+                self.ignored = ignored;
+                Ok(())
+            }
+        }
+    }
+
+    /// Prints the failures and the result line, and returns the exit status
+    /// for the run: 0 if it completed without failures, `FAILED` otherwise.
+    pub fn finish(&mut self, elapsed: Duration) -> io::Result<i32> {
+        self.abort("device disconnected during the test")?;
+        if !self.failures.is_empty() {
+            writeln!(self.out, "\nfailures:\n")?;
+            for (name, output) in &self.failures {
+                writeln!(self.out, "---- {} stdout ----\n{}\n", name, output)?;
+            }
+            writeln!(self.out, "\nfailures:")?;
+            for (name, _) in &self.failures {
+                writeln!(self.out, "    {}", name)?;
+            }
+        }
+        let ok = self.failed == 0 && self.exit == Some(0);
+        writeln!(self.out, "\ntest result: {}. {} passed; {} failed; {} ignored; 0 measured; 0 filtered out; finished in {:.2}s\n",
+            if ok { "ok" } else { "FAILED" }, self.passed, self.failed, self.ignored, elapsed.as_secs_f64())?;
+        self.out.flush()?;
+        Ok(if ok { 0 } else { FAILED })
+    }
+}
+
+/// Runs the harness over the frames read from `input` until the device
+/// exits or the stream ends, and returns the exit status.
+pub fn run<R: Read, W: Write>(input: R, out: W) -> io::Result<i32> {
+    let start = Instant::now();
+    let mut harness = Harness::new(out);
+    let mut frames = FrameReader::new(input);
+    let mut messages = MessageReader::new();
+    while !harness.is_done() {
+        let frame = match frames.next_frame()? {
+            Some(Ok(frame)) => frame,
+            Some(Err(_)) => continue,
+            None => break,
+        };
+        // Messages that fail to decode are skipped, like bad frames.
+        let _ = messages.read(&frame, |msg, _, _| harness.message(msg))?;
+    }
+    harness.finish(start.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sctl::harness::Test;
+    use sctl::{PanicRecord, Writer};
+    use std::thread;
+
+    fn passes() -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn fails() -> Result<(), &'static str> {
+        Err("expected failure")
+    }
+
+    fn skipped() -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    static TESTS: [Test; 3] = [
+        Test { name: "passes", run: passes, ignore: false },
+        Test { name: "fails", run: fails, ignore: false },
+        Test { name: "skipped", run: skipped, ignore: true },
+    ];
+
+    fn frame(f: &dyn Fn(&mut Writer)) -> Vec<u8> {
+        let mut wbuf = [0u8; 1024];
+        let mut w = Writer::new(&mut wbuf);
+        f(&mut w);
+        let mut out = [0u8; 1100];
+        w.encode(&mut out).unwrap().to_vec()
+    }
+
+    // Runs the host harness against `device`, which writes frames to a
+    // pipe from its own thread. Returns the status and the output with the
+    // timing left out.
+    fn simulate(device: fn(&mut dyn Write)) -> (i32, String) {
+        let (reader, mut writer) = io::pipe().unwrap();
+        let device = thread::spawn(move || device(&mut writer));
+        let mut out = Vec::new();
+        let status = run(reader, &mut out).unwrap();
+        device.join().unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let end = out.find("finished in").unwrap();
+        (status, out[..end].to_string())
+    }
+
+    #[test]
+    fn test_harness() {
+        let (status, out) = simulate(|pipe| {
+            sctl::harness::run(&TESTS, |f| {
+                pipe.write_all(f).unwrap();
+                // Output from the test that is running, if any. The host
+                // stops reading after the exit.
+                let _ = pipe.write_all(&frame(&|w| { w.stdout(b"log line\n").unwrap(); }));
+            });
+        });
+        assert_eq!(status, FAILED);
+        assert_eq!(out, "
+running 3 tests
+test passes ... ok
+test fails ... FAILED
+test skipped ... ignored
+
+failures:
+
+---- fails stdout ----
+log line
+expected failure
+
+
+failures:
+    fails
+
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; ");
+    }
+
+    #[test]
+    fn test_harness_pass() {
+        let (status, out) = simulate(|pipe| {
+            sctl::harness::run(&TESTS[..1], |f| pipe.write_all(f).unwrap());
+        });
+        assert_eq!(status, 0);
+        assert_eq!(out, "\nrunning 1 test\ntest passes ... ok\n\ntest result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; ");
+    }
+
+    #[test]
+    fn test_harness_panic() {
+        let (status, out) = simulate(|pipe| {
+            use sctl::harness::Event;
+            let events = [Event::List { count: 2 }, Event::Entry { index: 0, name: "a", ignored: false },
+                Event::Entry { index: 1, name: "b", ignored: false }, Event::Start { index: 0 }];
+            for event in events.iter() {
+                pipe.write_all(&frame(&|w| { event.write(w).unwrap(); })).unwrap();
+            }
+            let rec = PanicRecord { file: "src/main.rs", line: 7, column: 5, message: "boom" };
+            let mut buf = [0u8; 64];
+            let value = rec.encode(&mut buf).unwrap().to_vec();
+            pipe.write_all(&frame(&|w| { w.panic(&value).unwrap(); w.exit(101).unwrap(); })).unwrap();
+        });
+        assert_eq!(status, FAILED);
+        assert!(out.contains("test a ... FAILED\n"), "{}", out);
+        assert!(out.contains("---- a stdout ----\ndevice panicked at src/main.rs:7:5:\nboom\n"), "{}", out);
+        assert!(out.ends_with("test result: FAILED. 0 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out; "), "{}", out);
+    }
+
+    #[test]
+    fn test_harness_long() {
+        // Output and panic messages over 255 bytes arrive fragmented.
+        let (status, out) = simulate(|pipe| {
+            use sctl::harness::Event;
+            let events = [Event::List { count: 1 }, Event::Entry { index: 0, name: "a", ignored: false }, Event::Start { index: 0 }];
+            for event in events.iter() {
+                pipe.write_all(&frame(&|w| { event.write(w).unwrap(); })).unwrap();
+            }
+            let line = format!("{}\n", "x".repeat(299));
+            let (file, message) = (format!("src/{}.rs", "f".repeat(100)), "y".repeat(200));
+            let rec = PanicRecord { file: &file, line: 7, column: 5, message: &message };
+            let mut buf = [0u8; 512];
+            let value = rec.encode(&mut buf).unwrap().to_vec();
+            pipe.write_all(&frame(&|w| { w.stdout(line.as_bytes()).unwrap(); w.panic(&value).unwrap(); w.exit(101).unwrap(); })).unwrap();
+        });
+        assert_eq!(status, FAILED);
+        let expected = format!("---- a stdout ----\n{}\ndevice panicked at src/{}.rs:7:5:\n{}\n", "x".repeat(299), "f".repeat(100), "y".repeat(200));
+        assert!(out.contains(&expected), "{}", out);
+        assert!(!out.contains("device exited during the test"), "{}", out);
+    }
+}
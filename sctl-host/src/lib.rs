@@ -0,0 +1,14 @@
+//! Host-side tools for sctl links.
+
+extern crate cobs;
+extern crate sctl;
+
+pub mod capture;
+pub mod console;
+pub mod frame;
+pub mod harness;
+pub mod memory;
+pub mod pcapng;
+pub mod strings;
+pub mod telemetry;
+pub mod time;
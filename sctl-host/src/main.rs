@@ -0,0 +1,344 @@
+extern crate sctl;
+extern crate sctl_host;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::process;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration, SystemTime};
+
+use sctl::memory::Width;
+use sctl::transfer::{Phase, Sender, MAX_CHUNK};
+use sctl::Writer;
+use sctl_host::capture::{CaptureReader, CaptureWriter, Direction, Recorder, Replayer};
+use sctl_host::console::Console;
+use sctl_host::frame::{self, FrameReader, MessageReader};
+use sctl_host::harness;
+use sctl_host::memory::{self, Remote};
+use sctl_host::pcapng::{PcapngWriter, LINKTYPE_USER0};
+use sctl_host::strings::StringTable;
+use sctl_host::telemetry::{self, Exporter};
+use sctl_host::time;
+
+const USAGE: &str = "\
+usage: sctl <command> [options] [args]
+
+commands:
+    console <path>    print messages read from a device or raw stream
+    replay <capture>  print the messages in a capture
+    dump <capture>    print every decoded message in a capture
+    pcap <capture> <out.pcapng>
+                      convert a capture to pcapng
+    strings <elf>     print the string table of a firmware image
+    peek <path> <addr> <len>
+                      dump device memory as hex
+    poke <path> <addr> <hex>
+                      write bytes, given as hex, to device memory
+    push <path> <file>
+                      transfer a firmware image or other file to the device
+    telemetry <path>  export telemetry samples read from a device or raw
+                      stream
+    test <path>       report the results of tests run on the device, exiting
+                      with 101 if any failed
+
+options:
+    --strings <elf|table>  expand deferred logs (console, replay)
+    --record <capture>     record the session to a capture file (console)
+    --speed <factor>       replay at the given speed instead of as fast as
+                           possible, 1 for the original timing (replay)
+    --linktype <dlt>       link type for exported packets, default 147
+                           (pcap)
+    --width <1|2|4>        access width in bytes, default 1 (peek, poke)
+    --target <n>           storage target on the device, default 0 (push)
+    --format <csv|jsonl>   output format, default csv (telemetry)
+";
+
+#[derive(Default)]
+struct Options {
+    strings: Option<String>,
+    record: Option<String>,
+    speed: Option<f64>,
+    linktype: Option<u16>,
+    width: Option<Width>,
+    target: Option<u32>,
+    format: Option<telemetry::Format>,
+}
+
+fn usage() -> ! {
+    let _ = io::stderr().write_all(USAGE.as_bytes());
+    process::exit(2);
+}
+
+fn parse(args: &[String]) -> (Options, Vec<&str>) {
+    let mut opts = Options::default();
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().cloned().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--strings" => opts.strings = Some(value()),
+            "--record" => opts.record = Some(value()),
+            "--speed" => opts.speed = Some(match value().parse::<f64>() {
+                Ok(speed) if speed.is_finite() && speed > 0.0 => speed,
+                _ => usage(),
+            }),
+            "--linktype" => opts.linktype = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--target" => opts.target = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--width" => opts.width = Some(match value().as_str() {
+                "1" => Width::U8,
+                "2" => Width::U16,
+                "4" => Width::U32,
+                _ => usage(),
+            }),
+            "--format" => opts.format = Some(match value().as_str() {
+                "csv" => telemetry::Format::Csv,
+                "jsonl" => telemetry::Format::JsonLines,
+                _ => usage(),
+            }),
+            _ if arg.starts_with("--") => usage(),
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    (opts, positional)
+}
+
+fn console_for(opts: &Options) -> io::Result<Console<io::Stdout>> {
+    let strings = match opts.strings {
+        Some(ref path) => StringTable::load(&fs::read(path)?)?,
+        None => StringTable::new(),
+    };
+    Ok(Console::with_strings(io::stdout(), strings))
+}
+
+fn console(path: &str, opts: &Options) -> io::Result<()> {
+    let mut console = console_for(opts)?;
+    let device = File::open(path)?;
+    let source: Box<dyn Read> = match opts.record {
+        Some(ref capture) => {
+            let capture = CaptureWriter::new(File::create(capture)?, SystemTime::now())?;
+            Box::new(Recorder::new(device, capture))
+        }
+        None => Box::new(device),
+    };
+    let mut frames = FrameReader::new(source);
+    let mut messages = MessageReader::new();
+    while let Some(frame) = frames.next_frame()? {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("sctl: bad frame: {}", e);
+                continue
+            }
+        };
+        let result = messages.read(&frame, |msg, _, ticks| console.message(msg, ticks, SystemTime::now()))?;
+        if let Err(e) = result {
+            eprintln!("sctl: bad message: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn replay(path: &str, opts: &Options) -> io::Result<()> {
+    let mut console = console_for(opts)?;
+    let mut replayer = Replayer::new(CaptureReader::new(File::open(path)?)?, opts.speed);
+    let start = replayer.start();
+    replayer.run(|record, msg, ticks| {
+        if record.direction == Direction::FromDevice {
+            console.message(msg, ticks, start + record.time)?;
+        }
+        Ok(())
+    })?;
+    if replayer.errors() > 0 {
+        eprintln!("sctl: {} frames or messages failed to decode", replayer.errors());
+    }
+    Ok(())
+}
+
+fn dump(path: &str) -> io::Result<()> {
+    let mut replayer = Replayer::new(CaptureReader::new(File::open(path)?)?, None);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    replayer.run(|record, msg, ticks| {
+        let arrow = match record.direction {
+            Direction::FromDevice => "<",
+            Direction::ToDevice => ">",
+        };
+        write!(out, "{:>14} {} {:?}", time::format_secs(record.time), arrow, msg)?;
+        match ticks {
+            Some(ticks) => writeln!(out, " @{}", ticks),
+            None => writeln!(out),
+        }
+    })?;
+    if replayer.errors() > 0 {
+        eprintln!("sctl: {} frames or messages failed to decode", replayer.errors());
+    }
+    Ok(())
+}
+
+fn pcap(path: &str, out: &str, opts: &Options) -> io::Result<()> {
+    let mut capture = CaptureReader::new(File::open(path)?)?;
+    let start = capture.start();
+    let mut pcap = PcapngWriter::new(io::BufWriter::new(File::create(out)?), opts.linktype.unwrap_or(LINKTYPE_USER0))?;
+    let mut errors = 0;
+    while let Some(record) = capture.next_record()? {
+        match record.decode() {
+            Ok(frame) => pcap.write_packet(record.direction, start + record.time, &frame)?,
+            Err(_) => errors += 1,
+        }
+    }
+    if errors > 0 {
+        eprintln!("sctl: {} frames failed to decode", errors);
+    }
+    pcap.flush()
+}
+
+// Parses a number given in decimal or, with a 0x prefix, in hex.
+fn number(s: &str) -> u32 {
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    n.unwrap_or_else(|_| usage())
+}
+
+fn hex_bytes(s: &str) -> Vec<u8> {
+    if !s.len().is_multiple_of(2) {
+        usage()
+    }
+    (0..s.len()).step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()).unwrap_or_else(|| usage()))
+        .collect()
+}
+
+fn remote(path: &str) -> io::Result<Remote<File>> {
+    let device = OpenOptions::new().read(true).write(true).open(path)?;
+    Ok(Remote::new(device.try_clone()?, device))
+}
+
+fn peek(path: &str, addr: &str, len: &str, opts: &Options) -> io::Result<()> {
+    let addr = number(addr);
+    let data = remote(path)?.read(addr, number(len) as usize, opts.width.unwrap_or(Width::U8))?;
+    io::stdout().write_all(memory::hexdump(addr, &data).as_bytes())
+}
+
+fn poke(path: &str, addr: &str, data: &str, opts: &Options) -> io::Result<()> {
+    remote(path)?.write(number(addr), &hex_bytes(data), opts.width.unwrap_or(Width::U8))
+}
+
+fn sctl_error(e: sctl::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e))
+}
+
+fn push(path: &str, file: &str, opts: &Options) -> io::Result<()> {
+    let image = fs::read(file)?;
+    let mut device = OpenOptions::new().read(true).write(true).open(path)?;
+    let input = device.try_clone()?;
+    let rx = frame::spawn_reader(input);
+
+    let clock = time::Millis::new();
+    let mut sender = Sender::new(&clock, 500, opts.target.unwrap_or(0), &image, MAX_CHUNK);
+    let (mut wbuf, mut out) = ([0u8; 512], [0u8; 600]);
+    let mut messages = MessageReader::new();
+    let (mut shown, mut fragment_id) = (None, 0);
+    let result = (|| {
+        loop {
+            match sender.phase() {
+                Phase::Done => {
+                    eprintln!("\rsent {} bytes, verified", sender.size());
+                    return Ok(())
+                }
+                Phase::Failed(status) => return Err(io::Error::other(format!("transfer failed: {:?}", status))),
+                Phase::TimedOut => return Err(io::ErrorKind::TimedOut.into()),
+                _ => {}
+            }
+            if shown != Some(sender.offset()) {
+                eprint!("\rsent {}/{} bytes", sender.offset(), sender.size());
+                shown = Some(sender.offset());
+            }
+            let mut w = Writer::new(&mut wbuf);
+            w.set_fragment_id(fragment_id);
+            match sender.poll(&mut w) {
+                Ok(()) | Err(sctl::Error::Timeout) => {}
+                Err(e) => return Err(sctl_error(e)),
+            }
+            fragment_id = w.fragment_id();
+            if !w.as_ref().is_empty() {
+                device.write_all(w.encode(&mut out).map_err(sctl_error)?)?;
+            }
+            match rx.recv_timeout(Duration::from_millis(10)) {
+                Ok(Err(e)) => eprintln!("\rsctl: bad frame: {}", e),
+                Ok(Ok(frame)) => {
+                    let result = messages.read(&frame, |msg, _, _| sender.receive(msg).map(|_| ()).map_err(sctl_error))?;
+                    if let Err(e) = result {
+                        eprintln!("\rsctl: bad message: {}", e);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    })();
+    // Finish the progress line before the error is printed.
+    if result.is_err() && shown.is_some() {
+        eprintln!();
+    }
+    result
+}
+
+fn telemetry(path: &str, opts: &Options) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut exporter = Exporter::new(stdout.lock(), opts.format.unwrap_or(telemetry::Format::Csv));
+    let mut frames = FrameReader::new(File::open(path)?);
+    let mut messages = MessageReader::new();
+    while let Some(frame) = frames.next_frame()? {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                eprintln!("sctl: bad frame: {}", e);
+                continue
+            }
+        };
+        let result = messages.read(&frame, |msg, _, ticks| exporter.message(msg, ticks, SystemTime::now()))?;
+        if let Err(e) = result {
+            eprintln!("sctl: bad message: {}", e);
+        }
+        exporter.flush()?;
+    }
+    if exporter.dropped() > 0 {
+        eprintln!("sctl: {} sample messages dropped for undeclared signals", exporter.dropped());
+    }
+    Ok(())
+}
+
+fn test(path: &str) -> io::Result<()> {
+    let status = harness::run(File::open(path)?, io::stdout())?;
+    process::exit(status)
+}
+
+fn strings(path: &str) -> io::Result<()> {
+    let table = StringTable::from_elf(&fs::read(path)?)?;
+    io::stdout().write_all(table.to_sidecar().as_bytes())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (opts, args) = parse(&args);
+    let result = match args.as_slice() {
+        ["console", path] => console(path, &opts),
+        ["replay", path] => replay(path, &opts),
+        ["dump", path] => dump(path),
+        ["pcap", path, out] => pcap(path, out, &opts),
+        ["strings", path] => strings(path),
+        ["peek", path, addr, len] => peek(path, addr, len, &opts),
+        ["poke", path, addr, data] => poke(path, addr, data, &opts),
+        ["push", path, file] => push(path, file, &opts),
+        ["telemetry", path] => telemetry(path, &opts),
+        ["test", path] => test(path),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("sctl: {}", e);
+        process::exit(1);
+    }
+}
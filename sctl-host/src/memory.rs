@@ -0,0 +1,264 @@
+//! Reading and writing device memory over an sctl link.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use sctl::memory::{Request, Response, Status, Width, MAX_LEN};
+use sctl::{Reader, Writer};
+
+use frame::{self, Error};
+
+fn status_error(addr: u32, status: Status) -> io::Error {
+    let msg = match status {
+        Status::Denied => "access denied",
+        Status::Unaligned => "unaligned access",
+        Status::TooLong => "request too long",
+        Status::Fault => "bus fault",
+    };
+    io::Error::other(format!("0x{:08x}: {}", addr, msg))
+}
+
+/// How long `Remote` waits for the device to answer a request.
+pub const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Host side of the memory protocol, issuing one request at a time and
+/// splitting large accesses into requests of at most `MAX_LEN` bytes.
+///
+/// Frames from the device are read on a thread of their own, so that a
+/// device that never answers fails the request with `TimedOut`.
+pub struct Remote<W: Write> {
+    device: W,
+    frames: Receiver<Result<Vec<u8>, Error>>,
+    timeout: Duration,
+    seq: u32,
+    fragment_id: u8,
+}
+
+impl<W: Write> Remote<W> {
+    /// Creates a remote that writes requests to `device` and reads the
+    /// responses from `input`, usually a clone of the same port.
+    pub fn new<R: Read + Send + 'static>(input: R, device: W) -> Self {
+        Remote { device, frames: frame::spawn_reader(input), timeout: TIMEOUT, seq: 0, fragment_id: 0 }
+    }
+
+    /// Replaces `TIMEOUT` as the time to wait for each response.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        Remote { timeout, ..self }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.device
+    }
+
+    // Sends `req` and waits for the matching `Mem`, skipping anything else
+    // the device sends in the meantime.
+    fn request(&mut self, req: &Request) -> io::Result<Result<Vec<u8>, Status>> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut wbuf = [0u8; 512];
+        let mut w = Writer::new(&mut wbuf);
+        w.set_fragment_id(self.fragment_id);
+        let mut out = [0u8; 600];
+        let frame = w.seq(self.seq)
+            .and_then(|_| req.write(&mut w))
+            .and_then(|_| w.encode(&mut out))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+        self.fragment_id = w.fragment_id();
+        self.device.write_all(frame)?;
+        self.device.flush()?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut tmp = [0u8; 256];
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let frame = match self.frames.recv_timeout(wait) {
+                Ok(Ok(frame)) => frame,
+                Ok(Err(_)) => continue,
+                Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(io::ErrorKind::TimedOut, "no response from device")),
+                Err(RecvTimeoutError::Disconnected) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            };
+            let mut r = Reader::new(&frame);
+            while let Ok(Some(msg)) = r.read(&mut tmp) {
+                if let Ok(Some(resp)) = Response::from_message(&msg) {
+                    if resp.matches(req) && r.seq().is_none_or(|seq| seq == self.seq) {
+                        return Ok(resp.result.map(|data| data.to_vec()))
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn read(&mut self, addr: u32, len: usize, width: Width) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let at = addr.wrapping_add(data.len() as u32);
+            let n = (len - data.len()).min(MAX_LEN) as u32;
+            let req = Request::Peek { addr: at, len: n, width };
+            match self.request(&req)? {
+                Ok(ref chunk) if chunk.len() == n as usize => data.extend_from_slice(chunk),
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "short read")),
+                Err(status) => return Err(status_error(at, status)),
+            }
+        }
+        Ok(data)
+    }
+
+    pub fn write(&mut self, addr: u32, data: &[u8], width: Width) -> io::Result<()> {
+        for (i, chunk) in data.chunks(MAX_LEN).enumerate() {
+            let at = addr.wrapping_add((i * MAX_LEN) as u32);
+            let req = Request::Poke { addr: at, data: chunk, width };
+            if let Err(status) = self.request(&req)? {
+                return Err(status_error(at, status))
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats `data` as lines of 16 hex bytes followed by their ASCII.
+pub fn hexdump(addr: u32, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let _ = write!(out, "{:08x} ", addr.wrapping_add(i as u32 * 16));
+        for j in 0..16 {
+            match line.get(j) {
+                Some(b) => { let _ = write!(out, " {:02x}", b); }
+                None => out.push_str("   "),
+            }
+            if j == 7 {
+                out.push(' ');
+            }
+        }
+        out.push_str("  |");
+        out.extend(line.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sctl::memory::{Access, Handler, Memory, Region};
+    use std::sync::mpsc;
+
+    const BASE: u32 = 0x2000_0000;
+    const REGIONS: [Region; 1] = [Region::new(BASE, 512, Access::ReadWrite)];
+
+    struct Sim(Vec<u8>);
+
+    impl Memory for Sim {
+        fn read(&mut self, addr: u32, _: Width, buf: &mut [u8]) -> Result<(), Status> {
+            let start = (addr - BASE) as usize;
+            buf.copy_from_slice(&self.0[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn write(&mut self, addr: u32, _: Width, data: &[u8]) -> Result<(), Status> {
+            let start = (addr - BASE) as usize;
+            self.0[start..start + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    // A device that answers every frame written to it, preceded by an
+    // unrelated log message, over `rx`.
+    struct Device {
+        handler: Handler<'static, Sim>,
+        tx: Vec<u8>,
+        rx: mpsc::Sender<Vec<u8>>,
+        requests: usize,
+    }
+
+    // The host's end of the device's output.
+    struct Link(Receiver<Vec<u8>>, Vec<u8>);
+
+    impl Read for Link {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.1.is_empty() {
+                match self.0.recv() {
+                    Ok(data) => self.1 = data,
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = buf.len().min(self.1.len());
+            buf[..n].copy_from_slice(&self.1[..n]);
+            self.1.drain(..n);
+            Ok(n)
+        }
+    }
+
+    impl Write for Device {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.tx.extend_from_slice(buf);
+            while let Some(end) = self.tx.iter().position(|&b| b == 0) {
+                let mut frame = vec![0u8; end];
+                let n = ::cobs::decode(&self.tx[..end], &mut frame).unwrap();
+                self.tx.drain(..end + 1);
+
+                let mut wbuf = [0u8; 512];
+                let mut w = Writer::new(&mut wbuf);
+                w.info(b"poll").unwrap();
+                let mut r = Reader::new(&frame[..n]);
+                let mut tmp = [0u8; 256];
+                while let Some(msg) = r.read(&mut tmp).unwrap() {
+                    assert!(self.handler.serve(&msg, r.seq(), &mut w).unwrap());
+                    self.requests += 1;
+                }
+                let mut out = [0u8; 600];
+                let _ = self.rx.send(w.encode(&mut out).unwrap().to_vec());
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_remote() {
+        let sim = Sim((0..512).map(|i| i as u8).collect());
+        let (tx, rx) = mpsc::channel();
+        let device = Device { handler: Handler::new(sim, &REGIONS), tx: Vec::new(), rx: tx, requests: 0 };
+        let mut remote = Remote::new(Link(rx, Vec::new()), device);
+
+        let data = remote.read(BASE + 8, 300, Width::U32).unwrap();
+        assert_eq!(data, (8..308).map(|i| i as u8).collect::<Vec<u8>>());
+
+        remote.write(BASE + 500, &[1, 2, 3, 4], Width::U8).unwrap();
+        assert_eq!(remote.read(BASE + 498, 8, Width::U16).unwrap(), vec![242, 243, 1, 2, 3, 4, 248, 249]);
+
+        let err = remote.read(BASE + 510, 4, Width::U8).unwrap_err();
+        assert_eq!(err.to_string(), "0x200001fe: access denied");
+
+        let device = remote.into_inner();
+        assert_eq!(device.requests, 5);
+    }
+
+    #[test]
+    fn test_remote_timeout() {
+        // A device that never answers.
+        let (tx, rx) = mpsc::channel();
+        let mut remote = Remote::new(Link(rx, Vec::new()), Vec::new()).with_timeout(Duration::from_millis(20));
+        let start = Instant::now();
+        assert_eq!(remote.read(BASE, 4, Width::U8).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert!(!remote.into_inner().is_empty());
+        drop(tx);
+
+        // One that has gone away.
+        let mut remote = Remote::new(io::empty(), Vec::new());
+        assert_eq!(remote.write(BASE, &[1], Width::U8).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let data: Vec<u8> = b"sctl\x00\x01".iter().cloned().chain(0x41..0x55).collect();
+        assert_eq!(hexdump(0x2000_0010, &data), "\
+20000010  73 63 74 6c 00 01 41 42  43 44 45 46 47 48 49 4a  |sctl..ABCDEFGHIJ|
+20000020  4b 4c 4d 4e 4f 50 51 52  53 54                    |KLMNOPQRST|
+");
+    }
+}
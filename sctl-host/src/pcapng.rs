@@ -0,0 +1,198 @@
+//! pcapng export of sctl traffic.
+//!
+//! Each frame becomes an Enhanced Packet Block holding the decoded frame,
+//! i.e. the sequence of tlv8 messages, on a single interface with a
+//! user-defined link type (`LINKTYPE_USER0` unless chosen otherwise).
+//! Timestamps have microsecond resolution and the direction is carried in
+//! the `epb_flags` option: inbound for frames from the device, outbound for
+//! frames to it.
+
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use capture::Direction;
+
+/// First of the link types reserved for private use.
+pub const LINKTYPE_USER0: u16 = 147;
+
+const SECTION_HEADER: u32 = 0x0a0d_0d0a;
+const INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+const ENHANCED_PACKET: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+
+const OPT_END: u16 = 0;
+const SHB_USERAPPL: u16 = 4;
+const IF_NAME: u16 = 2;
+const IF_TSRESOL: u16 = 9;
+const EPB_FLAGS: u16 = 2;
+
+const FLAG_INBOUND: u32 = 0b01;
+const FLAG_OUTBOUND: u32 = 0b10;
+
+fn padding(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+// Appends a little-endian block of `kind` with `body` and its trailing
+// length field.
+fn block(out: &mut Vec<u8>, kind: u32, body: &[u8]) {
+    let len = (12 + body.len()) as u32;
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(body);
+    out.extend_from_slice(&len.to_le_bytes());
+}
+
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.extend_from_slice(&[0; 3][..padding(value.len())]);
+}
+
+pub struct PcapngWriter<W: Write> {
+    out: W,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Writes the section header and a single interface with `linktype`.
+    pub fn new(mut out: W, linktype: u16) -> io::Result<Self> {
+        let mut buf = Vec::new();
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        option(&mut body, SHB_USERAPPL, b"sctl");
+        option(&mut body, OPT_END, &[]);
+        block(&mut buf, SECTION_HEADER, &body);
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&linktype.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        option(&mut body, IF_NAME, b"sctl");
+        option(&mut body, IF_TSRESOL, &[6]);
+        option(&mut body, OPT_END, &[]);
+        block(&mut buf, INTERFACE_DESCRIPTION, &body);
+
+        out.write_all(&buf)?;
+        Ok(PcapngWriter { out })
+    }
+
+    pub fn write_packet(&mut self, direction: Direction, time: SystemTime, data: &[u8]) -> io::Result<()> {
+        let t = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let ts = t.as_secs() * 1_000_000 + t.subsec_micros() as u64;
+        let flags = match direction {
+            Direction::FromDevice => FLAG_INBOUND,
+            Direction::ToDevice => FLAG_OUTBOUND,
+        };
+
+        let mut body = Vec::with_capacity(data.len() + 40);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(ts as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+        body.extend_from_slice(&[0; 3][..padding(data.len())]);
+        option(&mut body, EPB_FLAGS, &flags.to_le_bytes());
+        option(&mut body, OPT_END, &[]);
+
+        let mut buf = Vec::with_capacity(body.len() + 12);
+        block(&mut buf, ENHANCED_PACKET, &body);
+        self.out.write_all(&buf)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn u16_at(b: &[u8], i: usize) -> u16 {
+        u16::from_le_bytes([b[i], b[i + 1]])
+    }
+
+    fn u32_at(b: &[u8], i: usize) -> u32 {
+        u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]])
+    }
+
+    // Splits a pcapng file into (type, body) blocks, checking both length
+    // fields of every block.
+    fn blocks(mut data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut out = Vec::new();
+        while !data.is_empty() {
+            let len = u32_at(data, 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(data, len - 4) as usize, len);
+            out.push((u32_at(data, 0), &data[8..len - 4]));
+            data = &data[len..];
+        }
+        out
+    }
+
+    // Returns the (code, value) options starting at `body[start..]`.
+    fn options(body: &[u8], mut i: usize) -> Vec<(u16, &[u8])> {
+        let mut out = Vec::new();
+        loop {
+            let (code, len) = (u16_at(body, i), u16_at(body, i + 2) as usize);
+            if code == OPT_END {
+                assert_eq!(i + 4, body.len());
+                return out
+            }
+            out.push((code, &body[i + 4..i + 4 + len]));
+            i += 4 + len + padding(len);
+        }
+    }
+
+    #[test]
+    fn test_pcapng() {
+        let start = UNIX_EPOCH + Duration::new(1_792_322_645, 250_000_000);
+        let mut w = PcapngWriter::new(Vec::new(), LINKTYPE_USER0).unwrap();
+        w.write_packet(Direction::FromDevice, start, &[0x01, 0x00]).unwrap();
+        w.write_packet(Direction::ToDevice, start + Duration::from_micros(1500), &[0x10, 0x03, b'a', b'b', b'c']).unwrap();
+        let data = w.into_inner();
+
+        let blocks = blocks(&data);
+        assert_eq!(blocks.len(), 4);
+
+        let (kind, shb) = blocks[0];
+        assert_eq!(kind, SECTION_HEADER);
+        assert_eq!(u32_at(shb, 0), BYTE_ORDER_MAGIC);
+        assert_eq!((u16_at(shb, 4), u16_at(shb, 6)), (1, 0));
+        assert_eq!(options(shb, 16), vec![(SHB_USERAPPL, &b"sctl"[..])]);
+
+        let (kind, idb) = blocks[1];
+        assert_eq!(kind, INTERFACE_DESCRIPTION);
+        assert_eq!(u16_at(idb, 0), LINKTYPE_USER0);
+        assert_eq!(options(idb, 8), vec![(IF_NAME, &b"sctl"[..]), (IF_TSRESOL, &[6u8][..])]);
+
+        let mut packets = Vec::new();
+        for &(kind, epb) in &blocks[2..] {
+            assert_eq!(kind, ENHANCED_PACKET);
+            assert_eq!(u32_at(epb, 0), 0);
+            let ts = (u32_at(epb, 4) as u64) << 32 | u32_at(epb, 8) as u64;
+            let len = u32_at(epb, 12) as usize;
+            assert_eq!(u32_at(epb, 16) as usize, len);
+            let data = &epb[20..20 + len];
+            let opts = options(epb, 20 + len + padding(len));
+            assert_eq!(opts.len(), 1);
+            assert_eq!(opts[0].0, EPB_FLAGS);
+            packets.push((ts, u32_at(opts[0].1, 0), data.to_vec()));
+        }
+        assert_eq!(packets, vec![
+            (1_792_322_645_250_000, FLAG_INBOUND, vec![0x01, 0x00]),
+            (1_792_322_645_251_500, FLAG_OUTBOUND, vec![0x10, 0x03, b'a', b'b', b'c']),
+        ]);
+    }
+}
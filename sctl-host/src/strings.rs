@@ -0,0 +1,339 @@
+//! Format strings for deferred log messages.
+//!
+//! The table is read either from the `.sctl.fmt` section of the firmware
+//! ELF file or from a sidecar file with one `<id> <format>` line per
+//! string, the ID in hex. `StringTable::to_sidecar` writes the latter.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::io;
+
+use sctl::deferred::Args;
+use sctl::Error;
+
+pub const SECTION: &str = ".sctl.fmt";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct StringTable {
+    strings: BTreeMap<u32, String>,
+}
+
+impl StringTable {
+    pub fn new() -> Self {
+        StringTable::default()
+    }
+
+    pub fn insert(&mut self, id: u32, fmt: &str) {
+        self.strings.insert(id, fmt.to_string());
+    }
+
+    pub fn get(&self, id: u32) -> Option<&str> {
+        self.strings.get(&id).map(|s| s.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+
+    /// Reads a sidecar file or, if `data` starts with the ELF magic, the
+    /// `.sctl.fmt` section of an ELF file.
+    pub fn load(data: &[u8]) -> io::Result<StringTable> {
+        if data.starts_with(b"\x7fELF") {
+            StringTable::from_elf(data)
+        } else {
+            let text = ::std::str::from_utf8(data).map_err(|_| invalid("sidecar is not UTF-8"))?;
+            StringTable::from_sidecar(text)
+        }
+    }
+
+    pub fn from_sidecar(text: &str) -> io::Result<StringTable> {
+        let mut table = StringTable::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let (id, fmt) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => return Err(invalid("missing format string")),
+            };
+            let id = u32::from_str_radix(id.trim_start_matches("0x"), 16).map_err(|_| invalid("bad string id"))?;
+            table.insert(id, &unescape(fmt));
+        }
+        Ok(table)
+    }
+
+    pub fn to_sidecar(&self) -> String {
+        let mut out = String::new();
+        for (id, fmt) in &self.strings {
+            let _ = writeln!(out, "0x{:08x} {}", id, fmt.replace('\\', "\\\\").replace('\n', "\\n"));
+        }
+        out
+    }
+
+    /// Reads the NUL-terminated strings in the `.sctl.fmt` section of a
+    /// little-endian ELF file. Each string's ID is its address.
+    pub fn from_elf(data: &[u8]) -> io::Result<StringTable> {
+        let (addr, bytes) = elf_section(data, SECTION)?.ok_or_else(|| invalid("no .sctl.fmt section"))?;
+        let mut table = StringTable::new();
+        let mut offset = 0;
+        for s in bytes.split(|&b| b == 0) {
+            if !s.is_empty() {
+                let s = ::std::str::from_utf8(s).map_err(|_| invalid("format string is not UTF-8"))?;
+                table.insert(addr.wrapping_add(offset) as u32, s);
+            }
+            offset += s.len() as u64 + 1;
+        }
+        Ok(table)
+    }
+
+    /// Expands the format string `id` with `args`.
+    pub fn format(&self, id: u32, args: &mut Args) -> Result<String, FormatError> {
+        let fmt = self.get(id).ok_or(FormatError::UnknownId(id))?;
+        let mut out = String::new();
+        let mut rest = fmt;
+        while let Some(start) = rest.find(['{', '}']) {
+            out.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                out.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue
+            }
+            if rest.starts_with('}') {
+                out.push('}');
+                rest = &rest[1..];
+                continue
+            }
+            let end = rest.find('}').ok_or(FormatError::BadFormat)?;
+            match &rest[1..end] {
+                "=u32" => write!(out, "{}", args.u32()?),
+                "=u32:x" => write!(out, "0x{:x}", args.u32()?),
+                "=i32" => write!(out, "{}", args.i32()?),
+                "=bool" => write!(out, "{}", args.bool()?),
+                "=str" => write!(out, "{}", args.str()?),
+                _ => return Err(FormatError::BadFormat),
+            }.unwrap();
+            rest = &rest[end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FormatError {
+    UnknownId(u32),
+    BadFormat,
+    BadArgs(Error),
+}
+
+impl From<Error> for FormatError {
+    fn from(other: Error) -> FormatError {
+        FormatError::BadArgs(other)
+    }
+}
+
+// Undoes the escapes `to_sidecar` writes, `\\` for a backslash and `\n`
+// for a newline. Any other backslash is kept as it is.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some('\\')) => out.push('\\'),
+            ('\\', Some('n')) => out.push('\n'),
+            _ => {
+                out.push(c);
+                continue
+            }
+        }
+        chars.next();
+    }
+    out
+}
+
+// Returns the `size` bytes at `offset`, which the file's own offsets and
+// sizes may place anywhere.
+fn slice(data: &[u8], offset: u64, size: u64) -> io::Result<&[u8]> {
+    offset.checked_add(size)
+        .and_then(|end| data.get(usize::try_from(offset).ok()?..usize::try_from(end).ok()?))
+        .ok_or_else(|| invalid("truncated ELF file"))
+}
+
+fn read_uint(data: &[u8], offset: u64, size: u64) -> io::Result<u64> {
+    let bytes = slice(data, offset, size)?;
+    Ok(bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64))
+}
+
+// Returns the address and contents of the named section.
+fn elf_section<'a>(data: &'a [u8], name: &str) -> io::Result<Option<(u64, &'a [u8])>> {
+    let wide = match data.get(4) {
+        Some(1) => false,
+        Some(2) => true,
+        _ => return Err(invalid("unknown ELF class")),
+    };
+    if data.get(5) != Some(&1) {
+        return Err(invalid("big-endian ELF files are not supported"));
+    }
+    let word = if wide { 8 } else { 4 };
+    let (shoff, shentsize, shnum, shstrndx) = if wide {
+        (read_uint(data, 0x28, 8)?, read_uint(data, 0x3a, 2)?, read_uint(data, 0x3c, 2)?, read_uint(data, 0x3e, 2)?)
+    } else {
+        (read_uint(data, 0x20, 4)?, read_uint(data, 0x2e, 2)?, read_uint(data, 0x30, 2)?, read_uint(data, 0x32, 2)?)
+    };
+    // sh_name, sh_addr, sh_offset and sh_size of section `i`.
+    let header = |i: u64| -> io::Result<(u64, u64, u64, u64)> {
+        let base = i.checked_mul(shentsize)
+            .and_then(|off| off.checked_add(shoff))
+            .filter(|base| base.checked_add(8 + 4 * word).is_some())
+            .ok_or_else(|| invalid("truncated ELF file"))?;
+        Ok((
+            read_uint(data, base, 4)?,
+            read_uint(data, base + 8 + word, word)?,
+            read_uint(data, base + 8 + 2 * word, word)?,
+            read_uint(data, base + 8 + 3 * word, word)?,
+        ))
+    };
+    let contents = |offset: u64, size: u64| slice(data, offset, size);
+    let (_, _, stroff, strsize) = header(shstrndx)?;
+    let names = contents(stroff, strsize)?;
+    for i in 0..shnum {
+        let (name_off, addr, offset, size) = header(i)?;
+        let section_name = names.get(name_off as usize..).unwrap_or(&[]);
+        let end = section_name.iter().position(|&b| b == 0).unwrap_or(section_name.len());
+        if &section_name[..end] == name.as_bytes() {
+            return Ok(Some((addr, contents(offset, size)?)))
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sctl::deferred::{decode, Arg, Encoder};
+    use sctl::Tag;
+
+    // Builds a minimal ELF32 file with a section name table and one
+    // `.sctl.fmt` section at `addr`.
+    fn elf32(addr: u32, contents: &[u8]) -> Vec<u8> {
+        let names = b"\0.shstrtab\0.sctl.fmt\0";
+        let mut data = vec![0u8; 52];
+        data[..6].copy_from_slice(b"\x7fELF\x01\x01");
+        let names_off = data.len() as u32;
+        data.extend_from_slice(names);
+        let fmt_off = data.len() as u32;
+        data.extend_from_slice(contents);
+        let shoff = data.len() as u32;
+        let mut section = |name: u32, addr: u32, offset: u32, size: u32| {
+            let mut h = [0u8; 40];
+            h[0..4].copy_from_slice(&name.to_le_bytes());
+            h[12..16].copy_from_slice(&addr.to_le_bytes());
+            h[16..20].copy_from_slice(&offset.to_le_bytes());
+            h[20..24].copy_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&h);
+        };
+        section(0, 0, 0, 0);
+        section(1, 0, names_off, names.len() as u32);
+        section(11, addr, fmt_off, contents.len() as u32);
+        data[0x20..0x24].copy_from_slice(&shoff.to_le_bytes());
+        data[0x2e..0x30].copy_from_slice(&40u16.to_le_bytes());
+        data[0x30..0x32].copy_from_slice(&3u16.to_le_bytes());
+        data[0x32..0x34].copy_from_slice(&1u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_elf() {
+        let table = StringTable::load(&elf32(0x100, b"boot\0x = {=i32}\0")).unwrap();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(0x100), Some("boot"));
+        assert_eq!(table.get(0x105), Some("x = {=i32}"));
+        assert!(StringTable::load(&elf32(0, b"")).unwrap().is_empty());
+        assert!(StringTable::load(b"\x7fELF\x01\x02").is_err());
+    }
+
+    #[test]
+    fn test_elf_corrupt() {
+        // Cut off anywhere up to the end of the last header field read.
+        let data = elf32(0x100, b"boot\0");
+        for len in 0..data.len() - 16 {
+            let e = StringTable::from_elf(&data[..len]).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "length {}", len);
+        }
+
+        // The format section runs past the end of the file.
+        let mut bad = data.clone();
+        let fmt = bad.len() - 40;
+        bad[fmt + 16..fmt + 24].copy_from_slice(&[0xf0, 0xff, 0xff, 0xff, 0x20, 0, 0, 0]);
+        assert_eq!(StringTable::from_elf(&bad).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // ELF64 offsets that overflow.
+        let mut wide = vec![0u8; 64];
+        wide[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        wide[0x28..0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+        wide[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        wide[0x3c..0x3e].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(StringTable::from_elf(&wide).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        wide[0x28..0x30].copy_from_slice(&0u64.to_le_bytes());
+        wide[0x3e..0x40].copy_from_slice(&0xffffu16.to_le_bytes());
+        wide[0x3a..0x3c].copy_from_slice(&0xffffu16.to_le_bytes());
+        assert_eq!(StringTable::from_elf(&wide).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let mut table = StringTable::new();
+        table.insert(0, "one\ntwo");
+        table.insert(0x20, "back\\slash {=u32}");
+        let text = table.to_sidecar();
+        assert_eq!(text, "0x00000000 one\\ntwo\n0x00000020 back\\\\slash {=u32}\n");
+        assert_eq!(StringTable::load(text.as_bytes()).unwrap(), table);
+        assert!(StringTable::from_sidecar("zz fmt").is_err());
+
+        // Escapes are undone in one pass, so a backslash followed by `n`
+        // stays that way.
+        let mut table = StringTable::new();
+        table.insert(1, "a\\n b\\\n c\\");
+        table.insert(2, "path C:\\new\\\\");
+        let text = table.to_sidecar();
+        assert_eq!(text, "0x00000001 a\\\\n b\\\\\\n c\\\\\n0x00000002 path C:\\\\new\\\\\\\\\n");
+        assert_eq!(StringTable::from_sidecar(&text).unwrap(), table);
+        assert_eq!(StringTable::from_sidecar("0x3 odd \\q \\").unwrap().get(3), Some("odd \\q \\"));
+    }
+
+    #[test]
+    fn test_format() {
+        let mut table = StringTable::new();
+        table.insert(1, "{=str}: {=i32} {=u32} {=u32:x} {=bool} {{literal}}");
+        table.insert(2, "{=f32}");
+
+        let mut buf = [0u8; 64];
+        let mut e = Encoder::new(&mut buf, Tag::Info, 1).unwrap();
+        "temp".encode(&mut e).unwrap();
+        (-40i32).encode(&mut e).unwrap();
+        7u8.encode(&mut e).unwrap();
+        0xbeefu32.encode(&mut e).unwrap();
+        false.encode(&mut e).unwrap();
+        let (_, id, mut args) = decode(e.finish()).unwrap();
+        assert_eq!(table.format(id, &mut args), Ok("temp: -40 7 0xbeef false {literal}".to_string()));
+
+        let (_, _, mut args) = decode(&[0x22, 0x01]).unwrap();
+        assert_eq!(table.format(1, &mut args), Err(FormatError::BadArgs(Error::InvalidRecord)));
+        assert_eq!(table.format(2, &mut args), Err(FormatError::BadFormat));
+        assert_eq!(table.format(3, &mut args), Err(FormatError::UnknownId(3)));
+
+        // Braces are unescaped before a placeholder as well as after one.
+        table.insert(4, "a}} {=u32} {{b}}");
+        let mut e = Encoder::new(&mut buf, Tag::Info, 4).unwrap();
+        5u32.encode(&mut e).unwrap();
+        let (_, id, mut args) = decode(e.finish()).unwrap();
+        assert_eq!(table.format(id, &mut args), Ok("a} 5 {b}".to_string()));
+    }
+}
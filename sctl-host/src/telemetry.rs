@@ -0,0 +1,241 @@
+//! Export of telemetry samples as CSV or JSON Lines.
+//!
+//! Every sample becomes one row with the time it was taken, the signal's
+//! name, its value and unit:
+//!
+//! ```text
+//! time,since_boot,signal,value,unit
+//! 2026-10-18 11:24:06.500000,1.500000,temp,21.5,degC
+//! ```
+//!
+//! ```text
+//! {"time":"2026-10-18 11:24:06.500000","since_boot":1.5,"signal":"temp","value":21.5,"unit":"degC"}
+//! ```
+//!
+//! Times come from the device timestamp as for the console, falling back
+//! to when the host received the sample; `since_boot` is left empty (or
+//! `null`) without one. A sample of an undeclared signal can't be decoded,
+//! so it is dropped along with the rest of its message.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::SystemTime;
+
+use sctl::telemetry::{Kind, SampleReader, Signal, Value};
+use sctl::{Hello, Message};
+
+use time::{self, Timeline};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Csv,
+    JsonLines,
+}
+
+struct Declared {
+    name: String,
+    kind: Kind,
+    unit: String,
+}
+
+pub struct Exporter<W: Write> {
+    out: W,
+    format: Format,
+    timeline: Timeline,
+    signals: BTreeMap<u32, Declared>,
+    header: bool,
+    dropped: usize,
+}
+
+// Quotes a CSV field if it needs it.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn format_value(value: Value, format: Format) -> String {
+    match value {
+        Value::U32(v) => v.to_string(),
+        Value::I32(v) => v.to_string(),
+        // JSON has no NaN or infinities.
+        Value::F32(v) if !v.is_finite() && format == Format::JsonLines => String::from("null"),
+        Value::F32(v) => v.to_string(),
+        Value::Bool(v) => v.to_string(),
+    }
+}
+
+impl<W: Write> Exporter<W> {
+    pub fn new(out: W, format: Format) -> Self {
+        Exporter { out, format, timeline: Timeline::new(), signals: BTreeMap::new(), header: false, dropped: 0 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Returns the number of `Sample` messages cut short by a signal that
+    /// hadn't been declared.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Handles a message read at `now`, along with the timestamp the
+    /// `Reader` returned for it. Messages other than `Boot`, `Signal` and
+    /// `Sample` are ignored.
+    pub fn message(&mut self, msg: &Message, ticks: Option<u32>, now: SystemTime) -> io::Result<()> {
+        match *msg {
+            Message::Boot(value) => {
+                let tick_rate = Hello::decode(value).map_or(0, |h| h.tick_rate);
+                self.timeline.boot(tick_rate, ticks, now);
+                // A rebooted device declares its signals again.
+                self.signals.clear();
+                Ok(())
+            }
+            Message::Signal(_) => {
+                if let Ok(Some(signal)) = Signal::from_message(msg) {
+                    let declared = Declared { name: signal.name.to_string(), kind: signal.kind, unit: signal.unit.to_string() };
+                    self.signals.insert(signal.id, declared);
+                }
+                Ok(())
+            }
+            Message::Sample(value) => self.samples(value, ticks, now),
+            _ => Ok(()),
+        }
+    }
+
+    fn samples(&mut self, value: &[u8], ticks: Option<u32>, now: SystemTime) -> io::Result<()> {
+        let stamp = ticks.and_then(|t| self.timeline.stamp(t));
+        let wall = time::format_utc(stamp.map_or(now, |s| s.wall));
+        let since_boot = stamp.map(|s| time::format_secs(s.since_boot));
+        let mut r = SampleReader::new(value);
+        loop {
+            let (id, value) = {
+                let signals = &self.signals;
+                match r.next(|id| signals.get(&id).map(|s| s.kind)) {
+                    Ok(Some(sample)) => sample,
+                    Ok(None) => return Ok(()),
+                    Err(_) => {
+                        self.dropped += 1;
+                        return Ok(())
+                    }
+                }
+            };
+            self.row(&wall, since_boot.as_deref(), id, value)?;
+        }
+    }
+
+    fn row(&mut self, wall: &str, since_boot: Option<&str>, id: u32, value: Value) -> io::Result<()> {
+        let signal = &self.signals[&id];
+        let value = format_value(value, self.format);
+        match self.format {
+            Format::Csv => {
+                if !self.header {
+                    writeln!(self.out, "time,since_boot,signal,value,unit")?;
+                    self.header = true;
+                }
+                writeln!(self.out, "{},{},{},{},{}", wall, since_boot.unwrap_or(""), csv_field(&signal.name), value,
+                    csv_field(&signal.unit))
+            }
+            Format::JsonLines => {
+                // Strip the padding `format_secs` adds to keep it a number.
+                let since_boot = since_boot.map_or_else(|| String::from("null"), |s| {
+                    s.trim_end_matches('0').trim_end_matches('.').to_string()
+                });
+                writeln!(self.out, "{{\"time\":\"{}\",\"since_boot\":{},\"signal\":{},\"value\":{},\"unit\":{}}}", wall,
+                    since_boot, json_string(&signal.name), value, json_string(&signal.unit))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sctl::hello::Features;
+    use sctl::telemetry::Samples;
+    use sctl::{Reader, Writer};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn export(format: Format) -> (String, usize) {
+        let hello = Hello {
+            version: 1,
+            device_id: &[0x42],
+            firmware: "0.1.0",
+            build: &[0xab],
+            max_frame: 256,
+            features: Features::NONE,
+            tick_rate: 1000,
+            nonce: &[],
+        };
+        let mut hbuf = [0u8; 64];
+        let mut wbuf = [0u8; 512];
+        let mut w = Writer::new(&mut wbuf);
+        w.time(0).unwrap();
+        w.boot(hello.encode(&mut hbuf).unwrap()).unwrap();
+        Signal { id: 1, name: "temp", kind: Kind::F32, unit: "degC" }.write(&mut w).unwrap();
+        Signal { id: 2, name: "fan, left", kind: Kind::Bool, unit: "" }.write(&mut w).unwrap();
+        let mut sbuf = [0u8; 32];
+        let mut samples = Samples::new(&mut sbuf);
+        samples.push(1, Value::F32(21.5)).unwrap();
+        samples.push(2, Value::Bool(true)).unwrap();
+        w.time(1500).unwrap();
+        samples.flush(&mut w).unwrap();
+        samples.push(3, Value::U32(7)).unwrap();
+        samples.flush(&mut w).unwrap();
+
+        let boot = UNIX_EPOCH + Duration::new(1_792_322_645, 0);
+        let mut exporter = Exporter::new(Vec::new(), format);
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 256];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            exporter.message(&msg, r.time(), boot).unwrap();
+        }
+        let dropped = exporter.dropped();
+        (String::from_utf8(exporter.into_inner()).unwrap(), dropped)
+    }
+
+    #[test]
+    fn test_csv() {
+        let (out, dropped) = export(Format::Csv);
+        assert_eq!(out, "\
+time,since_boot,signal,value,unit
+2026-10-18 11:24:06.500000,1.500000,temp,21.5,degC
+2026-10-18 11:24:06.500000,1.500000,\"fan, left\",true,
+");
+        assert_eq!(dropped, 1);
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let (out, _) = export(Format::JsonLines);
+        assert_eq!(out, "\
+{\"time\":\"2026-10-18 11:24:06.500000\",\"since_boot\":1.5,\"signal\":\"temp\",\"value\":21.5,\"unit\":\"degC\"}
+{\"time\":\"2026-10-18 11:24:06.500000\",\"since_boot\":1.5,\"signal\":\"fan, left\",\"value\":true,\"unit\":\"\"}
+");
+        assert_eq!(json_string("a\"b\\\u{1}"), "\"a\\\"b\\\\\\u0001\"");
+    }
+}
@@ -0,0 +1,139 @@
+//! Mapping device timestamps to time since boot and wall-clock time.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sctl::Clock;
+
+/// An `sctl::Clock` for the host, counting milliseconds since it was
+/// created.
+#[derive(Debug, Clone, Copy)]
+pub struct Millis(Instant);
+
+impl Default for Millis {
+    fn default() -> Self {
+        Millis(Instant::now())
+    }
+}
+
+impl Millis {
+    pub fn new() -> Self {
+        Millis::default()
+    }
+}
+
+impl Clock for Millis {
+    fn now(&self) -> u32 {
+        self.0.elapsed().as_millis() as u32
+    }
+}
+
+/// A device timestamp resolved against the device's boot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stamp {
+    pub since_boot: Duration,
+    pub wall: SystemTime,
+}
+
+/// Tracks the device clock from its `Boot` onwards. Device timestamps are
+/// 32-bit tick counts; they are assumed to arrive in order, so a smaller
+/// value than the last one means the counter wrapped.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    tick_rate: u32,
+    boot: Option<(u64, SystemTime)>,
+    last: u32,
+    high: u64,
+}
+
+impl Timeline {
+    pub fn new() -> Self {
+        Timeline::default()
+    }
+
+    /// Starts a new timeline at a `Boot` received at `wall`, with the
+    /// `Boot` message's own timestamp if it had one.
+    pub fn boot(&mut self, tick_rate: u32, ticks: Option<u32>, wall: SystemTime) {
+        let ticks = ticks.unwrap_or(0);
+        self.tick_rate = tick_rate;
+        self.last = ticks;
+        self.high = 0;
+        self.boot = Some((ticks as u64, wall));
+    }
+
+    /// Resolves a timestamp. Returns `None` before the first `Boot` or if
+    /// the device didn't declare a tick rate.
+    pub fn stamp(&mut self, ticks: u32) -> Option<Stamp> {
+        let (boot, wall) = self.boot?;
+        if self.tick_rate == 0 {
+            return None
+        }
+        if ticks < self.last {
+            self.high += 1 << 32;
+        }
+        self.last = ticks;
+        let elapsed = (self.high + ticks as u64).saturating_sub(boot);
+        let rate = self.tick_rate as u64;
+        let since_boot = Duration::new(elapsed / rate, ((elapsed % rate) * 1_000_000_000 / rate) as u32);
+        Some(Stamp { since_boot, wall: wall + since_boot })
+    }
+}
+
+// Converts days since 1970-01-01 to a (year, month, day) civil date.
+fn civil(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m as u32, d as u32)
+}
+
+/// Formats `t` as a UTC date and time with microseconds.
+pub fn format_utc(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs() as i64;
+    let (y, m, day) = civil(secs.div_euclid(86_400));
+    let s = secs.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}", y, m, day, s / 3600, s / 60 % 60, s % 60, d.subsec_micros())
+}
+
+/// Formats a duration as seconds with microseconds.
+pub fn format_secs(d: Duration) -> String {
+    format!("{}.{:06}", d.as_secs(), d.subsec_micros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(UNIX_EPOCH), "1970-01-01 00:00:00.000000");
+        assert_eq!(format_utc(UNIX_EPOCH + Duration::new(951_782_400, 5000)), "2000-02-29 00:00:00.000005");
+        assert_eq!(format_utc(UNIX_EPOCH + Duration::new(1_792_322_645, 0)), "2026-10-18 11:24:05.000000");
+    }
+
+    #[test]
+    fn test_timeline() {
+        let wall = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut t = Timeline::new();
+        assert_eq!(t.stamp(10), None);
+
+        t.boot(1000, Some(0xffff_ff00), wall);
+        let s = t.stamp(0xffff_ffff).unwrap();
+        assert_eq!(s.since_boot, Duration::from_millis(255));
+        assert_eq!(s.wall, wall + Duration::from_millis(255));
+        // The counter wraps.
+        assert_eq!(t.stamp(100).unwrap().since_boot, Duration::from_millis(356));
+
+        t.boot(32768, None, wall);
+        assert_eq!(t.stamp(49152).unwrap().since_boot, Duration::from_millis(1500));
+
+        t.boot(0, None, wall);
+        assert_eq!(t.stamp(1), None);
+    }
+}
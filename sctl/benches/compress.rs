@@ -0,0 +1,35 @@
+//! Compression throughput on log-like frames. Needs nightly:
+//! `cargo +nightly bench -p sctl`.
+
+#![feature(test)]
+
+extern crate sctl;
+extern crate test;
+
+use sctl::compress;
+use test::Bencher;
+
+const LOG: &[u8] = b"\
+[    12.000104] INFO  sensor: read temperature 21.5 C humidity 40%\n\
+[    12.000318] DEBUG i2c: write addr=0x48 len=2 status=ok\n\
+[    12.000522] DEBUG i2c: read addr=0x48 len=2 status=ok\n\
+[    12.010104] INFO  sensor: read temperature 21.6 C humidity 40%\n\
+[    12.010331] DEBUG i2c: write addr=0x48 len=2 status=ok\n\
+[    12.010540] DEBUG i2c: read addr=0x48 len=2 status=ok\n\
+[    12.020100] WARN  radio: retry 1 of 3 after timeout\n";
+
+#[bench]
+fn bench_compress(b: &mut Bencher) {
+    let mut packed = [0u8; 512];
+    b.bytes = LOG.len() as u64;
+    b.iter(|| compress::compress(test::black_box(LOG), &mut packed));
+}
+
+#[bench]
+fn bench_decompress(b: &mut Bencher) {
+    let mut packed = [0u8; 512];
+    let n = compress::compress(LOG, &mut packed).unwrap();
+    let mut out = [0u8; 512];
+    b.bytes = LOG.len() as u64;
+    b.iter(|| compress::decompress(test::black_box(&packed[..n]), &mut out));
+}
@@ -0,0 +1,415 @@
+//! Stop-and-wait ARQ for sctl links.
+//!
+//! A reliable frame starts with a `Frame(seq)` message followed by the
+//! messages it carries. The receiver answers every reliable frame with
+//! `Ack(seq)`, delivers each sequence number once and in order, and sends
+//! `Nak(expected)` when a frame arrives ahead of the one it is waiting for.
+//! The sender keeps one frame outstanding and retransmits it on `Nak` or
+//! when the retransmit timeout expires.
+//!
+//! When `Sender::poll` gives up on a frame with `Error::Timeout`, the frame
+//! is dropped and the sender moves on to the next sequence number, whether
+//! or not the frame arrived. Its next frame starts with `Sync(seq)`, which
+//! tells the receiver to accept that frame even if the one before it never
+//! arrived.
+
+use cobs;
+use tlv;
+
+use {Clock, Error, Message, Tag, Writer};
+
+pub struct Sender<'a, C: Clock> {
+    clock: C,
+    buf: &'a mut [u8],
+    len: usize,
+    seq: u8,
+    outstanding: bool,
+    resend: bool,
+    sync: bool,
+    sent: u32,
+    timeout: u32,
+    retries: u8,
+    max_retries: u8,
+}
+
+impl<'a, C: Clock> Sender<'a, C> {
+    /// Creates a sender that keeps the outstanding frame in `buf`, which
+    /// must hold the largest frame plus six bytes of header.
+    pub fn new(clock: C, buf: &'a mut [u8], timeout: u32, max_retries: u8) -> Self {
+        Sender {
+            clock,
+            buf,
+            len: 0,
+            seq: 0,
+            outstanding: false,
+            resend: false,
+            sync: false,
+            sent: 0,
+            timeout,
+            retries: 0,
+            max_retries,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.seq = 0;
+        self.outstanding = false;
+        self.resend = false;
+        self.sync = false;
+    }
+
+    /// Returns true if no frame is waiting to be acknowledged.
+    pub fn is_ready(&self) -> bool {
+        !self.outstanding
+    }
+
+    /// Takes the messages written to `w` as the next reliable frame and
+    /// returns its COBS encoding in `dst`, ready to transmit. Fails with
+    /// `Error::Busy` while the previous frame is unacknowledged.
+    pub fn send<'b>(&mut self, w: &mut Writer, dst: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        if self.outstanding {
+            return Err(Error::Busy)
+        }
+        let len = {
+            let mut tw = tlv::Writer::new(self.buf);
+            if self.sync {
+                tw.write_tlv8(Tag::Sync as u32, &[self.seq])?;
+            }
+            tw.write_tlv8(Tag::Frame as u32, &[self.seq])?;
+            tw.write(w.as_ref())?;
+            tw.pos()
+        };
+        w.clear();
+        self.len = len;
+        self.outstanding = true;
+        self.retries = 0;
+        self.transmit(dst)
+    }
+
+    /// Handles `Ack` and `Nak` messages from the receiver. Returns true if
+    /// `msg` was one of them.
+    pub fn receive(&mut self, msg: &Message) -> bool {
+        match *msg {
+            Message::Ack(seq) => {
+                if self.outstanding && seq == self.seq {
+                    self.outstanding = false;
+                    self.resend = false;
+                    self.sync = false;
+                    self.seq = self.seq.wrapping_add(1);
+                }
+                true
+            }
+            Message::Nak(seq) => {
+                if self.outstanding && seq == self.seq {
+                    self.resend = true;
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the outstanding frame if it is due for retransmission. Fails
+    /// with `Error::Timeout`, dropping the frame and moving on to the next
+    /// sequence number, once `max_retries` retransmissions have gone
+    /// unacknowledged.
+    pub fn poll<'b>(&mut self, dst: &'b mut [u8]) -> Result<Option<&'b [u8]>, Error> {
+        if !self.outstanding {
+            return Ok(None)
+        }
+        if !self.resend && self.clock.now().wrapping_sub(self.sent) < self.timeout {
+            return Ok(None)
+        }
+        if self.retries == self.max_retries {
+            self.outstanding = false;
+            self.resend = false;
+            self.sync = true;
+            self.seq = self.seq.wrapping_add(1);
+            return Err(Error::Timeout)
+        }
+        self.retries += 1;
+        self.transmit(dst).map(Some)
+    }
+
+    fn transmit<'b>(&mut self, dst: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let len = {
+            let mut w = cobs::Writer::new(dst);
+            w.encode_packet(&self.buf[..self.len])?
+        };
+        self.resend = false;
+        self.sent = self.clock.now();
+        Ok(&dst[..len])
+    }
+}
+
+#[derive(Default)]
+pub struct Receiver {
+    expected: u8,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Receiver { expected: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.expected = 0;
+    }
+
+    /// Accepts a decoded frame, writing the `Ack` or `Nak` it calls for to
+    /// `w`. Returns the messages the frame carries if they should be
+    /// delivered, or `None` for duplicate and out-of-order frames. Frames
+    /// without a `Frame` header are passed through unchanged.
+    ///
+    /// A frame that starts with `Sync` is accepted even if it is ahead of
+    /// the expected one, since the sender gave up on the frames in between.
+    pub fn receive<'f>(&mut self, frame: &'f [u8], w: &mut Writer) -> Result<Option<&'f [u8]>, Error> {
+        let mut r = tlv::Reader::new(frame);
+        let mut record = r.read_tlv8_ref()?;
+        let mut sync = None;
+        if let Some((tag, &[seq])) = record {
+            if tag == Tag::Sync as u32 {
+                sync = Some(seq);
+                record = r.read_tlv8_ref()?;
+            }
+        }
+        let seq = match record {
+            Some((tag, &[seq])) if tag == Tag::Frame as u32 => seq,
+            _ => return Ok(Some(frame)),
+        };
+        let payload = &frame[r.pos()..];
+        let behind = self.expected.wrapping_sub(seq);
+        if seq == self.expected || (sync == Some(seq) && behind > 128) {
+            w.ack(seq)?;
+            self.expected = seq.wrapping_add(1);
+            Ok(Some(payload))
+        } else if behind <= 128 {
+            // Already delivered; the sender missed our ack.
+            w.ack(seq)?;
+            Ok(None)
+        } else {
+            w.nak(self.expected)?;
+            Ok(None)
+        }
+    }
+
+    /// Asks for the expected frame to be sent again, e.g. after a frame
+    /// failed to decode.
+    pub fn nak(&self, w: &mut Writer) -> Result<usize, Error> {
+        w.nak(self.expected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+    use Reader;
+
+    // Delivers COBS frames after dropping, duplicating and reordering some of
+    // them, chosen by a fixed xorshift sequence.
+    struct LossyChannel {
+        state: u32,
+        queue: VecDeque<Vec<u8>>,
+    }
+
+    impl LossyChannel {
+        fn new(seed: u32) -> Self {
+            LossyChannel { state: seed, queue: VecDeque::new() }
+        }
+
+        fn random(&mut self) -> u32 {
+            self.state ^= self.state << 13;
+            self.state ^= self.state >> 17;
+            self.state ^= self.state << 5;
+            self.state % 100
+        }
+
+        fn send(&mut self, frame: &[u8]) {
+            match self.random() {
+                0..=24 => {}
+                25..=39 => {
+                    self.queue.push_back(frame.to_vec());
+                    self.queue.push_back(frame.to_vec());
+                }
+                40..=54 => self.queue.push_front(frame.to_vec()),
+                _ => self.queue.push_back(frame.to_vec()),
+            }
+        }
+
+        fn recv(&mut self) -> Option<Vec<u8>> {
+            self.queue.pop_front()
+        }
+    }
+
+    fn decode(frame: &[u8]) -> Vec<u8> {
+        let mut out = vec![0u8; frame.len()];
+        let n = cobs::decode(&frame[..frame.len() - 1], &mut out).unwrap();
+        out.truncate(n);
+        out
+    }
+
+    #[test]
+    fn test_receiver() {
+        let mut rx = Receiver::new();
+        let mut abuf = [0u8; 64];
+        let mut a = Writer::new(&mut abuf);
+        assert_eq!(rx.receive(&[0x41, 0x01, 0x00, 0x11, 0x01, b'a'], &mut a), Ok(Some(&[0x11, 0x01, b'a'][..])));
+        assert_eq!(rx.receive(&[0x41, 0x01, 0x00, 0x11, 0x01, b'a'], &mut a), Ok(None));
+        assert_eq!(rx.receive(&[0x41, 0x01, 0x02], &mut a), Ok(None));
+        assert_eq!(rx.receive(&[0x11, 0x01, b'b'], &mut a), Ok(Some(&[0x11, 0x01, b'b'][..])));
+        rx.nak(&mut a).unwrap();
+
+        let mut r = Reader::new(a.as_ref());
+        let mut tmp = [0u8; 16];
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Ack(0))));
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Ack(0))));
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Nak(1))));
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Nak(1))));
+        assert_eq!(r.read(&mut tmp), Ok(None));
+    }
+
+    #[test]
+    fn test_sender_retries() {
+        let clock = Cell::new(0);
+        let mut sbuf = [0u8; 64];
+        let mut tx = Sender::new(&clock, &mut sbuf, 10, 2);
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        let mut dst = [0u8; 80];
+
+        w.stdout(b"x").unwrap();
+        let first = tx.send(&mut w, &mut dst).unwrap().to_vec();
+        assert_eq!(w.as_ref().len(), 0);
+        assert!(!tx.is_ready());
+        assert_eq!(tx.send(&mut w, &mut dst), Err(Error::Busy));
+        assert_eq!(tx.poll(&mut dst), Ok(None));
+
+        clock.set(10);
+        assert_eq!(tx.poll(&mut dst), Ok(Some(&first[..])));
+        assert!(tx.receive(&Message::Nak(0)));
+        assert_eq!(tx.poll(&mut dst), Ok(Some(&first[..])));
+        clock.set(20);
+        assert_eq!(tx.poll(&mut dst), Err(Error::Timeout));
+        assert!(tx.is_ready());
+        assert_eq!(tx.poll(&mut dst), Ok(None));
+
+        // The next frame moves on to the next sequence number and says so.
+        // Stale and unrelated acknowledgements are ignored.
+        let second = decode(tx.send(&mut w, &mut dst).unwrap());
+        assert_eq!(second, [0x48, 0x01, 0x01, 0x41, 0x01, 0x01]);
+        assert!(tx.receive(&Message::Ack(0)));
+        assert!(!tx.is_ready());
+        assert!(!tx.receive(&Message::Stdout(b"")));
+        assert!(tx.receive(&Message::Ack(1)));
+        assert!(tx.is_ready());
+        assert_eq!(decode(tx.send(&mut w, &mut dst).unwrap()), [0x41, 0x01, 0x02]);
+    }
+
+    #[test]
+    fn test_give_up() {
+        let clock = Cell::new(0);
+        let mut sbuf = [0u8; 64];
+        let mut tx = Sender::new(&clock, &mut sbuf, 10, 1);
+        let mut rx = Receiver::new();
+        let mut dst = [0u8; 80];
+        let mut abuf = [0u8; 64];
+        let mut a = Writer::new(&mut abuf);
+        let mut send = |tx: &mut Sender<&Cell<u32>>, text: &[u8]| {
+            let mut wbuf = [0u8; 64];
+            let mut w = Writer::new(&mut wbuf);
+            w.stdout(text).unwrap();
+            decode(tx.send(&mut w, &mut dst).unwrap())
+        };
+
+        // The first frame arrives but every ack is lost.
+        let first = send(&mut tx, b"one");
+        assert_eq!(rx.receive(&first, &mut a), Ok(Some(&[0x11, 0x03, b'o', b'n', b'e'][..])));
+        clock.set(10);
+        let mut pbuf = [0u8; 80];
+        assert_eq!(rx.receive(&decode(tx.poll(&mut pbuf).unwrap().unwrap()), &mut a), Ok(None));
+        clock.set(20);
+        assert_eq!(tx.poll(&mut pbuf), Err(Error::Timeout));
+
+        // The next frame is still delivered.
+        let second = send(&mut tx, b"two");
+        assert_eq!(rx.receive(&second, &mut a), Ok(Some(&[0x11, 0x03, b't', b'w', b'o'][..])));
+        tx.receive(&Message::Ack(1));
+
+        // So is the one after a frame that never arrived, but not a late
+        // copy of it.
+        let lost = send(&mut tx, b"lost");
+        clock.set(30);
+        tx.poll(&mut pbuf).unwrap().unwrap();
+        clock.set(40);
+        assert_eq!(tx.poll(&mut pbuf), Err(Error::Timeout));
+        let fourth = send(&mut tx, b"four");
+        assert_eq!(rx.receive(&fourth, &mut a), Ok(Some(&[0x11, 0x04, b'f', b'o', b'u', b'r'][..])));
+        assert_eq!(rx.receive(&fourth, &mut a), Ok(None));
+        assert_eq!(rx.receive(&lost, &mut a), Ok(None));
+
+        // Without `Sync`, a frame ahead of the expected one is refused.
+        assert_eq!(rx.receive(&[0x41, 0x01, 0x07], &mut a), Ok(None));
+        assert_eq!(rx.receive(&[0x48, 0x01, 0x06, 0x41, 0x01, 0x07], &mut a), Ok(None));
+        assert_eq!(rx.receive(&[0x48, 0x01, 0x07, 0x41, 0x01, 0x07], &mut a), Ok(Some(&[][..])));
+    }
+
+    #[test]
+    fn test_lossy_channel() {
+        let clock = Cell::new(0);
+        let mut sbuf = [0u8; 64];
+        let mut tx = Sender::new(&clock, &mut sbuf, 4, 255);
+        let mut rx = Receiver::new();
+        let mut down = LossyChannel::new(0x1234_5678);
+        let mut up = LossyChannel::new(0x9abc_def0);
+        let mut received = Vec::new();
+        let mut next = 0u32;
+        let mut dst = [0u8; 80];
+
+        while received.len() < 200 {
+            assert!(clock.get() < 100_000, "no progress");
+            clock.set(clock.get() + 1);
+
+            if tx.is_ready() && next < 200 {
+                let mut wbuf = [0u8; 64];
+                let mut w = Writer::new(&mut wbuf);
+                w.stdout(&next.to_le_bytes()).unwrap();
+                down.send(tx.send(&mut w, &mut dst).unwrap());
+                next += 1;
+            }
+            if let Some(frame) = tx.poll(&mut dst).unwrap() {
+                down.send(frame);
+            }
+
+            if let Some(frame) = down.recv() {
+                let frame = decode(&frame);
+                let mut abuf = [0u8; 16];
+                let mut a = Writer::new(&mut abuf);
+                if let Some(payload) = rx.receive(&frame, &mut a).unwrap() {
+                    let mut r = Reader::new(payload);
+                    let mut tmp = [0u8; 16];
+                    while let Some(msg) = r.read(&mut tmp).unwrap() {
+                        match msg {
+                            Message::Stdout(v) => received.push(u32::from_le_bytes([v[0], v[1], v[2], v[3]])),
+                            other => panic!("unexpected {:?}", other),
+                        }
+                    }
+                }
+                let mut out = [0u8; 32];
+                up.send(a.encode(&mut out).unwrap());
+            }
+
+            if let Some(frame) = up.recv() {
+                let frame = decode(&frame);
+                let mut r = Reader::new(&frame);
+                let mut tmp = [0u8; 16];
+                while let Some(msg) = r.read(&mut tmp).unwrap() {
+                    assert!(tx.receive(&msg));
+                }
+            }
+        }
+        assert_eq!(received, (0..200).collect::<Vec<u32>>());
+    }
+}
@@ -0,0 +1,378 @@
+//! Numbered byte streams multiplexed over one sctl link.
+//!
+//! A `Channel` message carries an atlv8 record: the address is the one-byte
+//! channel number, the tag is an `Op` and the value is either stream data or
+//! a LEB128 credit grant.
+//!
+//! Flow control is optional and per channel. A `ChannelReader` grants the
+//! sender credit for the space it has free, and a `ChannelWriter` created
+//! with `with_flow_control` never sends more than it has been granted. The
+//! first grant covers the reader's whole buffer.
+//!
+//! `Allowance` keeps the count of credit on either side, and is shared with
+//! the stdio flow control in `flow`.
+
+use core::{cmp, mem};
+
+use record;
+use tlv;
+
+use {Error, Message, Writer};
+
+/// Largest amount of stream data carried by one `Channel` message.
+pub const MAX_DATA: usize = 255 - 4;
+
+/// Credit granted but not yet spent. Grants saturate rather than overflow,
+/// however much a peer hands out.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Allowance(u32);
+
+impl Allowance {
+    pub fn new(amount: u32) -> Self {
+        Allowance(amount)
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+
+    pub fn add(&mut self, amount: u32) {
+        self.0 = self.0.saturating_add(amount);
+    }
+
+    /// Spends up to `amount`, returning how much was spent.
+    pub fn spend(&mut self, amount: u32) -> u32 {
+        let n = cmp::min(amount, self.0);
+        self.0 -= n;
+        n
+    }
+
+    /// Spends all of it, returning how much there was.
+    pub fn take(&mut self) -> u32 {
+        mem::replace(&mut self.0, 0)
+    }
+}
+
+pub enum Op {
+    Data = 0x0,
+    Credit = 0x1,
+}
+
+/// Splits the value of a `Channel` message into channel number, op and
+/// payload.
+pub fn decode(value: &[u8]) -> Result<(u8, u32, &[u8]), Error> {
+    let mut r = tlv::Reader::new(value);
+    let addr = r.read_lv8_ref()?;
+    let op = r.read_tag()?;
+    let payload = r.read_lv8_ref()?;
+    match (addr, op, payload) {
+        (Some(addr), Some(op), Some(payload)) if addr.len() == 1 && r.remaining() == 0 => Ok((addr[0], op, payload)),
+        _ => Err(Error::InvalidRecord),
+    }
+}
+
+fn write_op(w: &mut Writer, id: u8, op: Op, value: &[u8]) -> Result<usize, Error> {
+    let mut buf = [0u8; 255];
+    let len = {
+        let mut tw = tlv::Writer::new(&mut buf);
+        tw.write_atlv8(&[id], op as u32, value)?;
+        tw.pos()
+    };
+    w.channel(&buf[..len])
+}
+
+pub struct ChannelWriter<'a> {
+    id: u8,
+    buf: &'a mut [u8],
+    len: usize,
+    credit: Option<Allowance>,
+}
+
+impl<'a> ChannelWriter<'a> {
+    pub fn new(id: u8, buf: &'a mut [u8]) -> Self {
+        ChannelWriter { id, buf, len: 0, credit: None }
+    }
+
+    /// Creates a writer that only sends data the reader has granted credit
+    /// for. It starts with no credit.
+    pub fn with_flow_control(id: u8, buf: &'a mut [u8]) -> Self {
+        ChannelWriter { id, buf, len: 0, credit: Some(Allowance::new(0)) }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Returns the number of bytes buffered but not yet sent.
+    pub fn pending(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the remaining credit, or `None` without flow control.
+    pub fn credit(&self) -> Option<usize> {
+        self.credit.map(|credit| credit.get() as usize)
+    }
+
+    /// Buffers as much of `data` as fits, returning the number of bytes
+    /// taken.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let n = cmp::min(data.len(), self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+        self.len += n;
+        n
+    }
+
+    /// Sends buffered data as `Channel` messages, as far as credit allows.
+    /// Returns the number of bytes sent.
+    pub fn flush(&mut self, w: &mut Writer) -> Result<usize, Error> {
+        let mut sent = 0;
+        let result = loop {
+            let mut n = cmp::min(self.len - sent, MAX_DATA);
+            if let Some(credit) = self.credit() {
+                n = cmp::min(n, credit);
+            }
+            if n == 0 {
+                break Ok(sent)
+            }
+            if let Err(e) = write_op(w, self.id, Op::Data, &self.buf[sent..sent + n]) {
+                break Err(e)
+            }
+            if let Some(ref mut credit) = self.credit {
+                credit.spend(n as u32);
+            }
+            sent += n;
+        };
+        self.buf.copy_within(sent..self.len, 0);
+        self.len -= sent;
+        result
+    }
+
+    fn add_credit(&mut self, n: u32) {
+        if let Some(ref mut credit) = self.credit {
+            credit.add(n);
+        }
+    }
+}
+
+pub struct ChannelReader<'a> {
+    id: u8,
+    buf: &'a mut [u8],
+    head: usize,
+    len: usize,
+    freed: Allowance,
+    overrun: usize,
+}
+
+impl<'a> ChannelReader<'a> {
+    pub fn new(id: u8, buf: &'a mut [u8]) -> Self {
+        let freed = Allowance::new(cmp::min(buf.len(), u32::MAX as usize) as u32);
+        ChannelReader { id, buf, head: 0, len: 0, freed, overrun: 0 }
+    }
+
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Returns the number of bytes waiting to be read.
+    pub fn available(&self) -> usize {
+        self.len
+    }
+
+    /// Returns the number of received bytes dropped because the buffer was
+    /// full.
+    pub fn overrun(&self) -> usize {
+        self.overrun
+    }
+
+    /// Buffers received data, returning the number of bytes kept.
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        let cap = self.buf.len();
+        let n = cmp::min(data.len(), cap - self.len);
+        for (i, &b) in data[..n].iter().enumerate() {
+            self.buf[(self.head + self.len + i) % cap] = b;
+        }
+        self.len += n;
+        self.overrun += data.len() - n;
+        n
+    }
+
+    /// Moves buffered data into `out`, returning the number of bytes read.
+    pub fn read(&mut self, out: &mut [u8]) -> usize {
+        let cap = self.buf.len();
+        let n = cmp::min(out.len(), self.len);
+        for (i, b) in out[..n].iter_mut().enumerate() {
+            *b = self.buf[(self.head + i) % cap];
+        }
+        if cap > 0 {
+            self.head = (self.head + n) % cap;
+        }
+        self.len -= n;
+        self.freed.add(n as u32);
+        n
+    }
+
+    /// Grants the sender credit for the space freed since the last grant.
+    pub fn grant(&mut self, w: &mut Writer) -> Result<usize, Error> {
+        if self.freed.get() == 0 {
+            return Ok(0)
+        }
+        let mut tmp = [0u8; 5];
+        let len = {
+            let mut lw = ::leb128::Writer::new(&mut tmp);
+            lw.write_u32(self.freed.get())?;
+            lw.pos()
+        };
+        let n = write_op(w, self.id, Op::Credit, &tmp[..len])?;
+        self.freed.take();
+        Ok(n)
+    }
+}
+
+/// Routes a `Channel` message to the reader or writer for its channel.
+/// Data for unknown channels is dropped. Returns `false` if `msg` is not a
+/// `Channel` message.
+pub fn dispatch(msg: &Message, readers: &mut [ChannelReader], writers: &mut [ChannelWriter]) -> Result<bool, Error> {
+    let value = match *msg {
+        Message::Channel(value) => value,
+        _ => return Ok(false),
+    };
+    let (id, op, payload) = decode(value)?;
+    match op {
+        0x0 => {
+            if let Some(r) = readers.iter_mut().find(|r| r.id == id) {
+                r.push(payload);
+            }
+        }
+        0x1 => {
+            let n = record::to_u32(payload)?;
+            if let Some(w) = writers.iter_mut().find(|w| w.id == id) {
+                w.add_credit(n);
+            }
+        }
+        _ => {}
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use Reader;
+
+    // Moves every message written to `from` through a Reader and dispatch.
+    fn deliver(from: &mut Writer, readers: &mut [ChannelReader], writers: &mut [ChannelWriter]) {
+        {
+            let mut r = Reader::new(from.as_ref());
+            let mut tmp = [0u8; 256];
+            while let Some(msg) = r.read(&mut tmp).unwrap() {
+                assert!(dispatch(&msg, readers, writers).unwrap());
+            }
+        }
+        from.clear();
+    }
+
+    #[test]
+    fn test_channel_decode() {
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        write_op(&mut w, 7, Op::Data, b"abc").unwrap();
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 256];
+        match r.read(&mut tmp).unwrap() {
+            Some(Message::Channel(value)) => assert_eq!(decode(value), Ok((7, 0, &b"abc"[..]))),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(decode(&[1, 7, 0]), Err(Error::InvalidRecord));
+        assert_eq!(decode(&[2, 7, 7, 0, 0]), Err(Error::InvalidRecord));
+    }
+
+    #[test]
+    fn test_channels() {
+        let (mut c_out, mut t_out, mut f_out) = ([0u8; 64], [0u8; 64], [0u8; 600]);
+        let mut device = [
+            ChannelWriter::new(1, &mut c_out),
+            ChannelWriter::new(2, &mut t_out),
+            ChannelWriter::with_flow_control(3, &mut f_out),
+        ];
+        let (mut c_in, mut t_in, mut f_in) = ([0u8; 64], [0u8; 64], [0u8; 100]);
+        let mut host = [
+            ChannelReader::new(1, &mut c_in),
+            ChannelReader::new(2, &mut t_in),
+            ChannelReader::new(3, &mut f_in),
+        ];
+
+        let image: Vec<u8> = (0..500).map(|i| i as u8).collect();
+        assert_eq!(device[0].write(b"hello "), 6);
+        assert_eq!(device[1].write(&[0x01, 0x02]), 2);
+        assert_eq!(device[2].write(&image), 500);
+        assert_eq!(device[0].write(b"world"), 5);
+
+        let mut dbuf = [0u8; 1024];
+        let mut d = Writer::new(&mut dbuf);
+        let mut hbuf = [0u8; 64];
+        let mut h = Writer::new(&mut hbuf);
+
+        // Without credit only the unregulated channels flow.
+        for c in device.iter_mut() {
+            c.flush(&mut d).unwrap();
+        }
+        assert_eq!(device[2].pending(), 500);
+        deliver(&mut d, &mut host, &mut []);
+        let mut out = [0u8; 64];
+        assert_eq!(host[0].read(&mut out), 11);
+        assert_eq!(&out[..11], b"hello world");
+        assert_eq!(host[1].read(&mut out), 2);
+        assert_eq!(&out[..2], &[0x01, 0x02]);
+
+        // A slow consumer reading 30 bytes at a time never overruns.
+        let mut received = Vec::new();
+        while received.len() < image.len() {
+            for r in host.iter_mut() {
+                r.grant(&mut h).unwrap();
+            }
+            deliver(&mut h, &mut [], &mut device);
+            assert!(device[2].credit().unwrap() <= 100);
+            device[2].flush(&mut d).unwrap();
+            deliver(&mut d, &mut host, &mut []);
+            let n = host[2].read(&mut out[..30]);
+            received.extend_from_slice(&out[..n]);
+        }
+        assert_eq!(received, image);
+        assert_eq!(host[2].overrun(), 0);
+        assert_eq!(device[2].pending(), 0);
+    }
+
+    #[test]
+    fn test_channel_overrun() {
+        let mut buf = [0u8; 4];
+        let mut r = ChannelReader::new(9, &mut buf);
+        assert_eq!(r.push(b"abc"), 3);
+        let mut out = [0u8; 2];
+        assert_eq!(r.read(&mut out), 2);
+        assert_eq!(r.push(b"defg"), 3);
+        assert_eq!(r.overrun(), 1);
+        let mut out = [0u8; 8];
+        assert_eq!(r.read(&mut out), 4);
+        assert_eq!(&out[..4], b"cdef");
+    }
+
+    #[test]
+    fn test_channel_credit_saturates() {
+        let mut buf = [0u8; 4];
+        let mut device = [ChannelWriter::with_flow_control(5, &mut buf)];
+        let mut hbuf = [0u8; 64];
+        let mut h = Writer::new(&mut hbuf);
+        for &n in &[u32::MAX - 1, 2, u32::MAX] {
+            let mut tmp = [0u8; 5];
+            let len = {
+                let mut lw = ::leb128::Writer::new(&mut tmp);
+                lw.write_u32(n).unwrap();
+                lw.pos()
+            };
+            write_op(&mut h, 5, Op::Credit, &tmp[..len]).unwrap();
+        }
+        deliver(&mut h, &mut [], &mut device);
+        assert_eq!(device[0].credit(), Some(u32::MAX as usize));
+    }
+}
@@ -0,0 +1,397 @@
+//! `tokio_util` codecs for COBS frames and sctl messages.
+//!
+//! Enabled with the `tokio` feature. `CobsCodec` turns a byte stream into
+//! decoded frames and back, and `MessageCodec` builds on it to read and
+//! write single messages as `Packet`s. Wrapped in `Framed`, anything
+//! `AsyncRead + AsyncWrite` becomes a `Stream` and `Sink` of messages:
+//!
+//! ```ignore
+//! let mut link = Framed::new(serial, MessageCodec::new());
+//! link.send(Packet::new(Tag::Stdin, b"help\n")).await?;
+//! while let Some(packet) = link.next().await {
+//!     println!("{:?}", packet?.message());
+//! }
+//! ```
+//!
+//! Frames that fail to decode are skipped and counted instead of ending the
+//! stream, since serial links do see noise. Compressed frames are
+//! decompressed and fragmented messages are reassembled; each message sent
+//! goes out in a frame of its own.
+
+use std::collections::VecDeque;
+use std::io;
+use std::vec::Vec;
+
+use bytes::BytesMut;
+use tokio_util::codec::{Decoder, Encoder};
+
+use cobs;
+use compress;
+use fragment;
+use {Error, Message, Reader, Tag, Writer};
+
+/// The default limit on the length of an encoded frame.
+pub const MAX_FRAME: usize = 4096;
+
+pub struct CobsCodec {
+    max_frame: usize,
+    errors: usize,
+    discarding: bool,
+}
+
+impl CobsCodec {
+    pub fn new() -> Self {
+        CobsCodec::with_max_frame(MAX_FRAME)
+    }
+
+    /// Creates a codec that skips encoded frames longer than `max_frame`.
+    pub fn with_max_frame(max_frame: usize) -> Self {
+        CobsCodec { max_frame, errors: 0, discarding: false }
+    }
+
+    /// Returns the number of frames skipped because they were too long or
+    /// failed to decode.
+    pub fn errors(&self) -> usize {
+        self.errors
+    }
+}
+
+impl Default for CobsCodec {
+    fn default() -> Self {
+        CobsCodec::new()
+    }
+}
+
+impl Decoder for CobsCodec {
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let end = match src.iter().position(|&b| b == 0) {
+                Some(end) => end,
+                None => {
+                    // Drop an overlong frame as it arrives, up to its end.
+                    if src.len() > self.max_frame {
+                        if !self.discarding {
+                            self.errors += 1;
+                            self.discarding = true;
+                        }
+                        src.clear();
+                    }
+                    return Ok(None)
+                }
+            };
+            let src = src.split_to(end + 1);
+            let src = &src[..end];
+            if self.discarding {
+                self.discarding = false;
+                continue
+            }
+            if src.is_empty() {
+                continue
+            }
+            if src.len() > self.max_frame {
+                self.errors += 1;
+                continue
+            }
+            let mut frame = vec![0u8; src.len()];
+            match cobs::decode(src, &mut frame) {
+                Ok(n) => {
+                    frame.truncate(n);
+                    return Ok(Some(frame))
+                }
+                Err(_) => self.errors += 1,
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Vec<u8>>> {
+        let frame = self.decode(src)?;
+        // Bytes after the last delimiter are an incomplete frame.
+        if frame.is_none() {
+            src.clear();
+        }
+        Ok(frame)
+    }
+}
+
+impl<'a> Encoder<&'a [u8]> for CobsCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: &'a [u8], dst: &mut BytesMut) -> io::Result<()> {
+        let mut buf = vec![0u8; frame.len() + frame.len() / 254 + 2];
+        let n = cobs::Writer::new(&mut buf).encode_packet(frame).map_err(Error::from)?;
+        dst.extend_from_slice(&buf[..n]);
+        Ok(())
+    }
+}
+
+/// An owned message, with the sequence number and timestamp attached to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub tag: u32,
+    pub value: Vec<u8>,
+    pub seq: Option<u32>,
+    pub time: Option<u32>,
+}
+
+impl Packet {
+    pub fn new(tag: Tag, value: &[u8]) -> Self {
+        Packet { tag: tag as u32, value: value.to_vec(), seq: None, time: None }
+    }
+
+    pub fn message(&self) -> Result<Message<'_>, Error> {
+        Message::decode(self.tag, &self.value)
+    }
+}
+
+pub struct MessageCodec {
+    frames: CobsCodec,
+    pending: VecDeque<Packet>,
+    errors: usize,
+    // Room for reassembling fragmented messages.
+    fragments: Vec<u8>,
+    // Carried across frames so that consecutive long messages don't share a
+    // fragment ID.
+    fragment_id: u8,
+}
+
+impl MessageCodec {
+    pub fn new() -> Self {
+        MessageCodec::with_max_frame(MAX_FRAME)
+    }
+
+    /// Creates a codec that skips encoded frames longer than `max_frame`.
+    pub fn with_max_frame(max_frame: usize) -> Self {
+        MessageCodec {
+            frames: CobsCodec::with_max_frame(max_frame),
+            pending: VecDeque::new(),
+            errors: 0,
+            fragments: vec![0u8; fragment::MAX_VALUE],
+            fragment_id: 0,
+        }
+    }
+
+    /// Returns the number of frames skipped, or cut short, because they
+    /// failed to decode.
+    pub fn errors(&self) -> usize {
+        self.frames.errors() + self.errors
+    }
+
+    // Queues the messages in a decoded frame. `Writer` puts every fragment
+    // of a message in the same frame, so each frame is reassembled on its
+    // own; the rebuilt message carries the sequence number and timestamp
+    // of its first fragment.
+    fn split(&mut self, frame: Vec<u8>) {
+        let mut buf = vec![0u8; compress::max_unpacked(frame.len())];
+        let contents = match compress::unpack(&frame, &mut buf) {
+            Ok(contents) => contents,
+            Err(_) => {
+                self.errors += 1;
+                return
+            }
+        };
+        let mut reassembler = fragment::Reassembler::new(&mut self.fragments);
+        let mut first = (None, None);
+        let mut r = Reader::new(contents);
+        let mut tmp = [0u8; 256];
+        loop {
+            match r.read_raw(&mut tmp) {
+                Ok(Some((tag, value))) if tag == Tag::Fragment as u32 => {
+                    if !reassembler.is_pending() {
+                        first = (r.seq(), r.time());
+                    }
+                    match reassembler.push_raw(value) {
+                        Ok(Some((tag, value))) => {
+                            self.pending.push_back(Packet { tag, value: value.to_vec(), seq: first.0, time: first.1 })
+                        }
+                        Ok(None) => {}
+                        Err(_) => {
+                            self.errors += 1;
+                            break
+                        }
+                    }
+                }
+                Ok(Some((tag, value))) => {
+                    self.pending.push_back(Packet { tag, value: value.to_vec(), seq: r.seq(), time: r.time() })
+                }
+                Ok(None) => break,
+                Err(_) => {
+                    self.errors += 1;
+                    break
+                }
+            }
+        }
+    }
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        MessageCodec::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = self.pending.pop_front() {
+                return Ok(Some(packet))
+            }
+            match self.frames.decode(src)? {
+                Some(frame) => self.split(frame),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
+        loop {
+            if let Some(packet) = self.pending.pop_front() {
+                return Ok(Some(packet))
+            }
+            match self.frames.decode_eof(src)? {
+                Some(frame) => self.split(frame),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Encoder<Packet> for MessageCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> io::Result<()> {
+        // Room for the prefixes, and for fragment headers on long values.
+        let len = packet.value.len();
+        let mut buf = vec![0u8; 32 + len + (len / fragment::FRAGMENT_SIZE + 1) * (2 + fragment::HEADER_SIZE)];
+        let mut w = Writer::new(&mut buf);
+        w.set_fragment_id(self.fragment_id);
+        if let Some(seq) = packet.seq {
+            w.seq(seq)?;
+        }
+        if let Some(time) = packet.time {
+            w.time(time)?;
+        }
+        w.write_raw(packet.tag, &packet.value)?;
+        self.fragment_id = w.fragment_id();
+        self.frames.encode(w.as_ref(), dst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{duplex, AsyncWriteExt};
+    use tokio_util::codec::{Framed, FramedRead};
+
+    #[test]
+    fn test_duplex() {
+        let (host, device) = duplex(4096);
+        let mut host = Framed::new(host, MessageCodec::new());
+        let mut device = Framed::new(device, MessageCodec::new());
+
+        let stdin = Packet::new(Tag::Stdin, b"help\n");
+        let val = Packet { seq: Some(7), time: Some(1000), ..Packet::new(Tag::Val, b"\x01\x02") };
+        block_on(host.send(stdin.clone())).unwrap();
+        block_on(host.send(val.clone())).unwrap();
+        assert_eq!(block_on(device.next()).unwrap().unwrap(), stdin);
+        let packet = block_on(device.next()).unwrap().unwrap();
+        assert_eq!(packet, val);
+        assert_eq!(packet.message(), Ok(Message::Val(b"\x01\x02")));
+
+        // Long values are fragmented on the wire and reassembled, keeping
+        // the stamps of the first fragment.
+        let long = Packet { seq: Some(8), time: Some(2000), ..Packet::new(Tag::Stdout, &vec![0x5a; 600]) };
+        block_on(device.send(long.clone())).unwrap();
+        block_on(device.send(long.clone())).unwrap();
+        assert_eq!(block_on(host.next()).unwrap().unwrap(), long);
+        assert_eq!(block_on(host.next()).unwrap().unwrap(), long);
+    }
+
+    #[test]
+    fn test_fragment_id() {
+        // Consecutive long messages get fragment IDs of their own, so a
+        // device reassembling across frames doesn't take the second for a
+        // repeat of the first.
+        let mut codec = MessageCodec::new();
+        let mut dst = BytesMut::new();
+        let long = vec![0x5a; 600];
+        codec.encode(Packet::new(Tag::Stdin, &long), &mut dst).unwrap();
+        codec.encode(Packet::new(Tag::Stdin, &long), &mut dst).unwrap();
+        let mut frames = CobsCodec::new();
+        let mut ids = Vec::new();
+        while let Some(frame) = frames.decode(&mut dst).unwrap() {
+            let mut r = Reader::new(&frame);
+            let mut tmp = [0u8; 256];
+            match r.read(&mut tmp).unwrap() {
+                Some(Message::Fragment(value)) => ids.push(value[0]),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(ids, [0, 1]);
+    }
+
+    #[test]
+    fn test_packet_message() {
+        // Packets come straight off the link, so their tag and value can be
+        // anything.
+        assert_eq!(Packet::new(Tag::Exit, b"\x02").message(), Ok(Message::Exit(2)));
+        assert_eq!(Packet::new(Tag::Exit, b"").message(), Err(Error::InvalidRecord));
+        assert_eq!(Packet::new(Tag::Sealed, b"\x01").message(), Err(Error::InvalidRecord));
+        let unknown = Packet { tag: 0x1234, value: vec![0x01], seq: None, time: None };
+        assert_eq!(unknown.message(), Err(Error::InvalidRecord));
+    }
+
+    #[test]
+    fn test_stream() {
+        let mut stream = vec![0x00, 0x11, 0x22, 0x00];
+        let mut wbuf = [0u8; 256];
+        let mut frame = [0u8; 300];
+        let mut w = Writer::new(&mut wbuf);
+        w.time(5).unwrap();
+        w.info(b"ready").unwrap();
+        w.stdout(b"> ").unwrap();
+        stream.extend_from_slice(w.encode(&mut frame).unwrap());
+        let text = [b"sensor: temperature 21.5 C\n".as_ref(); 4].concat();
+        w.stdout(&text).unwrap();
+        let mut scratch = [0u8; 256];
+        stream.extend_from_slice(compress::encode(&mut w, &mut scratch, &mut frame).unwrap());
+        stream.extend_from_slice(&[0x03, 0x11]);
+
+        let (mut tx, rx) = duplex(4096);
+        block_on(tx.write_all(&stream)).unwrap();
+        drop(tx);
+        let mut r = FramedRead::new(rx, MessageCodec::new());
+        let mut packets = Vec::new();
+        while let Some(packet) = block_on(r.next()) {
+            packets.push(packet.unwrap());
+        }
+        assert_eq!(packets, [
+            Packet { time: Some(5), ..Packet::new(Tag::Info, b"ready") },
+            Packet::new(Tag::Stdout, b"> "),
+            Packet::new(Tag::Stdout, &text),
+        ]);
+        assert_eq!(r.decoder().errors(), 1);
+    }
+
+    #[test]
+    fn test_max_frame() {
+        let mut codec = CobsCodec::with_max_frame(8);
+        let mut src = BytesMut::from(&[0x01; 12][..]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+        src.extend_from_slice(&[0x01, 0x01, 0x00, 0x02, 0x33, 0x00]);
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(vec![0x33]));
+        assert_eq!(codec.errors(), 1);
+
+        let mut dst = BytesMut::new();
+        codec.encode(&[0x11, 0x00, 0x22][..], &mut dst).unwrap();
+        assert_eq!(&dst[..], &[0x02, 0x11, 0x02, 0x22, 0x00]);
+    }
+}
@@ -0,0 +1,259 @@
+//! Optional LZSS compression of whole frames.
+//!
+//! When both sides have `Features::COMPRESS`, a sender may replace the
+//! contents of a frame with `Tag::Compressed` (0x7F) followed by the
+//! compressed contents. No message uses that tag, so a receiver can tell
+//! compressed frames from plain ones by their first byte and pass plain
+//! frames through untouched. Frames shorter than `MIN_SIZE`, and frames
+//! that don't get smaller, are sent as they are.
+//!
+//! The format is heatshrink-style LZSS over a bit stream, most significant
+//! bit first, with each frame compressed on its own:
+//!
+//! | bits                                  | meaning                            |
+//! |---------------------------------------|------------------------------------|
+//! | `1` + 8 bits                          | a literal byte                     |
+//! | `0` + 8 bits `d - 1` + 4 bits `n - 2` | copy `n` bytes from `d` bytes back |
+//!
+//! That makes a 256 byte window and matches of 2 to 17 bytes. The window is
+//! the frame's own output, so neither side needs memory beyond the frame
+//! buffers. The last byte is padded with zeros; fewer than 9 remaining bits
+//! end the stream.
+
+use cobs;
+
+use {Error, Tag, Writer};
+
+/// Frames shorter than this are never compressed.
+pub const MIN_SIZE: usize = 32;
+
+const WINDOW: usize = 256;
+const MIN_MATCH: usize = 2;
+const MAX_MATCH: usize = MIN_MATCH + 15;
+
+struct BitWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn write(&mut self, value: u32, count: u32) -> Option<()> {
+        self.bits = (self.bits << count) | value;
+        self.count += count;
+        while self.count >= 8 {
+            self.count -= 8;
+            *self.buf.get_mut(self.pos)? = (self.bits >> self.count) as u8;
+            self.pos += 1;
+        }
+        Some(())
+    }
+
+    fn finish(mut self) -> Option<usize> {
+        if self.count > 0 {
+            let pad = 8 - self.count;
+            self.write(0, pad)?;
+        }
+        Some(self.pos)
+    }
+}
+
+struct BitReader<'a> {
+    buf: &'a [u8],
+    bit: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn remaining(&self) -> usize {
+        self.buf.len() * 8 - self.bit
+    }
+
+    fn read(&mut self, count: u32) -> u32 {
+        let mut value = 0;
+        for _ in 0..count {
+            let byte = self.buf[self.bit / 8];
+            value = (value << 1) | ((byte >> (7 - self.bit % 8)) & 1) as u32;
+            self.bit += 1;
+        }
+        value
+    }
+}
+
+/// Compresses `src` into `dst`, returning the compressed length, or `None`
+/// if it doesn't fit.
+pub fn compress(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut w = BitWriter { buf: dst, pos: 0, bits: 0, count: 0 };
+    let mut i = 0;
+    while i < src.len() {
+        let (mut best_len, mut best_dist) = (0, 0);
+        let max = (src.len() - i).min(MAX_MATCH);
+        for start in i.saturating_sub(WINDOW)..i {
+            let len = (0..max).take_while(|&k| src[start + k] == src[i + k]).count();
+            if len > best_len {
+                best_len = len;
+                best_dist = i - start;
+            }
+        }
+        if best_len >= MIN_MATCH {
+            w.write(0, 1)?;
+            w.write((best_dist - 1) as u32, 8)?;
+            w.write((best_len - MIN_MATCH) as u32, 4)?;
+            i += best_len;
+        } else {
+            w.write(1, 1)?;
+            w.write(src[i] as u32, 8)?;
+            i += 1;
+        }
+    }
+    w.finish()
+}
+
+/// Decompresses `src` into `dst`, returning the decompressed length. Fails
+/// with `Error::InvalidRecord` if `src` refers back past the start of the
+/// output or the output doesn't fit in `dst`.
+pub fn decompress(src: &[u8], dst: &mut [u8]) -> Result<usize, Error> {
+    let mut r = BitReader { buf: src, bit: 0 };
+    let mut pos = 0;
+    while r.remaining() >= 9 {
+        if r.read(1) == 1 {
+            *dst.get_mut(pos).ok_or(Error::InvalidRecord)? = r.read(8) as u8;
+            pos += 1;
+        } else {
+            if r.remaining() < 12 {
+                break
+            }
+            let dist = r.read(8) as usize + 1;
+            let len = r.read(4) as usize + MIN_MATCH;
+            if dist > pos || pos + len > dst.len() {
+                return Err(Error::InvalidRecord)
+            }
+            // Byte by byte, since a match may overlap its own output.
+            for _ in 0..len {
+                dst[pos] = dst[pos - dist];
+                pos += 1;
+            }
+        }
+    }
+    Ok(pos)
+}
+
+/// Returns the longest output `len` bytes of compressed data can expand to,
+/// for sizing the buffer passed to `unpack`.
+pub fn max_unpacked(len: usize) -> usize {
+    len * 8 / (1 + 8 + 4) * MAX_MATCH
+}
+
+/// Encodes the contents of `w` as a COBS frame in `dst` like
+/// `Writer::encode`, compressing them with `scratch` as working space if
+/// that makes the frame smaller. `scratch` should be as long as `w`'s
+/// buffer; with less, larger frames are sent uncompressed.
+pub fn encode<'b>(w: &mut Writer, scratch: &mut [u8], dst: &'b mut [u8]) -> Result<&'b [u8], Error> {
+    let len = w.pos;
+    if len >= MIN_SIZE && !scratch.is_empty() {
+        scratch[0] = Tag::Compressed as u8;
+        if let Some(n) = compress(&w.buf[..len], &mut scratch[1..]) {
+            if 1 + n < len {
+                let n = cobs::Writer::new(dst).encode_packet(&scratch[..1 + n])?;
+                w.pos = 0;
+                return Ok(&dst[..n])
+            }
+        }
+    }
+    w.encode(dst)
+}
+
+/// Returns the contents of a decoded frame, decompressing them into `buf`
+/// if the frame is compressed.
+pub fn unpack<'b>(frame: &'b [u8], buf: &'b mut [u8]) -> Result<&'b [u8], Error> {
+    match frame.split_first() {
+        Some((&marker, data)) if marker == Tag::Compressed as u8 => {
+            let n = decompress(data, buf)?;
+            Ok(&buf[..n])
+        }
+        _ => Ok(frame),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Reader;
+    use Message;
+
+    const LOG: &[u8] = b"\
+[    12.000104] INFO  sensor: read temperature 21.5 C humidity 40%\n\
+[    12.000318] DEBUG i2c: write addr=0x48 len=2 status=ok\n\
+[    12.000522] DEBUG i2c: read addr=0x48 len=2 status=ok\n\
+[    12.010104] INFO  sensor: read temperature 21.6 C humidity 40%\n\
+[    12.010331] DEBUG i2c: write addr=0x48 len=2 status=ok\n\
+[    12.010540] DEBUG i2c: read addr=0x48 len=2 status=ok\n\
+[    12.020100] WARN  radio: retry 1 of 3 after timeout\n";
+
+    fn round_trip(data: &[u8]) -> usize {
+        let mut packed = [0u8; 1024];
+        let n = compress(data, &mut packed).unwrap();
+        let mut out = [0u8; 1024];
+        assert!(max_unpacked(n) >= data.len());
+        let m = decompress(&packed[..n], &mut out).unwrap();
+        assert_eq!(&out[..m], data);
+        n
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(round_trip(b""), 0);
+        assert_eq!(round_trip(b"a"), 2);
+        round_trip(&[0u8; 300]);
+        round_trip(&(0..=255).collect::<std::vec::Vec<u8>>());
+        let n = round_trip(LOG);
+        assert!(n * 2 < LOG.len(), "{} of {}", n, LOG.len());
+    }
+
+    #[test]
+    fn test_decompress_errors() {
+        // A match two bytes back with nothing written yet.
+        assert_eq!(decompress(&[0x00, 0x80, 0x00], &mut [0u8; 16]), Err(Error::InvalidRecord));
+        let mut packed = [0u8; 64];
+        let n = compress(b"abcabcabcabc", &mut packed).unwrap();
+        assert_eq!(decompress(&packed[..n], &mut [0u8; 8]), Err(Error::InvalidRecord));
+        assert_eq!(compress(b"abcabcabcabc", &mut [0u8; 2]), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let mut wbuf = [0u8; 512];
+        let mut scratch = [0u8; 512];
+        let mut frame = [0u8; 600];
+        let mut w = Writer::new(&mut wbuf);
+
+        // Small frames go out as they are.
+        w.stdout(b"ok\n").unwrap();
+        let plain = encode(&mut w, &mut scratch, &mut frame).unwrap().to_vec();
+        let mut decoded = [0u8; 600];
+        let n = cobs::decode(&plain[..plain.len() - 1], &mut decoded).unwrap();
+        assert_eq!(&decoded[..n], &[Tag::Stdout as u8, 3, b'o', b'k', b'\n']);
+
+        w.stdout(&LOG[..240]).unwrap();
+        w.stdout(&LOG[240..]).unwrap();
+        let len = w.pos;
+        let packed = encode(&mut w, &mut scratch, &mut frame).unwrap().to_vec();
+        assert!(packed.len() * 2 < len, "{} of {}", packed.len(), len);
+        assert_eq!(w.pos, 0);
+
+        let n = cobs::decode(&packed[..packed.len() - 1], &mut decoded).unwrap();
+        assert_eq!(decoded[0], Tag::Compressed as u8);
+        let mut buf = [0u8; 1024];
+        let contents = unpack(&decoded[..n], &mut buf).unwrap();
+        let mut r = Reader::new(contents);
+        let mut tmp = [0u8; 256];
+        let mut text = std::vec::Vec::new();
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            match msg {
+                Message::Stdout(value) => text.extend_from_slice(value),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(text, LOG);
+    }
+}
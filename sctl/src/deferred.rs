@@ -0,0 +1,261 @@
+//! Deferred formatting of log messages.
+//!
+//! Instead of formatting text on the device, `sctl_log!` sends a
+//! `Deferred` message holding the log level, the ID of the format string
+//! and the raw arguments. The host looks the format string up by ID and
+//! does the formatting.
+//!
+//! The value of a `Deferred` message is the log level's tag and the format
+//! ID as LEB128 values, followed by the arguments in order:
+//!
+//! | placeholder | argument types           | encoding                     |
+//! |-------------|--------------------------|------------------------------|
+//! | `{=u32}`    | `u8`, `u16`, `u32`       | unsigned LEB128              |
+//! | `{=i32}`    | `i8`, `i16`, `i32`       | signed LEB128                |
+//! | `{=bool}`   | `bool`                   | one byte, 0 or 1             |
+//! | `{=str}`    | `&str`                   | LEB128 length, then UTF-8    |
+//!
+//! A `u32` placeholder may add `:x` for hex, e.g. `{=u32:x}`. The
+//! placeholders must match the argument types; this isn't checked on the
+//! device.
+//!
+//! `sctl_log!` places each format string, NUL-terminated, in the
+//! `.sctl.fmt` linker section and uses its address as the ID. The section
+//! should be mapped to an `(INFO)` output section in the linker script so
+//! that the strings take no space in flash; the host reads them from the
+//! ELF file.
+
+use leb128;
+
+use {Error, Tag};
+
+/// Returns `s` followed by a NUL byte. `N` must be `s.len() + 1`.
+pub const fn nul_terminated<const N: usize>(s: &str) -> [u8; N] {
+    let b = s.as_bytes();
+    let mut out = [0u8; N];
+    let mut i = 0;
+    while i < b.len() {
+        out[i] = b[i];
+        i += 1;
+    }
+    out
+}
+
+/// Builds the value of a `Deferred` message.
+pub struct Encoder<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buf: &'a mut [u8], level: Tag, id: u32) -> Result<Self, Error> {
+        let mut e = Encoder { buf, pos: 0 };
+        e.u32(level as u32)?;
+        e.u32(id)?;
+        Ok(e)
+    }
+
+    fn writer<'b>(&'b mut self) -> leb128::Writer<'b> {
+        leb128::Writer::new(&mut self.buf[self.pos..])
+    }
+
+    pub fn u32(&mut self, value: u32) -> Result<(), Error> {
+        let mut w = self.writer();
+        w.write_u32(value)?;
+        self.pos += w.pos();
+        Ok(())
+    }
+
+    pub fn i32(&mut self, value: i32) -> Result<(), Error> {
+        let mut w = self.writer();
+        w.write_i32(value)?;
+        self.pos += w.pos();
+        Ok(())
+    }
+
+    pub fn bool(&mut self, value: bool) -> Result<(), Error> {
+        let mut w = self.writer();
+        w.write_u1(value)?;
+        self.pos += w.pos();
+        Ok(())
+    }
+
+    pub fn str(&mut self, value: &str) -> Result<(), Error> {
+        self.u32(value.len() as u32)?;
+        if self.buf.len() - self.pos < value.len() {
+            return Err(Error::Leb128Error(leb128::Error::BufferTooShort))
+        }
+        self.buf[self.pos..self.pos + value.len()].copy_from_slice(value.as_bytes());
+        self.pos += value.len();
+        Ok(())
+    }
+
+    pub fn finish(self) -> &'a [u8] {
+        &self.buf[..self.pos]
+    }
+}
+
+/// A value that can be passed to `sctl_log!`.
+pub trait Arg {
+    fn encode(&self, e: &mut Encoder) -> Result<(), Error>;
+}
+
+macro_rules! arg {
+    ($method:ident, $as:ty, $($t:ty),*) => {
+        $(impl Arg for $t {
+            fn encode(&self, e: &mut Encoder) -> Result<(), Error> {
+                e.$method(*self as $as)
+            }
+        })*
+    };
+}
+
+arg!(u32, u32, u8, u16, u32);
+arg!(i32, i32, i8, i16, i32);
+
+impl Arg for bool {
+    fn encode(&self, e: &mut Encoder) -> Result<(), Error> {
+        e.bool(*self)
+    }
+}
+
+impl Arg for &str {
+    fn encode(&self, e: &mut Encoder) -> Result<(), Error> {
+        e.str(self)
+    }
+}
+
+/// Splits the value of a `Deferred` message into log level tag, format ID
+/// and arguments.
+pub fn decode<'a>(value: &'a [u8]) -> Result<(u32, u32, Args<'a>), Error> {
+    let mut args = Args { r: leb128::Reader::new(value), buf: value };
+    let level = args.u32()?;
+    let id = args.u32()?;
+    Ok((level, id, args))
+}
+
+/// Reads the arguments of a `Deferred` message.
+pub struct Args<'a> {
+    r: leb128::Reader<'a>,
+    buf: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    pub fn remaining(&self) -> usize {
+        self.r.remaining()
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        self.r.read_u32()?.ok_or(Error::InvalidRecord)
+    }
+
+    pub fn i32(&mut self) -> Result<i32, Error> {
+        self.r.read_i32()?.ok_or(Error::InvalidRecord)
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        self.r.read_u1()?.ok_or(Error::InvalidRecord)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, Error> {
+        let len = self.u32()? as usize;
+        let pos = self.r.pos();
+        if self.buf.len() - pos < len {
+            return Err(Error::InvalidRecord)
+        }
+        let s = ::record::to_str(&self.buf[pos..pos + len])?;
+        self.r = leb128::Reader::new(&self.buf[pos + len..]);
+        self.buf = &self.buf[pos + len..];
+        Ok(s)
+    }
+}
+
+/// Writes a log message with deferred formatting to an `sctl::Writer`.
+///
+/// ```ignore
+/// sctl_log!(w, Info, "temperature {=i32} ({=str})", t, sensor.name())?;
+/// ```
+#[macro_export]
+macro_rules! sctl_log {
+    ($w:expr, $level:ident, $fmt:expr $(, $arg:expr)*) => {{
+        #[link_section = ".sctl.fmt"]
+        #[used]
+        static FMT: [u8; $fmt.len() + 1] = $crate::deferred::nul_terminated($fmt);
+        let id = &FMT as *const _ as usize as u32;
+        let mut buf = [0u8; 255];
+        let mut write = || -> Result<usize, $crate::Error> {
+            #[allow(unused_mut)]
+            let mut e = $crate::deferred::Encoder::new(&mut buf, $crate::Tag::$level, id)?;
+            $($crate::deferred::Arg::encode(&$arg, &mut e)?;)*
+            $w.deferred(e.finish())
+        };
+        write()
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Message, Reader, Writer};
+
+    #[test]
+    fn test_nul_terminated() {
+        const S: [u8; 4] = nul_terminated("abc");
+        assert_eq!(&S, b"abc\0");
+    }
+
+    #[test]
+    fn test_deferred() {
+        let mut buf = [0u8; 32];
+        let mut e = Encoder::new(&mut buf, Tag::Warn, 0x1234).unwrap();
+        300u16.encode(&mut e).unwrap();
+        (-2i8).encode(&mut e).unwrap();
+        true.encode(&mut e).unwrap();
+        "ok".encode(&mut e).unwrap();
+        7u32.encode(&mut e).unwrap();
+        let value = e.finish();
+        assert_eq!(value, &[0x21, 0xb4, 0x24, 0xac, 0x02, 0x7e, 0x01, 0x02, b'o', b'k', 0x07]);
+
+        let (level, id, mut args) = decode(value).unwrap();
+        assert_eq!((level, id), (Tag::Warn as u32, 0x1234));
+        assert_eq!(args.u32(), Ok(300));
+        assert_eq!(args.i32(), Ok(-2));
+        assert_eq!(args.bool(), Ok(true));
+        assert_eq!(args.str(), Ok("ok"));
+        assert_eq!(args.u32(), Ok(7));
+        assert_eq!(args.remaining(), 0);
+        assert_eq!(args.u32(), Err(Error::InvalidRecord));
+
+        let (_, _, mut args) = decode(&[0x22, 0x00, 0x05, b'a']).unwrap();
+        assert_eq!(args.str(), Err(Error::InvalidRecord));
+    }
+
+    #[test]
+    fn test_sctl_log() {
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        sctl_log!(w, Info, "boot").unwrap();
+        sctl_log!(w, Error, "code {=u32:x} in {=str}", 0xbeefu32, "main").unwrap();
+
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 64];
+        let first = match r.read(&mut tmp).unwrap() {
+            Some(Message::Deferred(value)) => {
+                let (level, id, args) = decode(value).unwrap();
+                assert_eq!((level, args.remaining()), (Tag::Info as u32, 0));
+                id
+            }
+            other => panic!("unexpected {:?}", other),
+        };
+        match r.read(&mut tmp).unwrap() {
+            Some(Message::Deferred(value)) => {
+                let (level, id, mut args) = decode(value).unwrap();
+                assert_eq!(level, Tag::Error as u32);
+                assert_ne!(id, first);
+                assert_eq!(args.u32(), Ok(0xbeef));
+                assert_eq!(args.str(), Ok("main"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
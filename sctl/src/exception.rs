@@ -0,0 +1,321 @@
+//! Fault reporting through sctl.
+//!
+//! `ExceptionRecord` is the structured payload of `Message::Exception`: the
+//! exception number, the registers stacked on exception entry, the fault
+//! status registers and an optional snapshot of the stack. Register sets are
+//! sent as big-endian words; the stack snapshot is sent as raw memory.
+
+use core::fmt;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use record::{self, RecordReader, RecordWriter};
+use Error;
+
+enum Field {
+    Number = 0x1,
+    Registers = 0x2,
+    FaultStatus = 0x3,
+    Sp = 0x4,
+    Stack = 0x5,
+}
+
+/// Registers stacked by the processor on exception entry, in stacking order.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Registers {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+impl From<[u32; 8]> for Registers {
+    fn from(frame: [u32; 8]) -> Registers {
+        Registers {
+            r0: frame[0],
+            r1: frame[1],
+            r2: frame[2],
+            r3: frame[3],
+            r12: frame[4],
+            lr: frame[5],
+            pc: frame[6],
+            xpsr: frame[7],
+        }
+    }
+}
+
+impl Registers {
+    fn to_words(self) -> [u32; 8] {
+        [self.r0, self.r1, self.r2, self.r3, self.r12, self.lr, self.pc, self.xpsr]
+    }
+}
+
+/// Cortex-M fault status and fault address registers.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FaultStatus {
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+}
+
+impl FaultStatus {
+    /// Reads the fault status registers from the System Control Block.
+    ///
+    /// # Safety
+    ///
+    /// Only valid on ARMv7-M and ARMv8-M Mainline cores.
+    pub unsafe fn read() -> FaultStatus {
+        use core::ptr::read_volatile;
+        FaultStatus {
+            cfsr: read_volatile(0xE000_ED28 as *const u32),
+            hfsr: read_volatile(0xE000_ED2C as *const u32),
+            mmfar: read_volatile(0xE000_ED34 as *const u32),
+            bfar: read_volatile(0xE000_ED38 as *const u32),
+        }
+    }
+
+    fn to_words(self) -> [u32; 4] {
+        [self.cfsr, self.hfsr, self.mmfar, self.bfar]
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ExceptionRecord<'a> {
+    pub number: u32,
+    pub registers: Registers,
+    pub fault: Option<FaultStatus>,
+    /// Stack pointer at the time of the exception.
+    pub sp: u32,
+    /// Memory copied from `sp` upwards.
+    pub stack: Option<&'a [u8]>,
+}
+
+impl<'a> ExceptionRecord<'a> {
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let len = {
+            let mut w = RecordWriter::new(buf);
+            w.write_u32(Field::Number as u32, self.number)?;
+            let mut regs = [0u8; 32];
+            BigEndian::write_u32_into(&self.registers.to_words(), &mut regs);
+            w.write_bytes(Field::Registers as u32, &regs)?;
+            if let Some(fault) = self.fault {
+                let mut fsr = [0u8; 16];
+                BigEndian::write_u32_into(&fault.to_words(), &mut fsr);
+                w.write_bytes(Field::FaultStatus as u32, &fsr)?;
+            }
+            w.write_u32(Field::Sp as u32, self.sp)?;
+            if let Some(stack) = self.stack {
+                w.write_bytes(Field::Stack as u32, stack)?;
+            }
+            w.pos()
+        };
+        Ok(&buf[..len])
+    }
+
+    pub fn decode(buf: &'a [u8]) -> Result<Self, Error> {
+        let mut rec = ExceptionRecord {
+            number: 0,
+            registers: Registers::default(),
+            fault: None,
+            sp: 0,
+            stack: None,
+        };
+        let mut r = RecordReader::new(buf);
+        while let Some((tag, value)) = r.read()? {
+            match tag {
+                0x1 => rec.number = record::to_u32(value)?,
+                0x2 => {
+                    if value.len() != 32 { return Err(Error::InvalidRecord) }
+                    let mut words = [0u32; 8];
+                    BigEndian::read_u32_into(value, &mut words);
+                    rec.registers = Registers::from(words);
+                }
+                0x3 => {
+                    if value.len() != 16 { return Err(Error::InvalidRecord) }
+                    let mut words = [0u32; 4];
+                    BigEndian::read_u32_into(value, &mut words);
+                    rec.fault = Some(FaultStatus {
+                        cfsr: words[0],
+                        hfsr: words[1],
+                        mmfar: words[2],
+                        bfar: words[3],
+                    });
+                }
+                0x4 => rec.sp = record::to_u32(value)?,
+                0x5 => rec.stack = Some(value),
+                _ => {}
+            }
+        }
+        Ok(rec)
+    }
+
+    /// Returns the conventional name of a Cortex-M system exception.
+    pub fn name(&self) -> Option<&'static str> {
+        match self.number {
+            1 => Some("Reset"),
+            2 => Some("NMI"),
+            3 => Some("HardFault"),
+            4 => Some("MemManage"),
+            5 => Some("BusFault"),
+            6 => Some("UsageFault"),
+            7 => Some("SecureFault"),
+            11 => Some("SVCall"),
+            12 => Some("DebugMonitor"),
+            14 => Some("PendSV"),
+            15 => Some("SysTick"),
+            _ => None,
+        }
+    }
+}
+
+const CFSR_BITS: [(u32, &str); 19] = [
+    (1 << 0, "IACCVIOL"),
+    (1 << 1, "DACCVIOL"),
+    (1 << 3, "MUNSTKERR"),
+    (1 << 4, "MSTKERR"),
+    (1 << 5, "MLSPERR"),
+    (1 << 7, "MMARVALID"),
+    (1 << 8, "IBUSERR"),
+    (1 << 9, "PRECISERR"),
+    (1 << 10, "IMPRECISERR"),
+    (1 << 11, "UNSTKERR"),
+    (1 << 12, "STKERR"),
+    (1 << 13, "LSPERR"),
+    (1 << 15, "BFARVALID"),
+    (1 << 16, "UNDEFINSTR"),
+    (1 << 17, "INVSTATE"),
+    (1 << 18, "INVPC"),
+    (1 << 19, "NOCP"),
+    (1 << 24, "UNALIGNED"),
+    (1 << 25, "DIVBYZERO"),
+];
+
+const HFSR_BITS: [(u32, &str); 3] = [
+    (1 << 1, "VECTTBL"),
+    (1 << 30, "FORCED"),
+    (1 << 31, "DEBUGEVT"),
+];
+
+fn write_bits(f: &mut fmt::Formatter, value: u32, bits: &[(u32, &str)]) -> fmt::Result {
+    write!(f, "0x{:08x}", value)?;
+    let mut sep = " [";
+    for &(mask, name) in bits {
+        if value & mask != 0 {
+            write!(f, "{}{}", sep, name)?;
+            sep = " ";
+        }
+    }
+    if sep == " " {
+        write!(f, "]")?;
+    }
+    Ok(())
+}
+
+impl<'a> fmt::Display for ExceptionRecord<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.name() {
+            Some(name) => writeln!(f, "{} (exception {})", name, self.number)?,
+            None if self.number >= 16 => writeln!(f, "IRQ{} (exception {})", self.number - 16, self.number)?,
+            None => writeln!(f, "exception {}", self.number)?,
+        }
+        let r = &self.registers;
+        writeln!(f, "  r0  = 0x{:08x}  r1  = 0x{:08x}  r2  = 0x{:08x}  r3   = 0x{:08x}", r.r0, r.r1, r.r2, r.r3)?;
+        writeln!(f, "  r12 = 0x{:08x}  lr  = 0x{:08x}  pc  = 0x{:08x}  xpsr = 0x{:08x}", r.r12, r.lr, r.pc, r.xpsr)?;
+        writeln!(f, "  sp  = 0x{:08x}", self.sp)?;
+        if let Some(fault) = self.fault {
+            write!(f, "  cfsr  = ")?;
+            write_bits(f, fault.cfsr, &CFSR_BITS)?;
+            write!(f, "\n  hfsr  = ")?;
+            write_bits(f, fault.hfsr, &HFSR_BITS)?;
+            writeln!(f)?;
+            if fault.cfsr & (1 << 7) != 0 {
+                writeln!(f, "  mmfar = 0x{:08x}", fault.mmfar)?;
+            }
+            if fault.cfsr & (1 << 15) != 0 {
+                writeln!(f, "  bfar  = 0x{:08x}", fault.bfar)?;
+            }
+        }
+        if let Some(stack) = self.stack {
+            writeln!(f, "  stack:")?;
+            for (i, line) in stack.chunks(16).enumerate() {
+                write!(f, "    {:08x}:", self.sp.wrapping_add(i as u32 * 16))?;
+                for word in line.chunks(4) {
+                    if word.len() == 4 {
+                        write!(f, " {:08x}", LittleEndian::read_u32(word))?;
+                    } else {
+                        for b in word {
+                            write!(f, " {:02x}", b)?;
+                        }
+                    }
+                }
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    fn registers() -> Registers {
+        Registers::from([0x0, 0x1, 0x2, 0x3, 0xc, 0x0800_0123, 0x0800_0456, 0x6100_0000])
+    }
+
+    #[test]
+    fn test_exception_record() {
+        let stack = [0x11u8, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88];
+        let rec = ExceptionRecord {
+            number: 3,
+            registers: registers(),
+            fault: Some(FaultStatus { cfsr: 0x8200, hfsr: 0x4000_0000, mmfar: 0, bfar: 0x2000_fffc }),
+            sp: 0x2000_1000,
+            stack: Some(&stack),
+        };
+        let mut buf = [0u8; 255];
+        let value = rec.encode(&mut buf).unwrap();
+        assert_eq!(ExceptionRecord::decode(value), Ok(rec));
+    }
+
+    #[test]
+    fn test_exception_record_minimal() {
+        let rec = ExceptionRecord { number: 20, registers: registers(), fault: None, sp: 0, stack: None };
+        let mut buf = [0u8; 255];
+        let value = rec.encode(&mut buf).unwrap();
+        let rec = ExceptionRecord::decode(value).unwrap();
+        assert_eq!(rec.fault, None);
+        assert_eq!(rec.stack, None);
+        assert!(rec.to_string().starts_with("IRQ4 (exception 20)\n"));
+        assert_eq!(ExceptionRecord::decode(&value[..value.len() - 1]), Err(Error::InvalidRecord));
+    }
+
+    #[test]
+    fn test_exception_display() {
+        let stack = [0x11u8, 0x22, 0x33, 0x44, 0xaa];
+        let rec = ExceptionRecord {
+            number: 3,
+            registers: registers(),
+            fault: Some(FaultStatus { cfsr: 0x8200, hfsr: 0x4000_0000, mmfar: 0, bfar: 0x2000_fffc }),
+            sp: 0x2000_1000,
+            stack: Some(&stack),
+        };
+        assert_eq!(rec.to_string(), "\
+HardFault (exception 3)
+  r0  = 0x00000000  r1  = 0x00000001  r2  = 0x00000002  r3   = 0x00000003
+  r12 = 0x0000000c  lr  = 0x08000123  pc  = 0x08000456  xpsr = 0x61000000
+  sp  = 0x20001000
+  cfsr  = 0x00008200 [PRECISERR BFARVALID]
+  hfsr  = 0x40000000 [FORCED]
+  bfar  = 0x2000fffc
+  stack:
+    20001000: 44332211 aa
+");
+    }
+}
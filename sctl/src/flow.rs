@@ -0,0 +1,285 @@
+//! Credit-based flow control for the stdio streams.
+//!
+//! The receiving end of a stream grants credit in `Credit` messages, counted
+//! either in bytes or in messages. `Credit` tracks the sending side and
+//! fails with `Error::WouldBlock` instead of writing past the credit it has
+//! been granted. `Window` issues grants on the receiving side as the
+//! application drains its buffer; its first grant covers the whole buffer.
+//! Both keep their count in a `channel::Allowance`, as channels do.
+
+use core::cmp;
+
+use channel::Allowance;
+use record::{self, RecordReader, RecordWriter};
+
+use {Error, Message, Tag, Writer};
+
+enum Field {
+    Stream = 0x1,
+    Bytes = 0x2,
+    Frames = 0x3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Bytes,
+    Frames,
+}
+
+/// The value of a `Credit` message.
+#[derive(Debug, PartialEq)]
+pub struct Grant {
+    /// Tag of the stream the credit applies to.
+    pub stream: u32,
+    pub unit: Unit,
+    pub amount: u32,
+}
+
+impl Grant {
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let len = {
+            let mut w = RecordWriter::new(buf);
+            w.write_u32(Field::Stream as u32, self.stream)?;
+            match self.unit {
+                Unit::Bytes => w.write_u32(Field::Bytes as u32, self.amount)?,
+                Unit::Frames => w.write_u32(Field::Frames as u32, self.amount)?,
+            };
+            w.pos()
+        };
+        Ok(&buf[..len])
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Grant, Error> {
+        let mut stream = None;
+        let mut credit = None;
+        let mut r = RecordReader::new(buf);
+        while let Some((tag, value)) = r.read()? {
+            match tag {
+                0x1 => stream = Some(record::to_u32(value)?),
+                0x2 => credit = Some((Unit::Bytes, record::to_u32(value)?)),
+                0x3 => credit = Some((Unit::Frames, record::to_u32(value)?)),
+                _ => {}
+            }
+        }
+        match (stream, credit) {
+            (Some(stream), Some((unit, amount))) => Ok(Grant { stream, unit, amount }),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+/// Sending side of a flow-controlled stream.
+pub struct Credit {
+    stream: Tag,
+    unit: Unit,
+    available: Allowance,
+}
+
+impl Credit {
+    /// Creates a sender for `stream` with no credit.
+    pub fn new(stream: Tag, unit: Unit) -> Self {
+        Credit { stream, unit, available: Allowance::new(0) }
+    }
+
+    pub fn available(&self) -> u32 {
+        self.available.get()
+    }
+
+    /// Adds the credit carried by a `Credit` message for this stream.
+    /// Returns true if `msg` was a `Credit` message.
+    pub fn receive(&mut self, msg: &Message) -> Result<bool, Error> {
+        match *msg {
+            Message::Credit(value) => {
+                let grant = Grant::decode(value)?;
+                if grant.stream == self.stream as u32 && grant.unit == self.unit {
+                    self.available.add(grant.amount);
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Writes as much of `value` to the stream as credit allows, returning
+    /// the number of bytes written. Fails with `Error::WouldBlock` when no
+    /// credit is left.
+    pub fn write(&mut self, w: &mut Writer, value: &[u8]) -> Result<usize, Error> {
+        if self.available.get() == 0 {
+            return Err(Error::WouldBlock)
+        }
+        let n = match self.unit {
+            Unit::Bytes => cmp::min(value.len(), self.available.get() as usize),
+            Unit::Frames => value.len(),
+        };
+        w.write_tlv(self.stream, &value[..n])?;
+        self.available.spend(match self.unit {
+            Unit::Bytes => n as u32,
+            Unit::Frames => 1,
+        });
+        Ok(n)
+    }
+}
+
+/// Receiving side of a flow-controlled stream.
+pub struct Window {
+    stream: Tag,
+    unit: Unit,
+    freed: Allowance,
+}
+
+impl Window {
+    /// Creates a receiver for `stream` with room for `capacity` bytes or
+    /// messages.
+    pub fn new(stream: Tag, unit: Unit, capacity: u32) -> Self {
+        Window { stream, unit, freed: Allowance::new(capacity) }
+    }
+
+    /// Records that the application has drained `amount` bytes or messages.
+    pub fn consume(&mut self, amount: u32) {
+        self.freed.add(amount);
+    }
+
+    /// Grants the sender credit for the room freed since the last grant.
+    pub fn grant(&mut self, w: &mut Writer) -> Result<usize, Error> {
+        if self.freed.get() == 0 {
+            return Ok(0)
+        }
+        let mut buf = [0u8; 16];
+        let grant = Grant { stream: self.stream as u32, unit: self.unit, amount: self.freed.get() };
+        let n = w.credit(grant.encode(&mut buf)?)?;
+        self.freed.take();
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+    use Reader;
+
+    #[test]
+    fn test_grant() {
+        let grant = Grant { stream: Tag::Stdin as u32, unit: Unit::Frames, amount: 300 };
+        let mut buf = [0u8; 16];
+        let value = grant.encode(&mut buf).unwrap();
+        assert_eq!(value, &[0x01, 0x01, 0x10, 0x03, 0x02, 0xac, 0x02]);
+        assert_eq!(Grant::decode(value), Ok(grant));
+        assert_eq!(Grant::decode(&value[..3]), Err(Error::InvalidRecord));
+    }
+
+    #[test]
+    fn test_credit_frames() {
+        let mut credit = Credit::new(Tag::Stdout, Unit::Frames);
+        let mut window = Window::new(Tag::Stdout, Unit::Frames, 2);
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+
+        assert_eq!(credit.write(&mut w, b"a"), Err(Error::WouldBlock));
+        window.grant(&mut w).unwrap();
+        assert_eq!(window.grant(&mut w), Ok(0));
+        {
+            let mut r = Reader::new(w.as_ref());
+            let mut tmp = [0u8; 16];
+            let msg = r.read(&mut tmp).unwrap().unwrap();
+            assert_eq!(credit.receive(&msg), Ok(true));
+            assert_eq!(credit.receive(&Message::Stdout(b"")), Ok(false));
+        }
+        w.clear();
+
+        // Grants for other streams or units don't apply.
+        let mut buf = [0u8; 16];
+        let other = Grant { stream: Tag::Stderr as u32, unit: Unit::Frames, amount: 5 };
+        assert_eq!(credit.receive(&Message::Credit(other.encode(&mut buf).unwrap())), Ok(true));
+        assert_eq!(credit.available(), 2);
+
+        assert_eq!(credit.write(&mut w, b"abc"), Ok(3));
+        assert_eq!(credit.write(&mut w, b"de"), Ok(2));
+        assert_eq!(credit.write(&mut w, b"f"), Err(Error::WouldBlock));
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 16];
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Stdout(b"abc"))));
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Stdout(b"de"))));
+        assert_eq!(r.read(&mut tmp), Ok(None));
+    }
+
+    #[test]
+    fn test_credit_saturates() {
+        let mut window = Window::new(Tag::Stdin, Unit::Bytes, u32::MAX - 1);
+        window.consume(1);
+        window.consume(u32::MAX);
+        let mut credit = Credit::new(Tag::Stdin, Unit::Bytes);
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        window.grant(&mut w).unwrap();
+        window.consume(5);
+        window.grant(&mut w).unwrap();
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 16];
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        match msg {
+            Message::Credit(value) => assert_eq!(Grant::decode(value).unwrap().amount, u32::MAX),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(credit.receive(&msg).unwrap());
+        assert!(credit.receive(&r.read(&mut tmp).unwrap().unwrap()).unwrap());
+        assert_eq!(credit.available(), u32::MAX);
+    }
+
+    #[test]
+    fn test_slow_consumer() {
+        // The host streams stdin to a device with a 64 byte buffer that
+        // drains 5 bytes per tick.
+        let input: Vec<u8> = (0..1000).map(|i| (i * 31) as u8).collect();
+        let mut credit = Credit::new(Tag::Stdin, Unit::Bytes);
+        let mut window = Window::new(Tag::Stdin, Unit::Bytes, 64);
+        let mut fifo = VecDeque::new();
+        let mut output = Vec::new();
+        let mut sent = 0;
+        let mut blocked = 0;
+
+        for _ in 0..1000 {
+            let mut dbuf = [0u8; 32];
+            let mut d = Writer::new(&mut dbuf);
+            window.grant(&mut d).unwrap();
+            let mut r = Reader::new(d.as_ref());
+            let mut tmp = [0u8; 256];
+            while let Some(msg) = r.read(&mut tmp).unwrap() {
+                assert!(credit.receive(&msg).unwrap());
+            }
+
+            let mut hbuf = [0u8; 256];
+            let mut h = Writer::new(&mut hbuf);
+            while sent < input.len() {
+                let end = cmp::min(sent + 40, input.len());
+                match credit.write(&mut h, &input[sent..end]) {
+                    Ok(n) => sent += n,
+                    Err(Error::WouldBlock) => {
+                        blocked += 1;
+                        break
+                    }
+                    Err(e) => panic!("{:?}", e),
+                }
+            }
+            let mut r = Reader::new(h.as_ref());
+            let mut tmp = [0u8; 256];
+            while let Some(msg) = r.read(&mut tmp).unwrap() {
+                match msg {
+                    Message::Stdin(v) => fifo.extend(v.iter().cloned()),
+                    other => panic!("unexpected {:?}", other),
+                }
+                assert!(fifo.len() <= 64);
+            }
+
+            let n = cmp::min(fifo.len(), 5);
+            output.extend(fifo.drain(..n));
+            window.consume(n as u32);
+            if output.len() == input.len() {
+                break
+            }
+        }
+        assert_eq!(output, input);
+        assert!(blocked > 0);
+    }
+}
@@ -0,0 +1,288 @@
+//! Fragmentation of values too long for a single sctl message.
+//!
+//! `Writer` splits any value over 255 bytes into `Fragment` messages. Each
+//! fragment starts with a message ID, its index, the fragment count and the
+//! tag of the original message, followed by up to `FRAGMENT_SIZE` bytes of
+//! the value. Fragment `i` always holds the bytes starting at
+//! `i * FRAGMENT_SIZE`, so `Reassembler` can place fragments in whatever
+//! order they arrive.
+
+use tlv;
+
+use {Error, Message};
+
+pub const HEADER_SIZE: usize = 4;
+pub const FRAGMENT_SIZE: usize = 255 - HEADER_SIZE;
+/// Length of the longest value that can be fragmented.
+pub const MAX_VALUE: usize = 255 * FRAGMENT_SIZE;
+
+/// Reassembles `Fragment` messages into a caller-supplied buffer.
+pub struct Reassembler<'a> {
+    buf: &'a mut [u8],
+    received: [u8; 32],
+    id: u8,
+    count: u8,
+    tag: u8,
+    len: usize,
+    // The ID, count and tag of the last completed message, and which of its
+    // fragments have been repeated since.
+    done: Option<(u8, u8, u8)>,
+    repeated: [u8; 32],
+}
+
+impl<'a> Reassembler<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Reassembler { buf, received: [0; 32], id: 0, count: 0, tag: 0, len: 0, done: None, repeated: [0; 32] }
+    }
+
+    /// Discards a partially reassembled message.
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.done = None;
+        self.repeated = [0; 32];
+    }
+
+    /// Returns true while a message is partially reassembled.
+    pub fn is_pending(&self) -> bool {
+        self.count != 0
+    }
+
+    /// Accepts the value of a `Fragment` message, returning the original
+    /// message once all of its fragments have arrived. Repeated fragments
+    /// are ignored, including late copies of a completed message. A
+    /// fragment that reuses the completed message's ID but carries
+    /// different bytes starts a new message.
+    ///
+    /// A fragment belonging to a different message while one is still
+    /// incomplete means fragments were lost: the incomplete message is
+    /// discarded, the new fragment is kept, and `Error::MissingFragment` is
+    /// returned.
+    pub fn push<'b>(&'b mut self, value: &[u8]) -> Result<Option<Message<'b>>, Error> {
+        match self.push_raw(value)? {
+            Some((tag, value)) => Message::decode(tag, value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Like `push`, but returns the tag and value of the original message
+    // without interpreting them.
+    pub(crate) fn push_raw<'b>(&'b mut self, value: &[u8]) -> Result<Option<(u32, &'b [u8])>, Error> {
+        if value.len() < HEADER_SIZE {
+            return Err(Error::InvalidRecord)
+        }
+        let (id, index, count, tag) = (value[0], value[1], value[2], value[3]);
+        let chunk = &value[HEADER_SIZE..];
+        if index >= count || chunk.len() > FRAGMENT_SIZE || (index + 1 < count && chunk.len() != FRAGMENT_SIZE) {
+            return Err(Error::InvalidRecord)
+        }
+
+        let mut missing = false;
+        if self.count != 0 && (id != self.id || count != self.count || tag != self.tag) {
+            missing = true;
+            self.count = 0;
+        }
+        let offset = index as usize * FRAGMENT_SIZE;
+        let (byte, bit) = (index as usize / 8, 1 << (index % 8));
+        if self.count == 0 {
+            let (mut received, mut len) = ([0; 32], 0);
+            if self.done == Some((id, count, tag)) && !missing {
+                let end = offset + chunk.len();
+                let last = index + 1 == count;
+                if (end == self.len || (!last && end < self.len)) && &self.buf[offset..end] == chunk {
+                    self.repeated[byte] |= bit;
+                    return Ok(None)
+                }
+                // Other fragments repeated since hold the same bytes in
+                // either message, so they count towards this one, as does
+                // the length if the last fragment was among them.
+                received = self.repeated;
+                received[byte] &= !bit;
+                let last = count as usize - 1;
+                if received[last / 8] & 1 << (last % 8) != 0 {
+                    len = self.len;
+                }
+            }
+            self.received = received;
+            self.len = len;
+            self.id = id;
+            self.count = count;
+            self.tag = tag;
+            self.done = None;
+            self.repeated = [0; 32];
+        }
+
+        if offset + chunk.len() > self.buf.len() {
+            self.count = 0;
+            return Err(Error::TlvError(tlv::Error::BufferTooShort))
+        }
+        if self.received[byte] & bit == 0 {
+            self.buf[offset..offset + chunk.len()].copy_from_slice(chunk);
+            self.received[byte] |= bit;
+            if index + 1 == count {
+                self.len = offset + chunk.len();
+            }
+        }
+
+        if missing {
+            return Err(Error::MissingFragment)
+        }
+        let received: u32 = self.received.iter().map(|b| b.count_ones()).sum();
+        if received < self.count as u32 {
+            return Ok(None)
+        }
+        self.done = Some((self.id, self.count, self.tag));
+        self.count = 0;
+        Ok(Some((self.tag as u32, &self.buf[..self.len])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use {Reader, Writer};
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    // Returns the values of the messages read from `buf`, keeping fragment
+    // values as-is.
+    fn messages(buf: &[u8]) -> Vec<(bool, Vec<u8>)> {
+        let mut out = Vec::new();
+        let mut r = Reader::new(buf);
+        let mut tmp = [0u8; 256];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            match msg {
+                Message::Fragment(v) => out.push((true, v.to_vec())),
+                Message::Stdout(v) => out.push((false, v.to_vec())),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_fragment_boundaries() {
+        for &(len, count) in &[(0, 0), (255, 0), (256, 2), (502, 2), (503, 3), (255 * FRAGMENT_SIZE, 255)] {
+            let value = pattern(len);
+            let mut wbuf = vec![0u8; 70_000];
+            let mut w = Writer::new(&mut wbuf);
+            w.stdout(&value).unwrap();
+            let msgs = messages(w.as_ref());
+            if count == 0 {
+                assert_eq!(msgs, vec![(false, value)]);
+                continue
+            }
+            assert_eq!(msgs.len(), count);
+
+            let mut rbuf = vec![0u8; 70_000];
+            let mut re = Reassembler::new(&mut rbuf);
+            for (i, &(fragment, ref v)) in msgs.iter().enumerate() {
+                assert!(fragment);
+                assert_eq!(v[1] as usize, i);
+                assert_eq!(v[2] as usize, count);
+                let result = re.push(v).unwrap();
+                if i + 1 < count {
+                    assert_eq!(result, None);
+                } else {
+                    assert_eq!(result, Some(Message::Stdout(&value)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_fragment_too_long() {
+        let value = pattern(255 * FRAGMENT_SIZE + 1);
+        let mut wbuf = vec![0u8; 70_000];
+        let mut w = Writer::new(&mut wbuf);
+        assert_eq!(w.stdout(&value), Err(Error::TlvError(tlv::Error::OutOfRange)));
+
+        // Nothing is left behind when the fragments don't fit.
+        let mut small = [0u8; 300];
+        let mut w = Writer::new(&mut small);
+        assert_eq!(w.stdout(&pattern(400)), Err(Error::TlvError(tlv::Error::BufferTooShort)));
+        assert_eq!(w.as_ref().len(), 0);
+    }
+
+    #[test]
+    fn test_fragment_out_of_order() {
+        let value = pattern(1000);
+        let mut wbuf = [0u8; 1100];
+        let mut w = Writer::new(&mut wbuf);
+        w.stdout(&value).unwrap();
+        let msgs = messages(w.as_ref());
+        assert_eq!(msgs.len(), 4);
+
+        let mut rbuf = [0u8; 1000];
+        let mut re = Reassembler::new(&mut rbuf);
+        assert_eq!(re.push(&msgs[3].1), Ok(None));
+        assert_eq!(re.push(&msgs[1].1), Ok(None));
+        assert_eq!(re.push(&msgs[1].1), Ok(None));
+        assert_eq!(re.push(&msgs[0].1), Ok(None));
+        assert!(re.is_pending());
+        assert_eq!(re.push(&msgs[2].1), Ok(Some(Message::Stdout(&value))));
+        assert!(!re.is_pending());
+
+        // Late duplicates of a completed message are dropped.
+        assert_eq!(re.push(&msgs[2].1), Ok(None));
+        assert!(!re.is_pending());
+    }
+
+    #[test]
+    fn test_fragment_missing() {
+        let (a, b) = (pattern(600), pattern(300));
+        let mut wbuf = [0u8; 1100];
+        let mut w = Writer::new(&mut wbuf);
+        w.stdout(&a).unwrap();
+        w.stdout(&b).unwrap();
+        let msgs = messages(w.as_ref());
+        assert_eq!(msgs.len(), 5);
+
+        let mut rbuf = [0u8; 600];
+        let mut re = Reassembler::new(&mut rbuf);
+        assert_eq!(re.push(&msgs[0].1), Ok(None));
+        assert_eq!(re.push(&msgs[2].1), Ok(None));
+        assert_eq!(re.push(&msgs[3].1), Err(Error::MissingFragment));
+        assert_eq!(re.push(&msgs[4].1), Ok(Some(Message::Stdout(&b))));
+
+        let mut small = [0u8; 300];
+        let mut re = Reassembler::new(&mut small);
+        assert_eq!(re.push(&msgs[2].1), Err(Error::TlvError(tlv::Error::BufferTooShort)));
+        assert_eq!(re.push(&[0, 2, 2, 0x11]), Err(Error::InvalidRecord));
+        assert_eq!(re.push(&[0, 0, 2, 0x11, 1]), Err(Error::InvalidRecord));
+        assert_eq!(re.push(&[0, 0]), Err(Error::InvalidRecord));
+    }
+
+    #[test]
+    fn test_fragment_new_writers() {
+        let (a, b) = (pattern(600), pattern(700));
+        let mut rbuf = [0u8; 1000];
+        let mut re = Reassembler::new(&mut rbuf);
+
+        // Each message goes out through its own Writer, once carrying the
+        // ID over and once starting again from zero.
+        for &carry in &[true, false] {
+            let mut id = 0;
+            for value in &[&a, &b] {
+                let mut wbuf = [0u8; 1100];
+                let mut w = Writer::new(&mut wbuf);
+                if carry {
+                    w.set_fragment_id(id);
+                }
+                w.stdout(value).unwrap();
+                id = w.fragment_id();
+                let msgs = messages(w.as_ref());
+                assert_eq!(msgs.len(), 3);
+                assert_eq!(re.push(&msgs[0].1), Ok(None));
+                // A late copy of the first message's fragment is dropped.
+                assert_eq!(re.push(&msgs[0].1), Ok(None));
+                assert_eq!(re.push(&msgs[1].1), Ok(None));
+                assert_eq!(re.push(&msgs[2].1), Ok(Some(Message::Stdout(value))));
+                assert_eq!(re.push(&msgs[2].1), Ok(None));
+            }
+            assert_eq!(id, if carry { 2 } else { 1 });
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Running tests on the device and reporting the results through sctl.
+//!
+//! The device announces its tests and reports on each in turn with `Test`
+//! messages, then sends a summary followed by `Exit`, with code 0 if every
+//! test passed and 101 otherwise, as libtest does. A `Start` goes out
+//! before each test runs, so that a test that panics or hangs the device
+//! can still be named by the host.
+//!
+//! Tests are plain functions returning `Result<(), &'static str>`; the
+//! `sctl_assert!` family returns the failure message. `sctl_tests!` builds
+//! the table that `run` takes:
+//!
+//! ```ignore
+//! sctl_tests!(static TESTS = [
+//!     crc_matches,
+//!     #[ignore] flash_erase,
+//! ]);
+//!
+//! let code = sctl::harness::run(&TESTS, |frame| uart.write_all(frame));
+//! ```
+
+use record::{self, RecordReader, RecordWriter};
+use {Error, Message, Writer};
+
+/// The longest name or failure message sent; longer ones are truncated.
+pub const MAX_TEXT: usize = 200;
+
+pub type TestFn = fn() -> Result<(), &'static str>;
+
+pub struct Test {
+    pub name: &'static str,
+    pub run: TestFn,
+    pub ignore: bool,
+}
+
+/// Builds a `static` table of `Test`s from test functions, named after the
+/// functions. Functions marked `#[ignore]` are listed but not run.
+#[macro_export]
+macro_rules! sctl_tests {
+    (@test #[ignore] $f:path) => {
+        $crate::harness::Test { name: stringify!($f), run: $f, ignore: true }
+    };
+    (@test $f:path) => {
+        $crate::harness::Test { name: stringify!($f), run: $f, ignore: false }
+    };
+    (static $name:ident = [$($(#[$attr:ident])* $f:path),* $(,)*]) => {
+        static $name: &[$crate::harness::Test] = &[$(sctl_tests!(@test $(#[$attr])* $f)),*];
+    };
+}
+
+/// Fails the test with the condition and its location unless `cond` holds.
+#[macro_export]
+macro_rules! sctl_assert {
+    ($cond:expr) => {
+        if !$cond {
+            return Err(concat!("assertion failed: ", stringify!($cond), ", ", file!(), ":", line!()))
+        }
+    };
+}
+
+/// Fails the test unless both expressions are equal.
+#[macro_export]
+macro_rules! sctl_assert_eq {
+    ($left:expr, $right:expr) => {
+        if $left != $right {
+            return Err(concat!("assertion failed: ", stringify!($left), " == ", stringify!($right), ", ",
+                file!(), ":", line!()))
+        }
+    };
+}
+
+enum Field {
+    Op = 0x1,
+    Index = 0x2,
+    Name = 0x3,
+    Ignored = 0x4,
+    Outcome = 0x5,
+    Text = 0x6,
+    Count = 0x7,
+    Passed = 0x8,
+    Failed = 0x9,
+    Skipped = 0xa,
+}
+
+enum Op {
+    List = 0x1,
+    Entry = 0x2,
+    Start = 0x3,
+    Result = 0x4,
+    Summary = 0x5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    Pass = 0x0,
+    Fail = 0x1,
+    Ignored = 0x2,
+}
+
+impl Outcome {
+    fn from_u32(value: u32) -> Result<Outcome, Error> {
+        match value {
+            0x0 => Ok(Outcome::Pass),
+            0x1 => Ok(Outcome::Fail),
+            0x2 => Ok(Outcome::Ignored),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event<'a> {
+    /// The number of tests, sent before their entries.
+    List { count: u32 },
+    Entry { index: u32, name: &'a str, ignored: bool },
+    Start { index: u32 },
+    /// The outcome of a test, with the failure message if it failed.
+    Result { index: u32, outcome: Outcome, message: &'a str },
+    Summary { passed: u32, failed: u32, ignored: u32 },
+}
+
+// Shortens `s` to at most `MAX_TEXT` bytes without splitting a character.
+fn truncate(s: &str) -> &str {
+    let mut end = s.len().min(MAX_TEXT);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+impl<'a> Event<'a> {
+    pub fn write(&self, w: &mut Writer) -> Result<usize, Error> {
+        let mut buf = [0u8; 255];
+        let len = {
+            let mut rw = RecordWriter::new(&mut buf);
+            match *self {
+                Event::List { count } => {
+                    rw.write_u32(Field::Op as u32, Op::List as u32)?;
+                    rw.write_u32(Field::Count as u32, count)?;
+                }
+                Event::Entry { index, name, ignored } => {
+                    rw.write_u32(Field::Op as u32, Op::Entry as u32)?;
+                    rw.write_u32(Field::Index as u32, index)?;
+                    rw.write_str(Field::Name as u32, truncate(name))?;
+                    if ignored {
+                        rw.write_bool(Field::Ignored as u32, true)?;
+                    }
+                }
+                Event::Start { index } => {
+                    rw.write_u32(Field::Op as u32, Op::Start as u32)?;
+                    rw.write_u32(Field::Index as u32, index)?;
+                }
+                Event::Result { index, outcome, message } => {
+                    rw.write_u32(Field::Op as u32, Op::Result as u32)?;
+                    rw.write_u32(Field::Index as u32, index)?;
+                    rw.write_u32(Field::Outcome as u32, outcome as u32)?;
+                    if !message.is_empty() {
+                        rw.write_str(Field::Text as u32, truncate(message))?;
+                    }
+                }
+                Event::Summary { passed, failed, ignored } => {
+                    rw.write_u32(Field::Op as u32, Op::Summary as u32)?;
+                    rw.write_u32(Field::Passed as u32, passed)?;
+                    rw.write_u32(Field::Failed as u32, failed)?;
+                    rw.write_u32(Field::Skipped as u32, ignored)?;
+                }
+            }
+            rw.pos()
+        };
+        w.test(&buf[..len])
+    }
+
+    /// Decodes a `Test` message, returning `None` for any other message.
+    pub fn from_message(msg: &Message<'a>) -> Result<Option<Self>, Error> {
+        let buf = match *msg {
+            Message::Test(buf) => buf,
+            _ => return Ok(None),
+        };
+        let mut op = None;
+        let (mut index, mut name, mut ignored, mut outcome, mut text) = (None, None, false, None, "");
+        let (mut count, mut passed, mut failed, mut skipped) = (None, None, None, None);
+        let mut r = RecordReader::new(buf);
+        while let Some((tag, value)) = r.read()? {
+            match tag {
+                0x1 => op = Some(record::to_u32(value)?),
+                0x2 => index = Some(record::to_u32(value)?),
+                0x3 => name = Some(record::to_str(value)?),
+                0x4 => ignored = record::to_bool(value)?,
+                0x5 => outcome = Some(Outcome::from_u32(record::to_u32(value)?)?),
+                0x6 => text = record::to_str(value)?,
+                0x7 => count = Some(record::to_u32(value)?),
+                0x8 => passed = Some(record::to_u32(value)?),
+                0x9 => failed = Some(record::to_u32(value)?),
+                0xa => skipped = Some(record::to_u32(value)?),
+                _ => {}
+            }
+        }
+        let event = match (op, index) {
+            (Some(0x1), _) => Event::List { count: count.ok_or(Error::InvalidRecord)? },
+            (Some(0x2), Some(index)) => Event::Entry { index, name: name.ok_or(Error::InvalidRecord)?, ignored },
+            (Some(0x3), Some(index)) => Event::Start { index },
+            (Some(0x4), Some(index)) => Event::Result { index, outcome: outcome.ok_or(Error::InvalidRecord)?, message: text },
+            (Some(0x5), _) => match (passed, failed, skipped) {
+                (Some(passed), Some(failed), Some(ignored)) => Event::Summary { passed, failed, ignored },
+                _ => return Err(Error::InvalidRecord),
+            },
+            _ => return Err(Error::InvalidRecord),
+        };
+        Ok(Some(event))
+    }
+}
+
+/// Runs `tests`, sending each message as a COBS frame through `send`, and
+/// returns the exit code that was sent.
+pub fn run<F: FnMut(&[u8])>(tests: &[Test], mut send: F) -> u8 {
+    let mut emit = |f: &dyn Fn(&mut Writer) -> Result<usize, Error>| {
+        let mut wbuf = [0u8; 260];
+        let mut frame = [0u8; 270];
+        let mut w = Writer::new(&mut wbuf);
+        if f(&mut w).is_ok() {
+            if let Ok(frame) = w.encode(&mut frame) {
+                send(frame);
+            }
+        }
+    };
+
+    emit(&|w| Event::List { count: tests.len() as u32 }.write(w));
+    for (index, test) in tests.iter().enumerate() {
+        let index = index as u32;
+        emit(&|w| Event::Entry { index, name: test.name, ignored: test.ignore }.write(w));
+    }
+
+    let (mut passed, mut failed, mut ignored) = (0, 0, 0);
+    for (index, test) in tests.iter().enumerate() {
+        let index = index as u32;
+        if test.ignore {
+            ignored += 1;
+            emit(&|w| Event::Result { index, outcome: Outcome::Ignored, message: "" }.write(w));
+            continue
+        }
+        emit(&|w| Event::Start { index }.write(w));
+        let (outcome, message) = match (test.run)() {
+            Ok(()) => (Outcome::Pass, ""),
+            Err(message) => (Outcome::Fail, message),
+        };
+        match outcome {
+            Outcome::Pass => passed += 1,
+            _ => failed += 1,
+        }
+        emit(&|w| Event::Result { index, outcome, message }.write(w));
+    }
+
+    emit(&|w| Event::Summary { passed, failed, ignored }.write(w));
+    let code = if failed == 0 { 0 } else { 101 };
+    emit(&|w| w.exit(code));
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cobs;
+    use std::vec::Vec;
+    use Reader;
+
+    fn adds() -> Result<(), &'static str> {
+        sctl_assert_eq!(1 + 1, 2);
+        Ok(())
+    }
+
+    fn fails() -> Result<(), &'static str> {
+        sctl_assert!(1 > 2);
+        Ok(())
+    }
+
+    fn slow() -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    sctl_tests!(static TESTS = [
+        adds,
+        fails,
+        #[ignore] slow,
+    ]);
+
+    #[test]
+    fn test_run() {
+        let mut frames: Vec<Vec<u8>> = Vec::new();
+        assert_eq!(run(TESTS, |frame| frames.push(frame.to_vec())), 101);
+
+        let mut events = Vec::new();
+        let mut tmp = [0u8; 256];
+        let mut exit = None;
+        for frame in &frames {
+            let mut decoded = [0u8; 256];
+            let n = cobs::decode(&frame[..frame.len() - 1], &mut decoded).unwrap();
+            let mut r = Reader::new(&decoded[..n]);
+            let msg = r.read(&mut tmp).unwrap().unwrap();
+            match Event::from_message(&msg).unwrap() {
+                Some(event) => events.push(format!("{:?}", event)),
+                None => exit = Some(msg == Message::Exit(101)),
+            }
+        }
+        assert_eq!(exit, Some(true));
+        let message = concat!("assertion failed: 1 > 2, ", file!());
+        assert_eq!(events[..7], [
+            "List { count: 3 }",
+            "Entry { index: 0, name: \"adds\", ignored: false }",
+            "Entry { index: 1, name: \"fails\", ignored: false }",
+            "Entry { index: 2, name: \"slow\", ignored: true }",
+            "Start { index: 0 }",
+            "Result { index: 0, outcome: Pass, message: \"\" }",
+            "Start { index: 1 }",
+        ]);
+        assert!(events[7].starts_with(&format!("Result {{ index: 1, outcome: Fail, message: \"{}:", message)));
+        assert_eq!(events[8..], [
+            "Result { index: 2, outcome: Ignored, message: \"\" }",
+            "Summary { passed: 1, failed: 1, ignored: 1 }",
+        ]);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let long = "é".repeat(150);
+        assert_eq!(truncate(&long).len(), MAX_TEXT);
+        let long = format!("a{}", long);
+        assert_eq!(truncate(&long).len(), MAX_TEXT - 1);
+    }
+}
@@ -0,0 +1,152 @@
+//! Link liveness.
+//!
+//! Each end sends a `Heartbeat` message every `interval` ticks, carrying a
+//! wrapping counter. Any message from the peer counts as a sign of life; the
+//! peer is declared dead once `max_missed` intervals pass without one.
+//! `Session::check` turns a dead link into a disconnect.
+
+use {Clock, Error, Message, Writer};
+
+pub struct Heartbeat<C: Clock> {
+    clock: C,
+    interval: u32,
+    max_missed: u32,
+    sent: u32,
+    seen: u32,
+    count: u8,
+}
+
+impl<C: Clock> Heartbeat<C> {
+    pub fn new(clock: C, interval: u32, max_missed: u32) -> Self {
+        let now = clock.now();
+        Heartbeat { clock, interval, max_missed, sent: now, seen: now, count: 0 }
+    }
+
+    /// Writes a `Heartbeat` message if one is due. Returns true if one was
+    /// written.
+    pub fn poll(&mut self, w: &mut Writer) -> Result<bool, Error> {
+        let now = self.clock.now();
+        if now.wrapping_sub(self.sent) < self.interval {
+            return Ok(false)
+        }
+        w.heartbeat(self.count)?;
+        self.count = self.count.wrapping_add(1);
+        self.sent = now;
+        Ok(true)
+    }
+
+    /// Records a message from the peer. Returns true if `msg` was a
+    /// `Heartbeat`.
+    pub fn receive(&mut self, msg: &Message) -> bool {
+        self.seen = self.clock.now();
+        matches!(*msg, Message::Heartbeat(_))
+    }
+
+    /// Returns the number of whole intervals since the peer was last heard.
+    pub fn missed(&self) -> u32 {
+        if self.interval == 0 {
+            return 0
+        }
+        self.clock.now().wrapping_sub(self.seen) / self.interval
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.missed() < self.max_missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use session::{Observer, Session, State};
+    use {Reader, Tag};
+
+    #[derive(Default)]
+    struct Deaths(u32);
+
+    impl Observer for Deaths {
+        fn dead(&mut self) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let clock = Cell::new(0xffff_fff0);
+        let mut device = Heartbeat::new(&clock, 10, 3);
+        let mut host = Heartbeat::new(&clock, 10, 3);
+        let mut session = Session::new(Deaths::default());
+        session.receive(&Message::Boot(b"")).unwrap();
+
+        // A quiet but healthy device keeps the link alive across the clock
+        // wrapping.
+        for _ in 0..100 {
+            clock.set(clock.get().wrapping_add(1));
+            let mut wbuf = [0u8; 8];
+            let mut w = Writer::new(&mut wbuf);
+            device.poll(&mut w).unwrap();
+            let mut r = Reader::new(w.as_ref());
+            let mut tmp = [0u8; 8];
+            while let Some(msg) = r.read(&mut tmp).unwrap() {
+                assert!(host.receive(&msg));
+                session.receive(&msg).unwrap();
+            }
+            assert!(session.check(&host));
+        }
+        assert_eq!(session.state(), State::Booted);
+
+        // Then it goes silent.
+        clock.set(clock.get().wrapping_add(29));
+        assert_eq!(host.missed(), 2);
+        assert!(session.check(&host));
+        clock.set(clock.get().wrapping_add(1));
+        assert!(!session.check(&host));
+        assert!(!session.check(&host));
+        assert_eq!(session.state(), State::Disconnected);
+        assert_eq!(session.observer().0, 1);
+
+        // It comes back without rebooting, and the session resumes.
+        for msg in [Message::Stdout(b"back"), Message::Run(b"")].iter() {
+            host.receive(msg);
+            session.receive(msg).unwrap();
+        }
+        assert!(session.check(&host));
+        assert_eq!(session.state(), State::Running);
+
+        // After a reboot it starts over instead.
+        clock.set(clock.get().wrapping_add(30));
+        assert!(!session.check(&host));
+        host.receive(&Message::Boot(b""));
+        session.receive(&Message::Boot(b"")).unwrap();
+        assert_eq!(session.state(), State::Booted);
+        assert_eq!(session.observer().0, 2);
+    }
+
+    #[test]
+    fn test_heartbeat_counter() {
+        let clock = Cell::new(0);
+        let mut hb = Heartbeat::new(&clock, 5, 1);
+        let mut wbuf = [0u8; 16];
+        let mut w = Writer::new(&mut wbuf);
+        assert_eq!(hb.poll(&mut w), Ok(false));
+        clock.set(5);
+        assert_eq!(hb.poll(&mut w), Ok(true));
+        assert_eq!(hb.poll(&mut w), Ok(false));
+        clock.set(10);
+        assert_eq!(hb.poll(&mut w), Ok(true));
+        assert!(!hb.is_alive());
+        assert!(!hb.receive(&Message::Stdout(b"")));
+        assert!(hb.is_alive());
+
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 8];
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Heartbeat(0))));
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Heartbeat(1))));
+        assert_eq!(r.read(&mut tmp), Ok(None));
+
+        // A heartbeat carries exactly one byte.
+        assert_eq!(Message::decode(Tag::Heartbeat as u32, b""), Err(Error::InvalidRecord));
+        assert_eq!(Message::decode(Tag::Heartbeat as u32, b"\x01\x02"), Err(Error::InvalidRecord));
+    }
+}
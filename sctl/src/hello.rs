@@ -0,0 +1,294 @@
+//! Boot handshake and protocol negotiation.
+//!
+//! After reset the device sends `Boot` carrying a `Hello` record that
+//! describes itself and what it supports. The host answers with `Hello`
+//! carrying its own record. Each side then calls `negotiate` with the peer's
+//! record; the result is the same on both ends: the lower protocol version,
+//! the smaller maximum frame size and the features both sides support.
+//! `Session::negotiate` does this and keeps the result for the session.
+
+use core::cmp;
+use core::fmt;
+
+use record::{self, RecordReader, RecordWriter};
+
+use Error;
+
+/// Highest protocol version implemented by this crate.
+pub const VERSION: u32 = 1;
+
+/// Lowest protocol version this crate can talk to.
+pub const MIN_VERSION: u32 = 1;
+
+/// Largest decoded frame the protocol allows, assumed for a peer that
+/// doesn't give its own limit.
+pub const MAX_FRAME: u32 = 4096;
+
+enum Field {
+    Version = 0x1,
+    DeviceId = 0x2,
+    Firmware = 0x3,
+    Build = 0x4,
+    MaxFrame = 0x5,
+    Features = 0x6,
+    TickRate = 0x7,
+    Nonce = 0x8,
+}
+
+/// Set of optional protocol features.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Features(pub u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// Stop-and-wait ARQ, see `arq`.
+    pub const ARQ: Features = Features(1 << 1);
+    /// Values over 255 bytes are fragmented, see `fragment`.
+    pub const FRAGMENT: Features = Features(1 << 2);
+    /// Credit flow control on stdio, see `flow`.
+    pub const FLOW: Features = Features(1 << 3);
+    /// Multiplexed channels, see `channel`.
+    pub const CHANNEL: Features = Features(1 << 4);
+    /// Requests carry sequence numbers, see `kv`.
+    pub const SEQ: Features = Features(1 << 5);
+    /// Periodic heartbeats, see `heartbeat`.
+    pub const HEARTBEAT: Features = Features(1 << 6);
+    /// Frames may be compressed, see `compress`.
+    pub const COMPRESS: Features = Features(1 << 7);
+    /// Frames are sealed with a key derived from a pre-shared key, see
+    /// `secure`.
+    pub const SECURE: Features = Features(1 << 8);
+
+    const NAMES: [(Features, &'static str); 8] = [
+        (Features::ARQ, "arq"),
+        (Features::FRAGMENT, "fragment"),
+        (Features::FLOW, "flow"),
+        (Features::CHANNEL, "channel"),
+        (Features::SEQ, "seq"),
+        (Features::HEARTBEAT, "heartbeat"),
+        (Features::COMPRESS, "compress"),
+        (Features::SECURE, "secure"),
+    ];
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    pub fn union(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut rest = self.0;
+        let mut sep = "";
+        for &(feature, name) in Features::NAMES.iter() {
+            if self.contains(feature) {
+                write!(f, "{}{}", sep, name)?;
+                rest &= !feature.0;
+                sep = ",";
+            }
+        }
+        if rest != 0 {
+            write!(f, "{}0x{:x}", sep, rest)?;
+        } else if sep.is_empty() {
+            write!(f, "none")?;
+        }
+        Ok(())
+    }
+}
+
+/// Description of one end of the link, exchanged at boot.
+#[derive(Debug, PartialEq)]
+pub struct Hello<'a> {
+    /// Highest protocol version supported.
+    pub version: u32,
+    pub device_id: &'a [u8],
+    pub firmware: &'a str,
+    /// Build identifier, typically a VCS commit hash.
+    pub build: &'a [u8],
+    /// Largest decoded frame this end can receive.
+    pub max_frame: u32,
+    pub features: Features,
+    /// Frequency of the clock used for timestamps, in ticks per second, or
+    /// 0 if this end doesn't timestamp messages.
+    pub tick_rate: u32,
+    /// Fresh random bytes for deriving session keys, or empty without
+    /// `Features::SECURE`.
+    pub nonce: &'a [u8],
+}
+
+/// Session parameters both ends agreed on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Params {
+    pub version: u32,
+    pub max_frame: u32,
+    pub features: Features,
+}
+
+impl<'a> Hello<'a> {
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let len = {
+            let mut w = RecordWriter::new(buf);
+            w.write_u32(Field::Version as u32, self.version)?;
+            w.write_bytes(Field::DeviceId as u32, self.device_id)?;
+            w.write_str(Field::Firmware as u32, self.firmware)?;
+            w.write_bytes(Field::Build as u32, self.build)?;
+            w.write_u32(Field::MaxFrame as u32, self.max_frame)?;
+            w.write_u32(Field::Features as u32, self.features.0)?;
+            if self.tick_rate != 0 {
+                w.write_u32(Field::TickRate as u32, self.tick_rate)?;
+            }
+            if !self.nonce.is_empty() {
+                w.write_bytes(Field::Nonce as u32, self.nonce)?;
+            }
+            w.pos()
+        };
+        Ok(&buf[..len])
+    }
+
+    /// Decodes a hello record. Fields a peer leaves out take their
+    /// defaults; a record without a version is rejected.
+    pub fn decode(buf: &'a [u8]) -> Result<Self, Error> {
+        let mut version = None;
+        let mut hello = Hello {
+            version: 0,
+            device_id: &[],
+            firmware: "",
+            build: &[],
+            max_frame: MAX_FRAME,
+            features: Features::NONE,
+            tick_rate: 0,
+            nonce: &[],
+        };
+        let mut r = RecordReader::new(buf);
+        while let Some((tag, value)) = r.read()? {
+            match tag {
+                0x1 => version = Some(record::to_u32(value)?),
+                0x2 => hello.device_id = value,
+                0x3 => hello.firmware = record::to_str(value)?,
+                0x4 => hello.build = value,
+                0x5 => hello.max_frame = record::to_u32(value)?,
+                0x6 => hello.features = Features(record::to_u32(value)?),
+                0x7 => hello.tick_rate = record::to_u32(value)?,
+                0x8 => hello.nonce = value,
+                _ => {}
+            }
+        }
+        hello.version = version.ok_or(Error::InvalidRecord)?;
+        Ok(hello)
+    }
+
+    /// Settles on the parameters shared with `peer`. Fails with
+    /// `Error::Incompatible` if no common protocol version exists.
+    pub fn negotiate(&self, peer: &Hello) -> Result<Params, Error> {
+        let version = cmp::min(self.version, peer.version);
+        if version < MIN_VERSION {
+            return Err(Error::Incompatible)
+        }
+        Ok(Params {
+            version,
+            max_frame: cmp::min(self.max_frame, peer.max_frame),
+            features: self.features.intersection(peer.features),
+        })
+    }
+}
+
+impl<'a> fmt::Display for Hello<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sctl v{} device ", self.version)?;
+        for b in self.device_id {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, " firmware {} build ", self.firmware)?;
+        for b in self.build {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, " max frame {} features {}", self.max_frame, self.features)
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sctl v{} max frame {} features {}", self.version, self.max_frame, self.features)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+    use {Message, Reader, Writer};
+
+    fn device() -> Hello<'static> {
+        Hello {
+            version: 1,
+            device_id: &[0x00, 0x1f, 0xa0, 0x42],
+            firmware: "0.3.1",
+            build: &[0xde, 0xad, 0xbe, 0xef],
+            max_frame: 256,
+            features: Features::ARQ.union(Features::FRAGMENT).union(Features::HEARTBEAT),
+            tick_rate: 32768,
+            nonce: &[0x5a; 16],
+        }
+    }
+
+    #[test]
+    fn test_hello() {
+        let hello = device();
+        let mut buf = [0u8; 64];
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        w.boot(hello.encode(&mut buf).unwrap()).unwrap();
+
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 64];
+        match r.read(&mut tmp).unwrap() {
+            Some(Message::Boot(value)) => assert_eq!(Hello::decode(value), Ok(device())),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(hello.to_string(), "sctl v1 device 001fa042 firmware 0.3.1 build deadbeef max frame 256 features arq,fragment,heartbeat");
+
+        // Only the version is required.
+        assert_eq!(Hello::decode(&[0x06, 0x01, 0x02]), Err(Error::InvalidRecord));
+        let bare = Hello::decode(&[0x01, 0x01, 0x02, 0x09, 0x01, 0x00]).unwrap();
+        assert_eq!(bare.version, 2);
+        assert_eq!(bare.features, Features::NONE);
+        assert_eq!(bare.max_frame, MAX_FRAME);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let device = device();
+        let host = Hello {
+            version: 3,
+            device_id: &[],
+            firmware: "host",
+            build: &[],
+            max_frame: 4096,
+            features: Features::ARQ.union(Features::FRAGMENT).union(Features::FLOW).union(Features(1 << 12)),
+            tick_rate: 0,
+            nonce: &[],
+        };
+        let params = device.negotiate(&host).unwrap();
+        assert_eq!(params, host.negotiate(&device).unwrap());
+        assert_eq!(params.version, 1);
+        assert_eq!(params.max_frame, 256);
+        assert!(params.features.contains(Features::ARQ.union(Features::FRAGMENT)));
+        assert!(!params.features.contains(Features::HEARTBEAT));
+        assert_eq!(params.to_string(), "sctl v1 max frame 256 features arq,fragment");
+        assert_eq!(host.features.to_string(), "arq,fragment,flow,0x1000");
+
+        // A peer that leaves out its frame size gets the protocol's.
+        let bare = Hello::decode(&[0x01, 0x01, 0x01]).unwrap();
+        assert_eq!(host.negotiate(&bare).unwrap().max_frame, MAX_FRAME);
+
+        let old = Hello { version: 0, ..device };
+        assert_eq!(old.negotiate(&host), Err(Error::Incompatible));
+    }
+}
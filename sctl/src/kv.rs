@@ -0,0 +1,583 @@
+//! Typed key-value access on top of `Get`, `Set` and `Val`.
+//!
+//! A key is either a path string or a numeric ID. `Get` carries a key, `Set`
+//! carries a key and a value, and every request is answered with a `Val`
+//! that echoes the key along with either the current value or a `Status`.
+//!
+//! `Client` attaches a sequence number to each request and pairs responses
+//! with requests by the echoed sequence number. Peers that don't echo
+//! sequence numbers answer in order, so un-numbered responses complete the
+//! oldest request in flight.
+
+use pending::Tracker;
+use record::{self, RecordReader, RecordWriter};
+use {Clock, Error, Message, Writer};
+
+pub use pending::Pending;
+
+enum Field {
+    Path = 0x1,
+    Id = 0x2,
+    Int = 0x3,
+    Bool = 0x4,
+    Str = 0x5,
+    Bytes = 0x6,
+    Status = 0x7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Key<'a> {
+    Path(&'a str),
+    Id(u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Int(i32),
+    Bool(bool),
+    Str(&'a str),
+    Bytes(&'a [u8]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    NotFound = 0x1,
+    ReadOnly = 0x2,
+    TypeMismatch = 0x3,
+    InvalidValue = 0x4,
+}
+
+impl Status {
+    fn from_u32(value: u32) -> Result<Status, Error> {
+        match value {
+            0x1 => Ok(Status::NotFound),
+            0x2 => Ok(Status::ReadOnly),
+            0x3 => Ok(Status::TypeMismatch),
+            0x4 => Ok(Status::InvalidValue),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+/// Variables exposed by the device.
+pub trait Registry {
+    fn get<'a>(&'a self, key: &Key) -> Result<Value<'a>, Status>;
+    fn set(&mut self, key: &Key, value: &Value) -> Result<(), Status>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request<'a> {
+    Get(Key<'a>),
+    Set(Key<'a>, Value<'a>),
+}
+
+impl<'a> Request<'a> {
+    pub fn key(&self) -> &Key<'a> {
+        match *self {
+            Request::Get(ref key) | Request::Set(ref key, _) => key,
+        }
+    }
+
+    pub fn write(&self, w: &mut Writer) -> Result<usize, Error> {
+        let mut buf = [0u8; 255];
+        let len = {
+            let mut rw = RecordWriter::new(&mut buf);
+            write_key(&mut rw, self.key())?;
+            if let Request::Set(_, ref value) = *self {
+                write_value(&mut rw, value)?;
+            }
+            rw.pos()
+        };
+        match *self {
+            Request::Get(_) => w.get(&buf[..len]),
+            Request::Set(..) => w.set(&buf[..len]),
+        }
+    }
+
+    /// Decodes a `Get` or `Set` message, returning `None` for any other message.
+    pub fn from_message(msg: &Message<'a>) -> Result<Option<Self>, Error> {
+        match *msg {
+            Message::Get(buf) => {
+                let (key, _, _) = read_record(buf)?;
+                Ok(Some(Request::Get(key)))
+            }
+            Message::Set(buf) => match read_record(buf)? {
+                (key, Some(value), None) => Ok(Some(Request::Set(key, value))),
+                _ => Err(Error::InvalidRecord),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Response<'a> {
+    pub key: Key<'a>,
+    pub result: Result<Value<'a>, Status>,
+}
+
+impl<'a> Response<'a> {
+    pub fn matches(&self, req: &Request) -> bool {
+        self.key == *req.key()
+    }
+
+    pub fn write(&self, w: &mut Writer) -> Result<usize, Error> {
+        let mut buf = [0u8; 255];
+        let len = {
+            let mut rw = RecordWriter::new(&mut buf);
+            write_key(&mut rw, &self.key)?;
+            match self.result {
+                Ok(ref value) => write_value(&mut rw, value)?,
+                Err(status) => rw.write_u32(Field::Status as u32, status as u32)?,
+            };
+            rw.pos()
+        };
+        w.val(&buf[..len])
+    }
+
+    /// Decodes a `Val` message, returning `None` for any other message.
+    pub fn from_message(msg: &Message<'a>) -> Result<Option<Self>, Error> {
+        match *msg {
+            Message::Val(buf) => match read_record(buf)? {
+                (key, Some(value), None) => Ok(Some(Response { key, result: Ok(value) })),
+                (key, None, Some(status)) => Ok(Some(Response { key, result: Err(status) })),
+                _ => Err(Error::InvalidRecord),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Answers a `Get` or `Set` message from `registry`, writing the `Val`
+/// response to `w`. `seq` is the sequence number attached to the request and
+/// is echoed on the response. Returns `false` if `msg` is not a key-value
+/// request.
+pub fn serve<R: Registry>(registry: &mut R, msg: &Message, seq: Option<u32>, w: &mut Writer) -> Result<bool, Error> {
+    let req = match Request::from_message(msg)? {
+        Some(req) => req,
+        None => return Ok(false),
+    };
+    w.all_or_nothing(|w| {
+        if let Some(seq) = seq {
+            w.seq(seq)?;
+        }
+        if let Request::Set(ref key, ref value) = req {
+            if let Err(status) = registry.set(key, value) {
+                return Response { key: *key, result: Err(status) }.write(w)
+            }
+        }
+        let key = *req.key();
+        Response { key, result: registry.get(&key) }.write(w)
+    })?;
+    Ok(true)
+}
+
+/// The outcome of a single request issued by `Client`.
+#[derive(Debug, PartialEq)]
+pub struct Completion<'a> {
+    pub seq: u32,
+    pub result: Result<Response<'a>, Error>,
+}
+
+/// Host side of the key-value protocol.
+///
+/// Each request in flight occupies one of the caller-supplied `pending`
+/// slots until its response arrives or `timeout` ticks pass.
+pub struct Client<'a, C: Clock> {
+    tracker: Tracker<'a, C>,
+}
+
+impl<'a, C: Clock> Client<'a, C> {
+    pub fn new(clock: C, timeout: u32, pending: &'a mut [Option<Pending>]) -> Self {
+        Client { tracker: Tracker::new(clock, timeout, pending) }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.tracker.in_flight()
+    }
+
+    /// Writes `req` to `w` and returns the sequence number assigned to it.
+    /// Fails with `Error::Busy` if every pending slot is in use.
+    pub fn request(&mut self, req: &Request, w: &mut Writer) -> Result<u32, Error> {
+        self.tracker.issue(|seq| w.all_or_nothing(|w| {
+            w.seq(seq)?;
+            req.write(w)?;
+            Ok(())
+        }))
+    }
+
+    /// Feeds a received message, along with the sequence number the reader
+    /// found attached to it. Returns the completed request if `msg` is a
+    /// response to one.
+    pub fn receive<'m>(&mut self, msg: &Message<'m>, seq: Option<u32>) -> Result<Option<Completion<'m>>, Error> {
+        let resp = match Response::from_message(msg)? {
+            Some(resp) => resp,
+            None => return Ok(None),
+        };
+        Ok(self.tracker.complete(seq).map(|seq| Completion { seq, result: Ok(resp) }))
+    }
+
+    /// Returns one request that has been waiting for `timeout` ticks or
+    /// more, releasing its slot. Call repeatedly until it returns `None`.
+    pub fn expire(&mut self) -> Option<Completion<'static>> {
+        self.tracker.expire().map(|seq| Completion { seq, result: Err(Error::Timeout) })
+    }
+}
+
+fn write_key(w: &mut RecordWriter, key: &Key) -> Result<usize, Error> {
+    match *key {
+        Key::Path(path) => w.write_str(Field::Path as u32, path),
+        Key::Id(id) => w.write_u32(Field::Id as u32, id),
+    }
+}
+
+pub(crate) fn write_value(w: &mut RecordWriter, value: &Value) -> Result<usize, Error> {
+    match *value {
+        Value::Int(v) => w.write_i32(Field::Int as u32, v),
+        Value::Bool(v) => w.write_bool(Field::Bool as u32, v),
+        Value::Str(v) => w.write_str(Field::Str as u32, v),
+        Value::Bytes(v) => w.write_bytes(Field::Bytes as u32, v),
+    }
+}
+
+/// Decodes a value field, returning `None` if `tag` is not a value tag.
+pub(crate) fn read_value(tag: u32, v: &[u8]) -> Result<Option<Value<'_>>, Error> {
+    match tag {
+        0x3 => Ok(Some(Value::Int(record::to_i32(v)?))),
+        0x4 => Ok(Some(Value::Bool(record::to_bool(v)?))),
+        0x5 => Ok(Some(Value::Str(record::to_str(v)?))),
+        0x6 => Ok(Some(Value::Bytes(v))),
+        _ => Ok(None),
+    }
+}
+
+fn read_record<'a>(buf: &'a [u8]) -> Result<(Key<'a>, Option<Value<'a>>, Option<Status>), Error> {
+    let mut key = None;
+    let mut value = None;
+    let mut status = None;
+    let mut r = RecordReader::new(buf);
+    while let Some((tag, v)) = r.read()? {
+        match tag {
+            0x1 => key = Some(Key::Path(record::to_str(v)?)),
+            0x2 => key = Some(Key::Id(record::to_u32(v)?)),
+            0x3..=0x6 => value = read_value(tag, v)?,
+            0x7 => status = Some(Status::from_u32(record::to_u32(v)?)?),
+            _ => {}
+        }
+    }
+    match key {
+        Some(key) => Ok((key, value, status)),
+        None => Err(Error::InvalidRecord),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use cobs;
+    use Reader;
+
+    struct Device {
+        counter: i32,
+        enabled: bool,
+        name: [u8; 16],
+        name_len: usize,
+    }
+
+    impl Registry for Device {
+        fn get<'a>(&'a self, key: &Key) -> Result<Value<'a>, Status> {
+            match *key {
+                Key::Path("counter") | Key::Id(1) => Ok(Value::Int(self.counter)),
+                Key::Path("enabled") | Key::Id(2) => Ok(Value::Bool(self.enabled)),
+                Key::Path("name") | Key::Id(3) => {
+                    Ok(Value::Str(::core::str::from_utf8(&self.name[..self.name_len]).unwrap()))
+                }
+                Key::Path("serial") | Key::Id(4) => Ok(Value::Bytes(&[0xde, 0xad, 0xbe, 0xef])),
+                _ => Err(Status::NotFound),
+            }
+        }
+
+        fn set(&mut self, key: &Key, value: &Value) -> Result<(), Status> {
+            match (*key, *value) {
+                (Key::Path("counter"), Value::Int(v)) | (Key::Id(1), Value::Int(v)) => self.counter = v,
+                (Key::Path("enabled"), Value::Bool(v)) | (Key::Id(2), Value::Bool(v)) => self.enabled = v,
+                (Key::Path("name"), Value::Str(v)) | (Key::Id(3), Value::Str(v)) => {
+                    if v.len() > self.name.len() {
+                        return Err(Status::InvalidValue)
+                    }
+                    self.name[..v.len()].copy_from_slice(v.as_bytes());
+                    self.name_len = v.len();
+                }
+                (Key::Path("serial"), _) | (Key::Id(4), _) => return Err(Status::ReadOnly),
+                (Key::Path("counter"), _) | (Key::Path("enabled"), _) | (Key::Path("name"), _) |
+                (Key::Id(1), _) | (Key::Id(2), _) | (Key::Id(3), _) => return Err(Status::TypeMismatch),
+                _ => return Err(Status::NotFound),
+            }
+            Ok(())
+        }
+    }
+
+    // Runs the requests through a COBS frame to the device and checks each
+    // response in the reply frame against `expected`.
+    fn exchange(device: &mut Device, requests: &[Request], expected: &[Response]) {
+        let mut hbuf = [0u8; 1024];
+        let mut frame = [0u8; 1100];
+        let mut h = Writer::new(&mut hbuf);
+        for req in requests {
+            req.write(&mut h).unwrap();
+        }
+        let frame = h.encode(&mut frame).unwrap();
+
+        let mut decoded = [0u8; 1100];
+        let n = cobs::decode(&frame[..frame.len() - 1], &mut decoded).unwrap();
+        let mut dbuf = [0u8; 1024];
+        let mut reply = [0u8; 1100];
+        let mut d = Writer::new(&mut dbuf);
+        let mut r = Reader::new(&decoded[..n]);
+        let mut tmp = [0u8; 256];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            assert!(serve(device, &msg, r.seq(), &mut d).unwrap());
+        }
+        let reply = d.encode(&mut reply).unwrap();
+
+        let n = cobs::decode(&reply[..reply.len() - 1], &mut decoded).unwrap();
+        let mut r = Reader::new(&decoded[..n]);
+        for (req, expected) in requests.iter().zip(expected) {
+            let msg = r.read(&mut tmp).unwrap().unwrap();
+            let resp = Response::from_message(&msg).unwrap().unwrap();
+            assert!(resp.matches(req));
+            assert_eq!(&resp, expected);
+        }
+        assert_eq!(r.read(&mut tmp), Ok(None));
+    }
+
+    #[test]
+    fn test_kv_loopback() {
+        let mut device = Device { counter: 7, enabled: false, name: [0; 16], name_len: 0 };
+        exchange(&mut device, &[
+            Request::Get(Key::Path("counter")),
+            Request::Get(Key::Id(2)),
+            Request::Get(Key::Path("serial")),
+            Request::Get(Key::Path("missing")),
+        ], &[
+            Response { key: Key::Path("counter"), result: Ok(Value::Int(7)) },
+            Response { key: Key::Id(2), result: Ok(Value::Bool(false)) },
+            Response { key: Key::Path("serial"), result: Ok(Value::Bytes(&[0xde, 0xad, 0xbe, 0xef])) },
+            Response { key: Key::Path("missing"), result: Err(Status::NotFound) },
+        ]);
+
+        exchange(&mut device, &[
+            Request::Set(Key::Path("counter"), Value::Int(-1000)),
+            Request::Set(Key::Id(2), Value::Bool(true)),
+            Request::Set(Key::Path("name"), Value::Str("sensor-1")),
+            Request::Set(Key::Path("serial"), Value::Bytes(&[0])),
+            Request::Set(Key::Path("counter"), Value::Str("x")),
+        ], &[
+            Response { key: Key::Path("counter"), result: Ok(Value::Int(-1000)) },
+            Response { key: Key::Id(2), result: Ok(Value::Bool(true)) },
+            Response { key: Key::Path("name"), result: Ok(Value::Str("sensor-1")) },
+            Response { key: Key::Path("serial"), result: Err(Status::ReadOnly) },
+            Response { key: Key::Path("counter"), result: Err(Status::TypeMismatch) },
+        ]);
+        assert_eq!(device.counter, -1000);
+        assert!(device.enabled);
+        assert_eq!(&device.name[..device.name_len], b"sensor-1");
+    }
+
+    #[test]
+    fn test_kv_not_request() {
+        let mut device = Device { counter: 0, enabled: false, name: [0; 16], name_len: 0 };
+        let mut dbuf = [0u8; 64];
+        let mut d = Writer::new(&mut dbuf);
+        assert_eq!(serve(&mut device, &Message::Stdout(b"hi"), None, &mut d), Ok(false));
+        assert_eq!(serve(&mut device, &Message::Get(b""), None, &mut d), Err(Error::InvalidRecord));
+        assert_eq!(d.as_ref().len(), 0);
+    }
+
+    #[test]
+    fn test_no_orphan_seq() {
+        // Room for a `Seq` but not the message after it.
+        let mut device = Device { counter: 0, enabled: false, name: [0; 16], name_len: 0 };
+        let mut wbuf = [0u8; 6];
+        let mut w = Writer::new(&mut wbuf);
+        let req = Request::Get(Key::Path("counter"));
+        let clock = Cell::new(0);
+        let mut pending = [None; 1];
+        let mut client = Client::new(&clock, 100, &mut pending);
+        assert!(client.request(&req, &mut w).is_err());
+        assert_eq!(client.in_flight(), 0);
+        assert_eq!(w.as_ref().len(), 0);
+
+        let mut rbuf = [0u8; 64];
+        let len = {
+            let mut r = Writer::new(&mut rbuf);
+            req.write(&mut r).unwrap();
+            r.as_ref().len()
+        };
+        let mut tmp = [0u8; 64];
+        let msg = Reader::new(&rbuf[..len]).read(&mut tmp).unwrap().unwrap();
+        assert!(serve(&mut device, &msg, Some(1), &mut w).is_err());
+        assert_eq!(w.as_ref().len(), 0);
+    }
+
+    // Answers every request in `requests`, writing the responses in reverse
+    // order.
+    fn answer_reversed<'a>(device: &mut Device, requests: &[u8], out: &'a mut [u8]) -> &'a [u8] {
+        let mut msgs = [[0u8; 64]; 4];
+        let mut lens = [0; 4];
+        let mut n = 0;
+        let mut r = Reader::new(requests);
+        loop {
+            let mut tmp = [0u8; 256];
+            let mut rbuf = [0u8; 64];
+            let len = match r.read(&mut tmp).unwrap() {
+                Some(msg) => {
+                    let mut w = Writer::new(&mut rbuf);
+                    serve(device, &msg, r.seq(), &mut w).unwrap();
+                    w.as_ref().len()
+                }
+                None => break,
+            };
+            msgs[n][..len].copy_from_slice(&rbuf[..len]);
+            lens[n] = len;
+            n += 1;
+        }
+        let mut pos = 0;
+        for i in (0..n).rev() {
+            let len = lens[i];
+            out[pos..pos + len].copy_from_slice(&msgs[i][..len]);
+            pos += len;
+        }
+        &out[..pos]
+    }
+
+    #[test]
+    fn test_client_out_of_order() {
+        let mut device = Device { counter: 3, enabled: true, name: [0; 16], name_len: 0 };
+        let clock = Cell::new(0);
+        let mut pending = [None; 2];
+        let mut client = Client::new(&clock, 100, &mut pending);
+
+        let mut hbuf = [0u8; 256];
+        let mut w = Writer::new(&mut hbuf);
+        let s1 = client.request(&Request::Get(Key::Path("counter")), &mut w).unwrap();
+        let s2 = client.request(&Request::Get(Key::Path("enabled")), &mut w).unwrap();
+        assert_eq!(client.request(&Request::Get(Key::Id(3)), &mut w), Err(Error::Busy));
+        assert_eq!(client.in_flight(), 2);
+
+        let mut out = [0u8; 256];
+        let reply = answer_reversed(&mut device, w.as_ref(), &mut out);
+        let mut r = Reader::new(reply);
+        let mut tmp = [0u8; 256];
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        let done = client.receive(&msg, r.seq()).unwrap().unwrap();
+        assert_eq!(done.seq, s2);
+        assert_eq!(done.result, Ok(Response { key: Key::Path("enabled"), result: Ok(Value::Bool(true)) }));
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        let done = client.receive(&msg, r.seq()).unwrap().unwrap();
+        assert_eq!(done.seq, s1);
+        assert_eq!(done.result, Ok(Response { key: Key::Path("counter"), result: Ok(Value::Int(3)) }));
+        assert_eq!(client.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_client_without_seq() {
+        let mut device = Device { counter: 3, enabled: true, name: [0; 16], name_len: 0 };
+        let clock = Cell::new(0);
+        let mut pending = [None; 4];
+        let mut client = Client::new(&clock, 100, &mut pending);
+
+        // A peer without sequence numbers answers in order.
+        let mut hbuf = [0u8; 256];
+        let mut w = Writer::new(&mut hbuf);
+        let s1 = client.request(&Request::Get(Key::Id(1)), &mut w).unwrap();
+        clock.set(1);
+        let s2 = client.request(&Request::Get(Key::Id(2)), &mut w).unwrap();
+
+        let mut r = Reader::new(w.as_ref());
+        let mut dbuf = [0u8; 256];
+        let mut d = Writer::new(&mut dbuf);
+        let mut tmp = [0u8; 256];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            serve(&mut device, &msg, None, &mut d).unwrap();
+        }
+
+        let mut r = Reader::new(d.as_ref());
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        assert_eq!(client.receive(&msg, r.seq()).unwrap().unwrap().seq, s1);
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        assert_eq!(client.receive(&msg, r.seq()).unwrap().unwrap().seq, s2);
+
+        // Responses with nothing in flight are ignored.
+        assert_eq!(client.receive(&msg, None), Ok(None));
+        assert_eq!(client.receive(&Message::Stdout(b"x"), Some(s1)), Ok(None));
+    }
+
+    #[test]
+    fn test_client_same_tick() {
+        let mut device = Device { counter: 3, enabled: true, name: [0; 16], name_len: 0 };
+        let clock = Cell::new(0);
+        let mut pending = [None; 4];
+        let mut client = Client::new(&clock, 100, &mut pending);
+
+        // Requests issued in the same tick still complete in order.
+        let mut hbuf = [0u8; 256];
+        let mut w = Writer::new(&mut hbuf);
+        let seqs = [
+            client.request(&Request::Get(Key::Id(1)), &mut w).unwrap(),
+            client.request(&Request::Get(Key::Id(2)), &mut w).unwrap(),
+            client.request(&Request::Get(Key::Id(3)), &mut w).unwrap(),
+        ];
+
+        let mut r = Reader::new(w.as_ref());
+        let mut dbuf = [0u8; 256];
+        let mut d = Writer::new(&mut dbuf);
+        let mut tmp = [0u8; 256];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            serve(&mut device, &msg, None, &mut d).unwrap();
+        }
+
+        let mut r = Reader::new(d.as_ref());
+        for (&seq, key) in seqs.iter().zip([Key::Id(1), Key::Id(2), Key::Id(3)].iter()) {
+            let msg = r.read(&mut tmp).unwrap().unwrap();
+            let done = client.receive(&msg, r.seq()).unwrap().unwrap();
+            assert_eq!(done.seq, seq);
+            assert_eq!(done.result.unwrap().key, *key);
+        }
+    }
+
+    #[test]
+    fn test_client_timeout() {
+        let clock = Cell::new(0xffff_fff0);
+        let mut pending = [None; 4];
+        let mut client = Client::new(&clock, 100, &mut pending);
+
+        let mut hbuf = [0u8; 256];
+        let mut w = Writer::new(&mut hbuf);
+        let s1 = client.request(&Request::Get(Key::Id(1)), &mut w).unwrap();
+        clock.set(0x10);
+        let s2 = client.request(&Request::Get(Key::Id(2)), &mut w).unwrap();
+        assert_eq!(client.expire(), None);
+
+        // The first request times out across the clock wrap.
+        clock.set(0x60);
+        assert_eq!(client.expire(), Some(Completion { seq: s1, result: Err(Error::Timeout) }));
+        assert_eq!(client.expire(), None);
+        assert_eq!(client.in_flight(), 1);
+
+        // A late response to the expired request is dropped.
+        let mut vbuf = [0u8; 64];
+        let mut v = Writer::new(&mut vbuf);
+        v.seq(s1).unwrap();
+        Response { key: Key::Id(1), result: Ok(Value::Int(0)) }.write(&mut v).unwrap();
+        let mut r = Reader::new(v.as_ref());
+        let mut tmp = [0u8; 256];
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        assert_eq!(client.receive(&msg, r.seq()), Ok(None));
+
+        clock.set(0x74);
+        assert_eq!(client.expire(), Some(Completion { seq: s2, result: Err(Error::Timeout) }));
+        assert_eq!(client.in_flight(), 0);
+    }
+}
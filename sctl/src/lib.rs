@@ -1,14 +1,111 @@
 #![no_std]
 
+extern crate byteorder;
+#[cfg(feature = "secure")]
+extern crate chacha20poly1305;
 extern crate cobs;
+#[cfg(feature = "embedded-io")]
+extern crate embedded_io;
+#[cfg(feature = "secure")]
+extern crate hkdf;
+extern crate leb128;
+#[cfg(feature = "secure")]
+extern crate sha2;
 extern crate tlv;
 
+#[cfg(feature = "tokio")]
+extern crate bytes;
+#[cfg(any(test, feature = "std"))]
+#[macro_use]
+extern crate std;
+#[cfg(feature = "tokio")]
+extern crate tokio_util;
+
+#[cfg(all(test, feature = "tokio"))]
+extern crate futures;
+#[cfg(all(test, feature = "tokio"))]
+extern crate tokio;
+
 use core::convert::AsRef;
+use core::fmt;
+
+mod record;
+pub mod arq;
+pub mod channel;
+#[cfg(feature = "tokio")]
+pub mod codec;
+pub mod compress;
+pub mod deferred;
+pub mod exception;
+pub mod flow;
+pub mod fragment;
+pub mod harness;
+pub mod heartbeat;
+pub mod hello;
+pub mod kv;
+pub mod memory;
+pub mod panic;
+mod pending;
+pub mod rpc;
+#[cfg(feature = "secure")]
+pub mod secure;
+#[cfg(feature = "embedded-io")]
+pub mod serial;
+pub mod session;
+pub mod telemetry;
+pub mod transfer;
+
+pub use exception::ExceptionRecord;
+pub use hello::{Hello, Params};
+pub use panic::PanicRecord;
+pub use session::{Session, State};
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     CobsError(cobs::Error),
     TlvError(tlv::Error),
+    Leb128Error(leb128::Error),
+    InvalidRecord,
+    Busy,
+    Timeout,
+    MissingFragment,
+    WouldBlock,
+    Incompatible,
+    Unexpected,
+    /// A sealed frame failed authentication, or a plain frame arrived where
+    /// only sealed ones are accepted.
+    Unauthenticated,
+    /// A sealed frame was authentic but not newer than the last one.
+    Replayed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::CobsError(ref e) => write!(f, "bad COBS frame: {:?}", e),
+            Error::TlvError(ref e) => write!(f, "bad TLV record: {:?}", e),
+            Error::Leb128Error(ref e) => write!(f, "bad LEB128 value: {:?}", e),
+            Error::InvalidRecord => write!(f, "invalid record"),
+            Error::Busy => write!(f, "busy"),
+            Error::Timeout => write!(f, "timed out"),
+            Error::MissingFragment => write!(f, "missing fragment"),
+            Error::WouldBlock => write!(f, "would block"),
+            Error::Incompatible => write!(f, "incompatible peer"),
+            Error::Unexpected => write!(f, "unexpected message"),
+            Error::Unauthenticated => write!(f, "frame failed authentication"),
+            Error::Replayed => write!(f, "replayed frame"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(other: Error) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, other)
+    }
 }
 
 impl From<cobs::Error> for Error {
@@ -23,24 +120,76 @@ impl From<tlv::Error> for Error {
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl From<leb128::Error> for Error {
+    fn from(other: leb128::Error) -> Error {
+        Error::Leb128Error(other)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Tag {
     Boot = 0x1,
     Run = 0x2,
     Exit = 0x3,
     Exception = 0x4,
     Panic = 0x5,
+    Hello = 0x6,
     Stdin = 0x10,
     Stdout = 0x11,
     Stderr = 0x12,
+    Channel = 0x13,
     Error = 0x20,
     Warn = 0x21,
     Info = 0x22,
     Debug = 0x23,
     Trace = 0x24,
+    Deferred = 0x25,
     Val = 0x30,
     Get = 0x31,
     Set = 0x32,
+    Peek = 0x50,
+    Poke = 0x51,
+    Mem = 0x52,
+    Transfer = 0x60,
+    TransferAck = 0x61,
+    Call = 0x70,
+    Return = 0x71,
+    Test = 0x80,
+    Signal = 0x90,
+    Sample = 0x91,
+    Seq = 0x40,
+    Frame = 0x41,
+    Ack = 0x42,
+    Nak = 0x43,
+    Fragment = 0x44,
+    Credit = 0x45,
+    Heartbeat = 0x46,
+    Time = 0x47,
+    Sync = 0x48,
+    /// Marks a sealed frame; never sent as a message.
+    Sealed = 0x7E,
+    /// Marks a compressed frame; never sent as a message.
+    Compressed = 0x7F,
+}
+
+/// Monotonic tick source used for timeouts. Ticks wrap at `u32::MAX`, so
+/// elapsed time must always be computed with `wrapping_sub`.
+pub trait Clock {
+    fn now(&self) -> u32;
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> u32 {
+        (**self).now()
+    }
+}
+
+// Lets tests drive time by hand.
+#[cfg(test)]
+impl Clock for core::cell::Cell<u32> {
+    fn now(&self) -> u32 {
+        self.get()
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -50,33 +199,110 @@ pub enum Message<'a> {
     Exit(u8),
     Exception(&'a [u8]),
     Panic(&'a [u8]),
+    Hello(&'a [u8]),
     Stdin(&'a [u8]),
     Stdout(&'a [u8]),
     Stderr(&'a [u8]),
+    Channel(&'a [u8]),
     Error(&'a [u8]),
     Warn(&'a [u8]),
     Info(&'a [u8]),
     Debug(&'a [u8]),
     Trace(&'a [u8]),
+    Deferred(&'a [u8]),
     Val(&'a [u8]),    
     Get(&'a [u8]),
     Set(&'a [u8]),
+    Peek(&'a [u8]),
+    Poke(&'a [u8]),
+    Mem(&'a [u8]),
+    Transfer(&'a [u8]),
+    TransferAck(&'a [u8]),
+    Call(&'a [u8]),
+    Return(&'a [u8]),
+    Test(&'a [u8]),
+    Signal(&'a [u8]),
+    Sample(&'a [u8]),
+    Frame(u8),
+    Ack(u8),
+    Nak(u8),
+    Fragment(&'a [u8]),
+    Credit(&'a [u8]),
+    Heartbeat(u8),
+    Sync(u8),
+}
+
+// Reads the value of a message that carries a single byte.
+fn to_byte(value: &[u8]) -> Result<u8, Error> {
+    match *value {
+        [b] => Ok(b),
+        _ => Err(Error::InvalidRecord),
+    }
+}
+
+impl<'a> Message<'a> {
+    /// Builds a message from its tag and value. Fails with
+    /// `Error::InvalidRecord` for an unknown tag or a malformed value.
+    pub fn decode(tag: u32, value: &'a [u8]) -> Result<Message<'a>, Error> {
+        match tag {
+            0x1 => Ok(Message::Boot(value)),
+            0x2 => Ok(Message::Run(value)),
+            0x3 => Ok(Message::Exit(to_byte(value)?)),
+            0x4 => Ok(Message::Exception(value)),
+            0x5 => Ok(Message::Panic(value)),
+            0x6 => Ok(Message::Hello(value)),
+            0x10 => Ok(Message::Stdin(value)),
+            0x11 => Ok(Message::Stdout(value)),
+            0x12 => Ok(Message::Stderr(value)),
+            0x13 => Ok(Message::Channel(value)),
+            0x20 => Ok(Message::Error(value)),
+            0x21 => Ok(Message::Warn(value)),
+            0x22 => Ok(Message::Info(value)),
+            0x23 => Ok(Message::Debug(value)),
+            0x24 => Ok(Message::Trace(value)),
+            0x25 => Ok(Message::Deferred(value)),
+            0x30 => Ok(Message::Val(value)),
+            0x31 => Ok(Message::Get(value)),
+            0x32 => Ok(Message::Set(value)),
+            0x50 => Ok(Message::Peek(value)),
+            0x51 => Ok(Message::Poke(value)),
+            0x52 => Ok(Message::Mem(value)),
+            0x60 => Ok(Message::Transfer(value)),
+            0x61 => Ok(Message::TransferAck(value)),
+            0x70 => Ok(Message::Call(value)),
+            0x71 => Ok(Message::Return(value)),
+            0x80 => Ok(Message::Test(value)),
+            0x90 => Ok(Message::Signal(value)),
+            0x91 => Ok(Message::Sample(value)),
+            0x41 => Ok(Message::Frame(to_byte(value)?)),
+            0x42 => Ok(Message::Ack(to_byte(value)?)),
+            0x43 => Ok(Message::Nak(to_byte(value)?)),
+            0x44 => Ok(Message::Fragment(value)),
+            0x45 => Ok(Message::Credit(value)),
+            0x46 => Ok(Message::Heartbeat(to_byte(value)?)),
+            0x48 => Ok(Message::Sync(to_byte(value)?)),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
 }
 
 pub struct Reader<'a> {
     buf: &'a [u8],
     len: usize,
     pos: usize,
+    seq: Option<u32>,
+    time: Option<u32>,
 }
 
 pub struct Writer<'a> {
     buf: &'a mut [u8],
     pos: usize,
+    fragment_id: u8,
 }
 
 impl<'a> Reader<'a> {
     pub fn new(buf: &'a [u8]) -> Self {
-        Reader { buf: buf, len: 0, pos: 0 }
+        Reader { buf: buf, len: 0, pos: 0, seq: None, time: None }
     }
 
     // pub fn decode(&mut self, src: &[u8]) -> Result<usize, Error> {
@@ -87,33 +313,57 @@ impl<'a> Reader<'a> {
     // }
 
     pub fn read<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<Message<'b>>, Error> {
+        match self.read_raw(buf)? {
+            Some((tag, value)) => Ok(Some(Message::decode(tag, value)?)),
+            None => Ok(None),
+        }
+    }
+
+    // Reads the tag and value of the next message without interpreting them.
+    pub(crate) fn read_raw<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<(u32, &'b [u8])>, Error> {
+        self.seq = None;
+        self.time = None;
+        loop {
+            let mut r = tlv::Reader::new(&self.buf[self.pos..]);
+            let tag = r.read_tag()?;
+            if tag != Some(Tag::Seq as u32) && tag != Some(Tag::Time as u32) {
+                break
+            }
+            if let Some(value) = r.read_lv8_ref()? {
+                let value = Some(record::to_u32(value)?);
+                if tag == Some(Tag::Seq as u32) {
+                    self.seq = value;
+                } else {
+                    self.time = value;
+                }
+                self.pos += r.pos();
+            } else {
+                return Ok(None)
+            }
+        }
         let mut r = tlv::Reader::new(&self.buf[self.pos..]);
-        if let Some((tag, value)) = r.read_tlv8(buf)? {
+        if let Some((tag, value)) = r.read_tlv8_ref()? {
             self.pos += r.pos();
-            match tag {
-                0x1 => Ok(Some(Message::Boot(value))),
-                0x2 => Ok(Some(Message::Run(value))),
-                0x3 => Ok(Some(Message::Exit(value[0]))),
-                0x4 => Ok(Some(Message::Exception(value))),
-                0x5 => Ok(Some(Message::Panic(value))),
-                0x10 => Ok(Some(Message::Stdin(value))),
-                0x11 => Ok(Some(Message::Stdout(value))),
-                0x12 => Ok(Some(Message::Stderr(value))),
-                0x20 => Ok(Some(Message::Error(value))),
-                0x21 => Ok(Some(Message::Warn(value))),
-                0x22 => Ok(Some(Message::Info(value))),
-                0x23 => Ok(Some(Message::Debug(value))),
-                0x24 => Ok(Some(Message::Trace(value))),
-                0x30 => Ok(Some(Message::Val(value))),
-                0x31 => Ok(Some(Message::Get(value))),
-                0x32 => Ok(Some(Message::Set(value))),
-                _ => unimplemented!(),
-            }
+            let buf = buf.get_mut(..value.len()).ok_or(Error::TlvError(tlv::Error::BufferTooShort))?;
+            buf.copy_from_slice(value);
+            Ok(Some((tag, buf)))
         } else {
             Ok(None)
         }
     }
 
+    /// Returns the sequence number attached to the last message read, if the
+    /// sender supplied one.
+    pub fn seq(&self) -> Option<u32> {
+        self.seq
+    }
+
+    /// Returns the device timestamp attached to the last message read, in
+    /// ticks of the rate declared in the device's `Hello`.
+    pub fn time(&self) -> Option<u32> {
+        self.time
+    }
+
     pub fn pos(&self) -> usize {
         self.pos
     }
@@ -129,7 +379,7 @@ impl<'a> Reader<'a> {
 
 impl<'a> Writer<'a> {
     pub fn new(buf: &'a mut [u8]) -> Self {
-        Writer { buf: buf, pos: 0 }
+        Writer { buf: buf, pos: 0, fragment_id: 0 }
     }
 
     pub fn encode<'b>(&mut self, dst: &'b mut [u8]) -> Result<&'b [u8], Error> {
@@ -141,13 +391,97 @@ impl<'a> Writer<'a> {
         Ok(&dst[..len])
     }
 
-    fn write_tlv(&mut self, tag: Tag, value: &[u8]) -> Result<usize, Error> {
+    /// Encodes the contents as a frame appended to `w`, e.g. to be sent
+    /// later with `cobs::Writer::poll_flush`, returning its length.
+    pub fn encode_into(&mut self, w: &mut cobs::Writer) -> Result<usize, Error> {
+        let len = w.encode_packet(&self.buf[..self.pos])?;
+        self.pos = 0;
+        Ok(len)
+    }
+
+    /// Returns the ID the next fragmented message will carry.
+    pub fn fragment_id(&self) -> u8 {
+        self.fragment_id
+    }
+
+    /// Sets the ID the next fragmented message will carry. A `Writer` made
+    /// for each frame should carry it over from the last one, so that
+    /// consecutive long messages don't share an ID.
+    pub fn set_fragment_id(&mut self, id: u8) {
+        self.fragment_id = id;
+    }
+
+    /// Discards everything written since the last `encode`.
+    pub fn clear(&mut self) {
+        self.pos = 0;
+    }
+
+    // Runs `f`, discarding what it wrote if it fails, so that a `Seq` is
+    // never left behind without the message it belongs to.
+    pub(crate) fn all_or_nothing<T, F: FnOnce(&mut Self) -> Result<T, Error>>(&mut self, f: F) -> Result<T, Error> {
+        let pos = self.pos;
+        f(self).inspect_err(|_| self.pos = pos)
+    }
+
+    pub(crate) fn write_tlv(&mut self, tag: Tag, value: &[u8]) -> Result<usize, Error> {
+        self.write_raw(tag as u32, value)
+    }
+
+    // Writes a message with any tag, fragmenting it if need be.
+    pub(crate) fn write_raw(&mut self, tag: u32, value: &[u8]) -> Result<usize, Error> {
+        if value.len() > 255 {
+            return self.write_fragments(tag, value)
+        }
         let mut tw = tlv::Writer::new(&mut self.buf[self.pos..]);
-        let len = tw.write_tlv8(tag as u32, value)?;
+        let len = tw.write_tlv8(tag, value)?;
+        self.pos += len;
+        Ok(len)
+    }
+
+    // Splits a value too long for a single message into `Fragment` messages.
+    // Nothing is written if the fragments don't all fit.
+    fn write_fragments(&mut self, tag: u32, value: &[u8]) -> Result<usize, Error> {
+        let count = value.len().div_ceil(fragment::FRAGMENT_SIZE);
+        if count > 255 {
+            return Err(Error::TlvError(tlv::Error::OutOfRange))
+        }
+        let (id, tag) = (self.fragment_id, tag as u8);
+        let len = {
+            let mut tw = tlv::Writer::new(&mut self.buf[self.pos..]);
+            for (index, chunk) in value.chunks(fragment::FRAGMENT_SIZE).enumerate() {
+                tw.write_tag(Tag::Fragment as u32)?;
+                tw.write_u8((fragment::HEADER_SIZE + chunk.len()) as u8)?;
+                tw.write(&[id, index as u8, count as u8, tag])?;
+                tw.write(chunk)?;
+            }
+            tw.pos()
+        };
+        self.fragment_id = self.fragment_id.wrapping_add(1);
         self.pos += len;
         Ok(len)
     }
 
+    fn write_leb128(&mut self, tag: Tag, value: u32) -> Result<usize, Error> {
+        let mut tmp = [0u8; 5];
+        let len = {
+            let mut w = leb128::Writer::new(&mut tmp);
+            w.write_u32(value)?;
+            w.pos()
+        };
+        self.write_tlv(tag, &tmp[..len])
+    }
+
+    /// Attaches a sequence number to the next message written.
+    pub fn seq(&mut self, value: u32) -> Result<usize, Error> {
+        self.write_leb128(Tag::Seq, value)
+    }
+
+    /// Attaches a device timestamp, usually `Clock::now`, to the next
+    /// message written.
+    pub fn time(&mut self, ticks: u32) -> Result<usize, Error> {
+        self.write_leb128(Tag::Time, ticks)
+    }
+
     pub fn boot(&mut self, value: &[u8]) -> Result<usize, Error> {
         self.write_tlv(Tag::Boot, value)
     }
@@ -164,6 +498,10 @@ impl<'a> Writer<'a> {
         self.write_tlv(Tag::Panic, value)
     }    
 
+    pub fn hello(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Hello, value)
+    }
+
     pub fn exit(&mut self, value: u8) -> Result<usize, Error> {        
         self.write_tlv(Tag::Exit, &[value])
     }    
@@ -180,6 +518,10 @@ impl<'a> Writer<'a> {
         self.write_tlv(Tag::Stderr, value)
     }
 
+    pub fn channel(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Channel, value)
+    }
+
     pub fn error(&mut self, value: &[u8]) -> Result<usize, Error> {
         self.write_tlv(Tag::Error, value)
     }  
@@ -200,6 +542,11 @@ impl<'a> Writer<'a> {
         self.write_tlv(Tag::Trace, value)
     }  
 
+    /// Writes a log message with deferred formatting, see `sctl_log!`.
+    pub fn deferred(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Deferred, value)
+    }
+
     pub fn val(&mut self, value: &[u8]) -> Result<usize, Error> {
         self.write_tlv(Tag::Val, value)
     }  
@@ -212,6 +559,69 @@ impl<'a> Writer<'a> {
         self.write_tlv(Tag::Set, value)
     }  
 
+    pub fn peek(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Peek, value)
+    }
+
+    pub fn poke(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Poke, value)
+    }
+
+    pub fn mem(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Mem, value)
+    }
+
+    pub fn transfer(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Transfer, value)
+    }
+
+    pub fn transfer_ack(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::TransferAck, value)
+    }
+
+    pub fn call(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Call, value)
+    }
+
+    /// Writes a `Return`, the answer to a `Call`.
+    pub fn ret(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Return, value)
+    }
+
+    pub fn test(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Test, value)
+    }
+
+    /// Writes a `Signal`, declaring a telemetry signal.
+    pub fn signal(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Signal, value)
+    }
+
+    /// Writes a `Sample`, one or more telemetry samples.
+    pub fn sample(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Sample, value)
+    }
+
+    pub fn ack(&mut self, seq: u8) -> Result<usize, Error> {
+        self.write_tlv(Tag::Ack, &[seq])
+    }
+
+    pub fn nak(&mut self, seq: u8) -> Result<usize, Error> {
+        self.write_tlv(Tag::Nak, &[seq])
+    }
+
+    pub fn sync(&mut self, seq: u8) -> Result<usize, Error> {
+        self.write_tlv(Tag::Sync, &[seq])
+    }
+
+    pub fn credit(&mut self, value: &[u8]) -> Result<usize, Error> {
+        self.write_tlv(Tag::Credit, value)
+    }
+
+    pub fn heartbeat(&mut self, count: u8) -> Result<usize, Error> {
+        self.write_tlv(Tag::Heartbeat, &[count])
+    }
+
 }
 
 impl<'a> AsRef<[u8]> for Writer<'a> {
@@ -263,4 +673,78 @@ mod tests {
         assert_eq!(r.read(&mut tmp[..]), Ok(Some(Message::Set(b"set"))));
         assert_eq!(r.read(&mut tmp[..]), Ok(Some(Message::Exit(0x55))));
     }
+
+    #[test]
+    fn test_seq() {
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+
+        w.seq(1).unwrap();
+        w.get(b"a").unwrap();
+        w.get(b"b").unwrap();
+        w.seq(300).unwrap();
+        w.val(b"c").unwrap();
+        w.seq(5).unwrap();
+
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 256];
+        assert_eq!(r.read(&mut tmp[..]), Ok(Some(Message::Get(b"a"))));
+        assert_eq!(r.seq(), Some(1));
+        assert_eq!(r.read(&mut tmp[..]), Ok(Some(Message::Get(b"b"))));
+        assert_eq!(r.seq(), None);
+        assert_eq!(r.read(&mut tmp[..]), Ok(Some(Message::Val(b"c"))));
+        assert_eq!(r.seq(), Some(300));
+        assert_eq!(r.read(&mut tmp[..]), Ok(None));
+    }
+
+    #[test]
+    fn test_time() {
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+
+        w.time(0xffff_ffff).unwrap();
+        w.info(b"a").unwrap();
+        w.warn(b"b").unwrap();
+        w.seq(7).unwrap();
+        w.time(1000).unwrap();
+        w.val(b"c").unwrap();
+
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 256];
+        assert_eq!(r.read(&mut tmp[..]), Ok(Some(Message::Info(b"a"))));
+        assert_eq!(r.time(), Some(0xffff_ffff));
+        assert_eq!(r.read(&mut tmp[..]), Ok(Some(Message::Warn(b"b"))));
+        assert_eq!(r.time(), None);
+        assert_eq!(r.read(&mut tmp[..]), Ok(Some(Message::Val(b"c"))));
+        assert_eq!((r.seq(), r.time()), (Some(7), Some(1000)));
+    }
+
+    #[test]
+    fn test_byte_values() {
+        for &tag in [Tag::Frame, Tag::Ack, Tag::Nak, Tag::Sync].iter() {
+            assert_eq!(Message::decode(tag as u32, b""), Err(Error::InvalidRecord));
+            assert_eq!(Message::decode(tag as u32, b"\x01\x02"), Err(Error::InvalidRecord));
+        }
+        assert_eq!(Message::decode(Tag::Ack as u32, b"\x07"), Ok(Message::Ack(7)));
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        assert_eq!(Message::decode(Tag::Exit as u32, b""), Err(Error::InvalidRecord));
+        assert_eq!(Message::decode(Tag::Exit as u32, b"\x01\x02"), Err(Error::InvalidRecord));
+        assert_eq!(Message::decode(Tag::Sealed as u32, b"\x01"), Err(Error::InvalidRecord));
+        assert_eq!(Message::decode(0x1234, b""), Err(Error::InvalidRecord));
+
+        // The reader reports unknown and malformed messages and moves on.
+        let buf = [0x7e, 0x01, 0x01, 0x03, 0x00, 0xa1, 0x24, 0x00, 0x11, 0x02, b'o', b'k', 0x11, 0x03, b'a', b'b', b'c'];
+        let mut r = Reader::new(&buf);
+        let mut tmp = [0u8; 2];
+        assert_eq!(r.read(&mut tmp), Err(Error::InvalidRecord));
+        assert_eq!(r.read(&mut tmp), Err(Error::InvalidRecord));
+        assert_eq!(r.read(&mut tmp), Err(Error::InvalidRecord));
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Stdout(b"ok"))));
+        // A value longer than the caller's buffer is an error, not a panic.
+        assert_eq!(r.read(&mut tmp), Err(Error::TlvError(tlv::Error::BufferTooShort)));
+        assert_eq!(r.read(&mut tmp), Ok(None));
+    }
 }
@@ -0,0 +1,437 @@
+//! Remote memory access on top of `Peek`, `Poke` and `Mem`.
+//!
+//! `Peek` carries an address and a length, `Poke` an address and the bytes
+//! to write. Either may give an access width of 2 or 4 bytes for peripheral
+//! registers that must be accessed a halfword or word at a time; the
+//! address and length must then be multiples of the width. Every request is
+//! answered with a `Mem` that echoes the address along with either the data
+//! read (nothing for a write) or a `Status`.
+//!
+//! `Handler` serves requests on the device and refuses any access that
+//! isn't entirely inside one of the regions it was configured with.
+
+use record::{self, RecordReader, RecordWriter};
+use {Error, Message, Writer};
+
+/// The largest number of bytes a single `Peek` or `Poke` may transfer.
+pub const MAX_LEN: usize = 240;
+
+enum Field {
+    Addr = 0x1,
+    Len = 0x2,
+    Data = 0x3,
+    Width = 0x4,
+    Status = 0x5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Width {
+    U8 = 1,
+    U16 = 2,
+    U32 = 4,
+}
+
+impl Width {
+    fn from_u32(value: u32) -> Result<Width, Error> {
+        match value {
+            1 => Ok(Width::U8),
+            2 => Ok(Width::U16),
+            4 => Ok(Width::U32),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Denied = 0x1,
+    Unaligned = 0x2,
+    TooLong = 0x3,
+    Fault = 0x4,
+}
+
+impl Status {
+    fn from_u32(value: u32) -> Result<Status, Error> {
+        match value {
+            0x1 => Ok(Status::Denied),
+            0x2 => Ok(Status::Unaligned),
+            0x3 => Ok(Status::TooLong),
+            0x4 => Ok(Status::Fault),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+/// Memory as seen by `Handler`. Accesses have already been checked against
+/// the allowed regions and for alignment.
+pub trait Memory {
+    fn read(&mut self, addr: u32, width: Width, buf: &mut [u8]) -> Result<(), Status>;
+    fn write(&mut self, addr: u32, width: Width, data: &[u8]) -> Result<(), Status>;
+}
+
+/// The device's own address space, accessed with volatile reads and
+/// writes of the requested width.
+pub struct Raw(());
+
+impl Raw {
+    /// # Safety
+    ///
+    /// The regions given to `Handler` must only cover addresses that can be
+    /// read, and where allowed written, at any time without breaking the
+    /// running program.
+    pub unsafe fn new() -> Raw {
+        Raw(())
+    }
+}
+
+impl Memory for Raw {
+    fn read(&mut self, addr: u32, width: Width, buf: &mut [u8]) -> Result<(), Status> {
+        use core::ptr::read_volatile;
+        let n = width as usize;
+        for (i, chunk) in buf.chunks_mut(n).enumerate() {
+            let p = addr as usize + i * n;
+            unsafe {
+                match width {
+                    Width::U8 => chunk[0] = read_volatile(p as *const u8),
+                    Width::U16 => chunk.copy_from_slice(&read_volatile(p as *const u16).to_ne_bytes()),
+                    Width::U32 => chunk.copy_from_slice(&read_volatile(p as *const u32).to_ne_bytes()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, width: Width, data: &[u8]) -> Result<(), Status> {
+        use core::ptr::write_volatile;
+        let n = width as usize;
+        for (i, chunk) in data.chunks(n).enumerate() {
+            let p = addr as usize + i * n;
+            unsafe {
+                match width {
+                    Width::U8 => write_volatile(p as *mut u8, chunk[0]),
+                    Width::U16 => write_volatile(p as *mut u16, u16::from_ne_bytes([chunk[0], chunk[1]])),
+                    Width::U32 => write_volatile(p as *mut u32, u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    ReadWrite,
+}
+
+/// A range of addresses the host may access.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub start: u32,
+    pub len: u32,
+    pub access: Access,
+}
+
+impl Region {
+    pub const fn new(start: u32, len: u32, access: Access) -> Region {
+        Region { start, len, access }
+    }
+
+    fn contains(&self, addr: u32, len: u32) -> bool {
+        addr >= self.start && (addr - self.start) as u64 + len as u64 <= self.len as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request<'a> {
+    Peek { addr: u32, len: u32, width: Width },
+    Poke { addr: u32, data: &'a [u8], width: Width },
+}
+
+impl<'a> Request<'a> {
+    pub fn addr(&self) -> u32 {
+        match *self {
+            Request::Peek { addr, .. } | Request::Poke { addr, .. } => addr,
+        }
+    }
+
+    pub fn write(&self, w: &mut Writer) -> Result<usize, Error> {
+        let mut buf = [0u8; 255];
+        let len = {
+            let mut rw = RecordWriter::new(&mut buf);
+            rw.write_u32(Field::Addr as u32, self.addr())?;
+            let width = match *self {
+                Request::Peek { len, width, .. } => {
+                    rw.write_u32(Field::Len as u32, len)?;
+                    width
+                }
+                Request::Poke { data, width, .. } => {
+                    rw.write_bytes(Field::Data as u32, data)?;
+                    width
+                }
+            };
+            if width != Width::U8 {
+                rw.write_u32(Field::Width as u32, width as u32)?;
+            }
+            rw.pos()
+        };
+        match *self {
+            Request::Peek { .. } => w.peek(&buf[..len]),
+            Request::Poke { .. } => w.poke(&buf[..len]),
+        }
+    }
+
+    /// Decodes a `Peek` or `Poke` message, returning `None` for any other
+    /// message.
+    pub fn from_message(msg: &Message<'a>) -> Result<Option<Self>, Error> {
+        let (buf, peek) = match *msg {
+            Message::Peek(buf) => (buf, true),
+            Message::Poke(buf) => (buf, false),
+            _ => return Ok(None),
+        };
+        let f = read_record(buf)?;
+        let width = match f.width {
+            Some(width) => Width::from_u32(width)?,
+            None => Width::U8,
+        };
+        match (f.addr, f.len, f.data, peek) {
+            (Some(addr), Some(len), None, true) => Ok(Some(Request::Peek { addr, len, width })),
+            (Some(addr), None, Some(data), false) => Ok(Some(Request::Poke { addr, data, width })),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Response<'a> {
+    pub addr: u32,
+    pub result: Result<&'a [u8], Status>,
+}
+
+impl<'a> Response<'a> {
+    pub fn matches(&self, req: &Request) -> bool {
+        self.addr == req.addr()
+    }
+
+    pub fn write(&self, w: &mut Writer) -> Result<usize, Error> {
+        let mut buf = [0u8; 255];
+        let len = {
+            let mut rw = RecordWriter::new(&mut buf);
+            rw.write_u32(Field::Addr as u32, self.addr)?;
+            match self.result {
+                Ok(data) => rw.write_bytes(Field::Data as u32, data)?,
+                Err(status) => rw.write_u32(Field::Status as u32, status as u32)?,
+            };
+            rw.pos()
+        };
+        w.mem(&buf[..len])
+    }
+
+    /// Decodes a `Mem` message, returning `None` for any other message.
+    pub fn from_message(msg: &Message<'a>) -> Result<Option<Self>, Error> {
+        let buf = match *msg {
+            Message::Mem(buf) => buf,
+            _ => return Ok(None),
+        };
+        let f = read_record(buf)?;
+        match (f.addr, f.data, f.status) {
+            (Some(addr), Some(data), None) => Ok(Some(Response { addr, result: Ok(data) })),
+            (Some(addr), None, Some(status)) => Ok(Some(Response { addr, result: Err(Status::from_u32(status)?) })),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Fields<'a> {
+    addr: Option<u32>,
+    len: Option<u32>,
+    data: Option<&'a [u8]>,
+    width: Option<u32>,
+    status: Option<u32>,
+}
+
+fn read_record(buf: &[u8]) -> Result<Fields<'_>, Error> {
+    let mut f = Fields::default();
+    let mut r = RecordReader::new(buf);
+    while let Some((tag, value)) = r.read()? {
+        match tag {
+            0x1 => f.addr = Some(record::to_u32(value)?),
+            0x2 => f.len = Some(record::to_u32(value)?),
+            0x3 => f.data = Some(value),
+            0x4 => f.width = Some(record::to_u32(value)?),
+            0x5 => f.status = Some(record::to_u32(value)?),
+            _ => {}
+        }
+    }
+    Ok(f)
+}
+
+/// Device side of the memory protocol.
+pub struct Handler<'a, M: Memory> {
+    memory: M,
+    regions: &'a [Region],
+}
+
+impl<'a, M: Memory> Handler<'a, M> {
+    pub fn new(memory: M, regions: &'a [Region]) -> Self {
+        Handler { memory, regions }
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    fn check(&self, addr: u32, len: usize, width: Width, write: bool) -> Result<(), Status> {
+        if len > MAX_LEN {
+            return Err(Status::TooLong)
+        }
+        let n = width as u32;
+        if !addr.is_multiple_of(n) || !(len as u32).is_multiple_of(n) {
+            return Err(Status::Unaligned)
+        }
+        let allowed = self.regions.iter().any(|region| {
+            region.contains(addr, len as u32) && (!write || region.access == Access::ReadWrite)
+        });
+        if allowed { Ok(()) } else { Err(Status::Denied) }
+    }
+
+    /// Answers a `Peek` or `Poke` message, writing the `Mem` response to
+    /// `w`. `seq` is the sequence number attached to the request and is
+    /// echoed on the response. Returns `false` if `msg` is not a memory
+    /// request.
+    pub fn serve(&mut self, msg: &Message, seq: Option<u32>, w: &mut Writer) -> Result<bool, Error> {
+        let req = match Request::from_message(msg)? {
+            Some(req) => req,
+            None => return Ok(false),
+        };
+        let mut buf = [0u8; MAX_LEN];
+        let result = match req {
+            Request::Peek { addr, len, width } => {
+                let len = len as usize;
+                self.check(addr, len, width, false)
+                    .and_then(|_| self.memory.read(addr, width, &mut buf[..len]))
+                    .map(|_| &buf[..len])
+            }
+            Request::Poke { addr, data, width } => {
+                self.check(addr, data.len(), width, true)
+                    .and_then(|_| self.memory.write(addr, width, data))
+                    .map(|_| &buf[..0])
+            }
+        };
+        w.all_or_nothing(|w| {
+            if let Some(seq) = seq {
+                w.seq(seq)?;
+            }
+            Response { addr: req.addr(), result }.write(w)
+        })?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use Reader;
+
+    const BASE: u32 = 0x2000_0000;
+
+    // 64 bytes of simulated memory at BASE, recording the width of the last
+    // access.
+    struct Sim {
+        data: [u8; 64],
+        width: Option<Width>,
+    }
+
+    impl Memory for Sim {
+        fn read(&mut self, addr: u32, width: Width, buf: &mut [u8]) -> Result<(), Status> {
+            let start = (addr - BASE) as usize;
+            buf.copy_from_slice(&self.data[start..start + buf.len()]);
+            self.width = Some(width);
+            Ok(())
+        }
+
+        fn write(&mut self, addr: u32, width: Width, data: &[u8]) -> Result<(), Status> {
+            let start = (addr - BASE) as usize;
+            self.data[start..start + data.len()].copy_from_slice(data);
+            self.width = Some(width);
+            Ok(())
+        }
+    }
+
+    const REGIONS: [Region; 2] = [
+        Region::new(BASE, 32, Access::ReadWrite),
+        Region::new(BASE + 32, 32, Access::Read),
+    ];
+
+    // Sends `req` to `handler` and returns the decoded result along with the
+    // sequence number echoed on the response.
+    fn roundtrip(handler: &mut Handler<Sim>, req: Request) -> (Result<Vec<u8>, Status>, Option<u32>) {
+        let mut wbuf = [0u8; 512];
+        let mut w = Writer::new(&mut wbuf);
+        w.seq(9).unwrap();
+        req.write(&mut w).unwrap();
+        let mut rbuf = [0u8; 512];
+        let mut resp = Writer::new(&mut rbuf);
+        let mut tmp = [0u8; 256];
+        let mut r = Reader::new(w.as_ref());
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        assert_eq!(Request::from_message(&msg), Ok(Some(req)));
+        assert!(handler.serve(&msg, r.seq(), &mut resp).unwrap());
+
+        let mut r = Reader::new(resp.as_ref());
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        let resp = Response::from_message(&msg).unwrap().unwrap();
+        assert!(resp.matches(&req));
+        (resp.result.map(|data| data.to_vec()), r.seq())
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let mut sim = Sim { data: [0; 64], width: None };
+        for (i, b) in sim.data.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let mut handler = Handler::new(sim, &REGIONS);
+
+        let peek = Request::Peek { addr: BASE + 4, len: 4, width: Width::U8 };
+        assert_eq!(roundtrip(&mut handler, peek), (Ok(vec![4, 5, 6, 7]), Some(9)));
+        assert_eq!(handler.memory().width, Some(Width::U8));
+
+        let poke = Request::Poke { addr: BASE + 8, data: &[0xaa, 0xbb, 0xcc, 0xdd], width: Width::U32 };
+        assert_eq!(roundtrip(&mut handler, poke).0, Ok(vec![]));
+        assert_eq!(&handler.memory().data[8..12], &[0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(handler.memory().width, Some(Width::U32));
+
+        // Reads may span adjacent regions only if one region covers them.
+        let peek = Request::Peek { addr: BASE + 40, len: 24, width: Width::U16 };
+        assert_eq!(roundtrip(&mut handler, peek).0, Ok((40..64).collect()));
+        let peek = Request::Peek { addr: BASE + 28, len: 8, width: Width::U8 };
+        assert_eq!(roundtrip(&mut handler, peek).0, Err(Status::Denied));
+    }
+
+    #[test]
+    fn test_denied() {
+        let mut handler = Handler::new(Sim { data: [0; 64], width: None }, &REGIONS);
+
+        let cases = [
+            (Request::Poke { addr: BASE + 32, data: &[1], width: Width::U8 }, Status::Denied),
+            (Request::Peek { addr: BASE + 60, len: 8, width: Width::U8 }, Status::Denied),
+            (Request::Peek { addr: BASE - 4, len: 4, width: Width::U8 }, Status::Denied),
+            (Request::Peek { addr: 0xffff_fffc, len: 8, width: Width::U8 }, Status::Denied),
+            (Request::Peek { addr: BASE + 2, len: 4, width: Width::U32 }, Status::Unaligned),
+            (Request::Poke { addr: BASE, data: &[1, 2, 3], width: Width::U16 }, Status::Unaligned),
+            (Request::Peek { addr: BASE, len: MAX_LEN as u32 + 4, width: Width::U8 }, Status::TooLong),
+        ];
+        for &(req, status) in cases.iter() {
+            assert_eq!(roundtrip(&mut handler, req).0, Err(status), "{:?}", req);
+        }
+        assert_eq!(handler.memory().width, None);
+        assert_eq!(&handler.memory().data[..], &[0u8; 64][..]);
+    }
+}
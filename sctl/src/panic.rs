@@ -0,0 +1,246 @@
+//! Panic reporting through sctl.
+//!
+//! `PanicRecord` is the structured payload of `Message::Panic`. On the
+//! device, enabling the `panic-handler` feature installs a `#[panic_handler]`
+//! that encodes the record, appends `Exit(EXIT_CODE)` and hands the COBS
+//! frame to the sink registered with `set_sink`. The feature is for
+//! `no_std` target builds; it does nothing alongside `std`.
+
+use core::fmt;
+use core::panic::PanicInfo;
+use core::str;
+
+use record::{self, RecordReader, RecordWriter};
+use {Error, Writer};
+
+/// Exit code sent after a panic record, matching the code used by `std`.
+pub const EXIT_CODE: u8 = 101;
+
+/// Longest formatted panic message kept by `report`.
+pub const MAX_MESSAGE: usize = 160;
+
+/// Longest file path kept by `report`; longer paths keep their tail.
+pub const MAX_FILE: usize = 64;
+
+enum Field {
+    Message = 0x1,
+    File = 0x2,
+    Line = 0x3,
+    Column = 0x4,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PanicRecord<'a> {
+    pub message: &'a str,
+    pub file: &'a str,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl<'a> PanicRecord<'a> {
+    pub fn encode<'b>(&self, buf: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let len = {
+            let mut w = RecordWriter::new(buf);
+            w.write_str(Field::Message as u32, self.message)?;
+            w.write_str(Field::File as u32, self.file)?;
+            w.write_u32(Field::Line as u32, self.line)?;
+            w.write_u32(Field::Column as u32, self.column)?;
+            w.pos()
+        };
+        Ok(&buf[..len])
+    }
+
+    pub fn decode(buf: &'a [u8]) -> Result<Self, Error> {
+        let mut rec = PanicRecord { message: "", file: "", line: 0, column: 0 };
+        let mut r = RecordReader::new(buf);
+        while let Some((tag, value)) = r.read()? {
+            match tag {
+                0x1 => rec.message = record::to_str(value)?,
+                0x2 => rec.file = record::to_str(value)?,
+                0x3 => rec.line = record::to_u32(value)?,
+                0x4 => rec.column = record::to_u32(value)?,
+                _ => {}
+            }
+        }
+        Ok(rec)
+    }
+}
+
+impl<'a> fmt::Display for PanicRecord<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "panicked at {}:{}:{}:\n{}", self.file, self.line, self.column, self.message)
+    }
+}
+
+static mut SINK: Option<fn(&[u8])> = None;
+
+/// Registers the function that receives encoded frames from `report`.
+///
+/// # Safety
+///
+/// Must not race with a panic on another core or in an interrupt handler.
+pub unsafe fn set_sink(sink: fn(&[u8])) {
+    SINK = Some(sink);
+}
+
+/// Encodes `info` as a `Panic` message followed by `Exit(EXIT_CODE)` and
+/// passes the COBS frame to the registered sink. Does nothing if no sink has
+/// been registered.
+pub fn report(info: &PanicInfo) {
+    let sink = match unsafe { SINK } {
+        Some(sink) => sink,
+        None => return,
+    };
+
+    let mut mbuf = [0u8; MAX_MESSAGE];
+    let message = {
+        let mut t = Truncate::new(&mut mbuf);
+        let _ = fmt::write(&mut t, format_args!("{}", info.message()));
+        t.len()
+    };
+    let message = str::from_utf8(&mbuf[..message]).unwrap_or("");
+    let (file, line, column) = match info.location() {
+        Some(loc) => (tail(loc.file(), MAX_FILE), loc.line(), loc.column()),
+        None => ("", 0, 0),
+    };
+    let rec = PanicRecord { message, file, line, column };
+
+    let mut rbuf = [0u8; 255];
+    let mut wbuf = [0u8; 264];
+    let mut out = [0u8; 272];
+    let mut w = Writer::new(&mut wbuf);
+    if let Ok(value) = rec.encode(&mut rbuf) {
+        let _ = w.panic(value);
+    }
+    let _ = w.exit(EXIT_CODE);
+    if let Ok(frame) = w.encode(&mut out) {
+        sink(frame);
+    }
+}
+
+// With std, std's own handler is used and this one would clash with it.
+#[cfg(all(feature = "panic-handler", not(feature = "std"), not(test)))]
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    report(info);
+    loop {}
+}
+
+// Keeps the last `max` bytes of `s`, starting on a character boundary.
+fn tail(s: &str, max: usize) -> &str {
+    if s.len() <= max {
+        return s
+    }
+    let mut start = s.len() - max;
+    while !s.is_char_boundary(start) {
+        start += 1;
+    }
+    &s[start..]
+}
+
+// fmt::Write sink that silently drops whatever doesn't fit, without
+// splitting a character.
+struct Truncate<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Truncate<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Truncate { buf, pos: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<'a> fmt::Write for Truncate<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut len = s.len().min(self.buf.len() - self.pos);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.buf[self.pos..self.pos + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.pos += len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+    use {Message, Reader};
+
+    #[test]
+    fn test_panic_record() {
+        let rec = PanicRecord {
+            message: "index out of bounds: the len is 3 but the index is 7",
+            file: "src/main.rs",
+            line: 42,
+            column: 300,
+        };
+        let mut buf = [0u8; 255];
+        let value = rec.encode(&mut buf).unwrap();
+        assert_eq!(PanicRecord::decode(value), Ok(rec));
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let rec = PanicRecord { message: "boom", file: "src/lib.rs", line: 7, column: 5 };
+        let mut rbuf = [0u8; 255];
+        let mut wbuf = [0u8; 264];
+        let mut w = Writer::new(&mut wbuf);
+        w.panic(rec.encode(&mut rbuf).unwrap()).unwrap();
+        w.exit(EXIT_CODE).unwrap();
+
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 256];
+        match r.read(&mut tmp).unwrap() {
+            Some(Message::Panic(value)) => {
+                let rec = PanicRecord::decode(value).unwrap();
+                assert_eq!(rec.to_string(), "panicked at src/lib.rs:7:5:\nboom");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(r.read(&mut tmp), Ok(Some(Message::Exit(EXIT_CODE))));
+    }
+
+    #[test]
+    fn test_panic_decode_skips_unknown() {
+        let mut buf = [0u8; 64];
+        let len = {
+            let mut w = RecordWriter::new(&mut buf);
+            w.write_str(0x1, "oops").unwrap();
+            w.write_u32(0x7f, 1234).unwrap();
+            w.write_u32(0x3, 9).unwrap();
+            w.pos()
+        };
+        let rec = PanicRecord::decode(&buf[..len]).unwrap();
+        assert_eq!(rec, PanicRecord { message: "oops", file: "", line: 9, column: 0 });
+        assert_eq!(PanicRecord::decode(&buf[..len - 1]), Err(Error::InvalidRecord));
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut buf = [0u8; 8];
+        let len = {
+            let mut t = Truncate::new(&mut buf);
+            fmt::write(&mut t, format_args!("{}-{}", "ab", "çdéf")).unwrap();
+            t.len()
+        };
+        // "ab-çdé" is 8 bytes; the trailing 'f' is dropped.
+        assert_eq!(str::from_utf8(&buf[..len]), Ok("ab-çdé"));
+
+        let mut buf = [0u8; 4];
+        let len = {
+            let mut t = Truncate::new(&mut buf);
+            fmt::write(&mut t, format_args!("abcé")).unwrap();
+            t.len()
+        };
+        assert_eq!(str::from_utf8(&buf[..len]), Ok("abc"));
+        assert_eq!(tail("/home/user/src/main.rs", 11), "src/main.rs");
+        assert_eq!(tail("é/a.rs", 5), "/a.rs");
+    }
+}
@@ -0,0 +1,132 @@
+//! Requests in flight for the `kv` and `rpc` clients.
+//!
+//! Each request occupies one of a caller-supplied set of slots from when it
+//! is issued until its response arrives or `timeout` ticks pass. Responses
+//! are paired with requests by the sequence number echoed on them. Peers
+//! that don't echo sequence numbers answer in order, so an un-numbered
+//! response completes the oldest request in flight.
+
+use {Clock, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pending {
+    seq: u32,
+    sent: u32,
+}
+
+pub(crate) struct Tracker<'a, C: Clock> {
+    clock: C,
+    timeout: u32,
+    next_seq: u32,
+    slots: &'a mut [Option<Pending>],
+}
+
+impl<'a, C: Clock> Tracker<'a, C> {
+    pub fn new(clock: C, timeout: u32, slots: &'a mut [Option<Pending>]) -> Self {
+        for slot in slots.iter_mut() {
+            *slot = None;
+        }
+        Tracker { clock, timeout, next_seq: 1, slots }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Issues a request, passing its sequence number to `write`, and returns
+    /// the sequence number. Fails with `Error::Busy`, without calling
+    /// `write`, if every slot is in use; nothing is tracked if `write`
+    /// fails.
+    pub fn issue<F: FnOnce(u32) -> Result<(), Error>>(&mut self, write: F) -> Result<u32, Error> {
+        let index = match self.slots.iter().position(|slot| slot.is_none()) {
+            Some(index) => index,
+            None => return Err(Error::Busy),
+        };
+        let seq = self.next_seq;
+        write(seq)?;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.slots[index] = Some(Pending { seq, sent: self.clock.now() });
+        Ok(seq)
+    }
+
+    /// Releases the request answered by a response carrying `seq`, or the
+    /// oldest one without it, and returns its sequence number.
+    pub fn complete(&mut self, seq: Option<u32>) -> Option<u32> {
+        let next_seq = self.next_seq;
+        let index = match seq {
+            Some(seq) => self.slots.iter().position(|slot| matches!(*slot, Some(p) if p.seq == seq)),
+            // The oldest request is the one issued furthest before the next,
+            // which stays true when requests share a tick.
+            None => self.slots.iter().enumerate()
+                .filter_map(|(i, slot)| slot.map(|p| (i, next_seq.wrapping_sub(p.seq))))
+                .max_by_key(|&(_, behind)| behind)
+                .map(|(i, _)| i),
+        };
+        index.and_then(|i| self.slots[i].take()).map(|p| p.seq)
+    }
+
+    /// Releases one request that has been waiting for `timeout` ticks or
+    /// more and returns its sequence number.
+    pub fn expire(&mut self) -> Option<u32> {
+        let now = self.clock.now();
+        let timeout = self.timeout;
+        for slot in self.slots.iter_mut() {
+            if let Some(p) = *slot {
+                if now.wrapping_sub(p.sent) >= timeout {
+                    *slot = None;
+                    return Some(p.seq)
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    #[test]
+    fn test_order() {
+        let clock = Cell::new(5);
+        let mut slots = [None; 3];
+        let mut t = Tracker::new(&clock, 10, &mut slots);
+
+        // Requests issued in the same tick complete in the order issued.
+        let s1 = t.issue(|_| Ok(())).unwrap();
+        let s2 = t.issue(|_| Ok(())).unwrap();
+        assert_eq!(t.issue(|_| Err(Error::WouldBlock)), Err(Error::WouldBlock));
+        let s3 = t.issue(|_| Ok(())).unwrap();
+        assert_eq!(t.issue(|_| panic!("no free slot")), Err(Error::Busy));
+        assert_eq!((s1, s2, s3), (1, 2, 3));
+        assert_eq!(t.complete(Some(s2)), Some(s2));
+        assert_eq!(t.complete(None), Some(s1));
+        let s4 = t.issue(|_| Ok(())).unwrap();
+        assert_eq!(t.complete(None), Some(s3));
+        assert_eq!(t.complete(None), Some(s4));
+        assert_eq!(t.complete(None), None);
+        assert_eq!(t.complete(Some(s1)), None);
+        assert_eq!(t.in_flight(), 0);
+    }
+
+    #[test]
+    fn test_order_across_wrap() {
+        let clock = Cell::new(0);
+        let mut slots = [None; 2];
+        let mut t = Tracker::new(&clock, 10, &mut slots);
+        t.next_seq = u32::MAX;
+        let s1 = t.issue(|_| Ok(())).unwrap();
+        let s2 = t.issue(|_| Ok(())).unwrap();
+        assert_eq!((s1, s2), (u32::MAX, 0));
+        assert_eq!(t.complete(None), Some(s1));
+        assert_eq!(t.complete(None), Some(s2));
+
+        let s3 = t.issue(|_| Ok(())).unwrap();
+        clock.set(9);
+        assert_eq!(t.expire(), None);
+        clock.set(10);
+        assert_eq!(t.expire(), Some(s3));
+        assert_eq!(t.expire(), None);
+    }
+}
@@ -0,0 +1,116 @@
+//! Nested TLV records carried inside the value of a single sctl message.
+//!
+//! Every field is a tlv8 entry. Integers are LEB128 encoded so that small
+//! values stay small on the wire. Readers skip fields they don't recognize.
+
+use core::str;
+
+use leb128;
+use tlv;
+
+use Error;
+
+pub struct RecordWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> RecordWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        RecordWriter { buf, pos: 0 }
+    }
+
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub fn write_bytes(&mut self, tag: u32, value: &[u8]) -> Result<usize, Error> {
+        let mut tw = tlv::Writer::new(&mut self.buf[self.pos..]);
+        let len = tw.write_tlv8(tag, value)?;
+        self.pos += len;
+        Ok(len)
+    }
+
+    pub fn write_str(&mut self, tag: u32, value: &str) -> Result<usize, Error> {
+        self.write_bytes(tag, value.as_bytes())
+    }
+
+    pub fn write_u32(&mut self, tag: u32, value: u32) -> Result<usize, Error> {
+        let mut tmp = [0u8; 5];
+        let len = {
+            let mut w = leb128::Writer::new(&mut tmp);
+            w.write_u32(value)?;
+            w.pos()
+        };
+        self.write_bytes(tag, &tmp[..len])
+    }
+
+    pub fn write_i32(&mut self, tag: u32, value: i32) -> Result<usize, Error> {
+        let mut tmp = [0u8; 5];
+        let len = {
+            let mut w = leb128::Writer::new(&mut tmp);
+            w.write_i32(value)?;
+            w.pos()
+        };
+        self.write_bytes(tag, &tmp[..len])
+    }
+
+    pub fn write_bool(&mut self, tag: u32, value: bool) -> Result<usize, Error> {
+        self.write_bytes(tag, &[value as u8])
+    }
+}
+
+impl<'a> AsRef<[u8]> for RecordWriter<'a> {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf[..self.pos]
+    }
+}
+
+pub struct RecordReader<'a> {
+    r: tlv::Reader<'a>,
+}
+
+impl<'a> RecordReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        RecordReader { r: tlv::Reader::new(buf) }
+    }
+
+    /// Returns the next field, or `None` once the record is exhausted.
+    pub fn read(&mut self) -> Result<Option<(u32, &'a [u8])>, Error> {
+        if self.r.remaining() == 0 {
+            return Ok(None)
+        }
+        match self.r.read_tlv8_ref()? {
+            Some(field) => Ok(Some(field)),
+            None => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+pub fn to_u32(value: &[u8]) -> Result<u32, Error> {
+    let mut r = leb128::Reader::new(value);
+    match r.read_u32()? {
+        Some(v) if r.remaining() == 0 => Ok(v),
+        _ => Err(Error::InvalidRecord),
+    }
+}
+
+pub fn to_i32(value: &[u8]) -> Result<i32, Error> {
+    let mut r = leb128::Reader::new(value);
+    match r.read_i32()? {
+        Some(v) if r.remaining() == 0 => Ok(v),
+        _ => Err(Error::InvalidRecord),
+    }
+}
+
+pub fn to_bool(value: &[u8]) -> Result<bool, Error> {
+    match *value {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        _ => Err(Error::InvalidRecord),
+    }
+}
+
+pub fn to_str(value: &[u8]) -> Result<&str, Error> {
+    str::from_utf8(value).map_err(|_| Error::InvalidRecord)
+}
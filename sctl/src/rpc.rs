@@ -0,0 +1,497 @@
+//! Remote procedure calls on top of `Call` and `Return`.
+//!
+//! A `Call` carries a method ID followed by the arguments, and the device
+//! answers with a `Return` carrying either the results or an error `Code`.
+//! Arguments and results are encoded like `kv` values, one field per value,
+//! in order.
+//!
+//! The device serves calls from a static table of `Method`s. Handlers read
+//! their arguments from `Args` and write their results to `Values`; neither
+//! allocates. The host side is `Client`, which pairs returns with calls by
+//! sequence number so that several calls can be in flight at once.
+
+use kv::{self, Value};
+use pending::Tracker;
+use record::{self, RecordWriter};
+use {tlv, Clock, Error, Message, Writer};
+
+pub use pending::Pending;
+
+enum Field {
+    Method = 0x1,
+    Error = 0x2,
+}
+
+/// Why a call failed on the device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Code {
+    UnknownMethod,
+    BadArgs,
+    TooLarge,
+    /// An error defined by the method itself.
+    App(u16),
+}
+
+impl Code {
+    fn to_u32(self) -> u32 {
+        match self {
+            Code::UnknownMethod => 0x1,
+            Code::BadArgs => 0x2,
+            Code::TooLarge => 0x3,
+            Code::App(code) => 0x100 + code as u32,
+        }
+    }
+
+    fn from_u32(value: u32) -> Result<Code, Error> {
+        match value {
+            0x1 => Ok(Code::UnknownMethod),
+            0x2 => Ok(Code::BadArgs),
+            0x3 => Ok(Code::TooLarge),
+            0x100..=0x100ff => Ok(Code::App((value - 0x100) as u16)),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+/// Reads the arguments of a call, or the results of a return, in order.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Args<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Args<'a> {
+    /// Returns the next value; fails with `Code::BadArgs` if there is none.
+    pub fn value(&mut self) -> Result<Value<'a>, Code> {
+        while !self.buf.is_empty() {
+            let mut r = tlv::Reader::new(self.buf);
+            let (tag, v) = match r.read_tlv8_ref() {
+                Ok(Some(field)) => field,
+                _ => return Err(Code::BadArgs),
+            };
+            self.buf = &self.buf[r.pos()..];
+            match kv::read_value(tag, v) {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) => {}
+                Err(_) => return Err(Code::BadArgs),
+            }
+        }
+        Err(Code::BadArgs)
+    }
+
+    pub fn int(&mut self) -> Result<i32, Code> {
+        match self.value()? {
+            Value::Int(v) => Ok(v),
+            _ => Err(Code::BadArgs),
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Code> {
+        match self.value()? {
+            Value::Bool(v) => Ok(v),
+            _ => Err(Code::BadArgs),
+        }
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, Code> {
+        match self.value()? {
+            Value::Str(v) => Ok(v),
+            _ => Err(Code::BadArgs),
+        }
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], Code> {
+        match self.value()? {
+            Value::Bytes(v) => Ok(v),
+            _ => Err(Code::BadArgs),
+        }
+    }
+
+    /// Fails with `Code::BadArgs` if there are values left.
+    pub fn end(&mut self) -> Result<(), Code> {
+        match self.value() {
+            Ok(_) => Err(Code::BadArgs),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Reads all of the remaining values as `P`.
+    pub fn take<P: Param<'a>>(&mut self) -> Result<P, Code> {
+        let p = P::read(self)?;
+        self.end()?;
+        Ok(p)
+    }
+}
+
+/// Writes the arguments of a call, or the results of a return.
+pub struct Values<'a> {
+    rw: RecordWriter<'a>,
+}
+
+impl<'a> Values<'a> {
+    fn new(buf: &'a mut [u8]) -> Self {
+        Values { rw: RecordWriter::new(buf) }
+    }
+
+    /// Fails with `Code::TooLarge` if the value doesn't fit in the message.
+    pub fn push(&mut self, value: &Value) -> Result<(), Code> {
+        kv::write_value(&mut self.rw, value).map(|_| ()).map_err(|_| Code::TooLarge)
+    }
+}
+
+/// A type that can be passed as arguments or returned as results.
+pub trait Param<'a>: Sized {
+    fn write(&self, values: &mut Values) -> Result<(), Code>;
+    fn read(args: &mut Args<'a>) -> Result<Self, Code>;
+}
+
+macro_rules! param {
+    ($t:ty, $variant:ident, $read:ident) => {
+        impl<'a> Param<'a> for $t {
+            fn write(&self, values: &mut Values) -> Result<(), Code> {
+                values.push(&Value::$variant(*self))
+            }
+
+            fn read(args: &mut Args<'a>) -> Result<Self, Code> {
+                args.$read()
+            }
+        }
+    };
+}
+
+param!(i32, Int, int);
+param!(bool, Bool, bool);
+param!(&'a str, Str, str);
+param!(&'a [u8], Bytes, bytes);
+
+macro_rules! tuple {
+    ($($name:ident),*) => {
+        impl<'a, $($name: Param<'a>),*> Param<'a> for ($($name,)*) {
+            #[allow(non_snake_case, unused_variables)]
+            fn write(&self, values: &mut Values) -> Result<(), Code> {
+                let ($(ref $name,)*) = *self;
+                $($name.write(values)?;)*
+                Ok(())
+            }
+
+            #[allow(unused_variables)]
+            fn read(args: &mut Args<'a>) -> Result<Self, Code> {
+                Ok(($($name::read(args)?,)*))
+            }
+        }
+    };
+}
+
+tuple!();
+tuple!(A);
+tuple!(A, B);
+tuple!(A, B, C);
+tuple!(A, B, C, D);
+
+/// Handles a call with the device state `T`.
+pub type Handler<T> = fn(&mut T, &mut Args<'_>, &mut Values<'_>) -> Result<(), Code>;
+
+/// An entry in the device's method table.
+pub struct Method<T> {
+    pub id: u32,
+    pub handler: Handler<T>,
+}
+
+/// Decodes a `Call` message into its method ID and arguments, returning
+/// `None` for any other message.
+pub fn decode_call<'a>(msg: &Message<'a>) -> Result<Option<(u32, Args<'a>)>, Error> {
+    let buf = match *msg {
+        Message::Call(buf) => buf,
+        _ => return Ok(None),
+    };
+    let mut r = tlv::Reader::new(buf);
+    match r.read_tlv8_ref()? {
+        Some((0x1, id)) => Ok(Some((record::to_u32(id)?, Args { buf: &buf[r.pos()..] }))),
+        _ => Err(Error::InvalidRecord),
+    }
+}
+
+/// Decodes a `Return` message, returning `None` for any other message.
+pub fn decode_return<'a>(msg: &Message<'a>) -> Result<Option<Result<Args<'a>, Code>>, Error> {
+    let buf = match *msg {
+        Message::Return(buf) => buf,
+        _ => return Ok(None),
+    };
+    let mut r = tlv::Reader::new(buf);
+    if r.remaining() > 0 {
+        if let Some((0x2, code)) = r.read_tlv8_ref()? {
+            return Ok(Some(Err(Code::from_u32(record::to_u32(code)?)?)))
+        }
+    }
+    Ok(Some(Ok(Args { buf })))
+}
+
+/// Answers a `Call` message from `methods`, writing the `Return` to `w`.
+/// `seq` is the sequence number attached to the call and is echoed on the
+/// return. Returns `false` if `msg` is not a call.
+pub fn serve<T>(state: &mut T, methods: &[Method<T>], msg: &Message, seq: Option<u32>, w: &mut Writer) -> Result<bool, Error> {
+    let (id, mut args) = match decode_call(msg)? {
+        Some(call) => call,
+        None => return Ok(false),
+    };
+    let mut buf = [0u8; 255];
+    let result = {
+        let mut values = Values::new(&mut buf);
+        match methods.iter().find(|m| m.id == id) {
+            Some(method) => (method.handler)(state, &mut args, &mut values).map(|_| values.rw.pos()),
+            None => Err(Code::UnknownMethod),
+        }
+    };
+    let len = match result {
+        Ok(len) => len,
+        Err(code) => {
+            let mut rw = RecordWriter::new(&mut buf);
+            rw.write_u32(Field::Error as u32, code.to_u32())?;
+            rw.pos()
+        }
+    };
+    w.all_or_nothing(|w| {
+        if let Some(seq) = seq {
+            w.seq(seq)?;
+        }
+        w.ret(&buf[..len])
+    })?;
+    Ok(true)
+}
+
+/// Why a call issued by `Client` failed.
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    /// No valid return arrived, e.g. `Error::Timeout`.
+    Link(Error),
+    /// The device answered with an error.
+    Remote(Code),
+}
+
+/// The outcome of a single call issued by `Client`.
+#[derive(Debug, PartialEq)]
+pub struct Completion<'a> {
+    pub seq: u32,
+    pub result: Result<Args<'a>, Failure>,
+}
+
+impl<'a> Completion<'a> {
+    /// Decodes the results as `R`.
+    pub fn decode<R: Param<'a>>(self) -> Result<R, Failure> {
+        self.result?.take().map_err(|_| Failure::Link(Error::InvalidRecord))
+    }
+}
+
+/// Host side of the RPC protocol.
+///
+/// Each call in flight occupies one of the caller-supplied `pending` slots
+/// until its return arrives or `timeout` ticks pass.
+pub struct Client<'a, C: Clock> {
+    tracker: Tracker<'a, C>,
+}
+
+impl<'a, C: Clock> Client<'a, C> {
+    pub fn new(clock: C, timeout: u32, pending: &'a mut [Option<Pending>]) -> Self {
+        Client { tracker: Tracker::new(clock, timeout, pending) }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.tracker.in_flight()
+    }
+
+    /// Writes a call of `method` with `args` to `w` and returns the sequence
+    /// number assigned to it. Fails with `Error::Busy` if every pending slot
+    /// is in use.
+    pub fn call<'p, A: Param<'p>>(&mut self, method: u32, args: &A, w: &mut Writer) -> Result<u32, Error> {
+        self.tracker.issue(|seq| {
+            let mut buf = [0u8; 255];
+            let len = {
+                let mut values = Values::new(&mut buf);
+                values.rw.write_u32(Field::Method as u32, method)?;
+                args.write(&mut values).map_err(|_| Error::TlvError(tlv::Error::BufferTooShort))?;
+                values.rw.pos()
+            };
+            w.all_or_nothing(|w| {
+                w.seq(seq)?;
+                w.call(&buf[..len])?;
+                Ok(())
+            })
+        })
+    }
+
+    /// Feeds a received message, along with the sequence number the reader
+    /// found attached to it. Returns the completed call if `msg` is a
+    /// return for one.
+    pub fn receive<'m>(&mut self, msg: &Message<'m>, seq: Option<u32>) -> Result<Option<Completion<'m>>, Error> {
+        let result = match decode_return(msg)? {
+            Some(result) => result.map_err(Failure::Remote),
+            None => return Ok(None),
+        };
+        Ok(self.tracker.complete(seq).map(|seq| Completion { seq, result }))
+    }
+
+    /// Returns one call that has been waiting for `timeout` ticks or more,
+    /// releasing its slot. Call repeatedly until it returns `None`.
+    pub fn expire(&mut self) -> Option<Completion<'static>> {
+        self.tracker.expire().map(|seq| Completion { seq, result: Err(Failure::Link(Error::Timeout)) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::vec::Vec;
+    use Reader;
+
+    struct Device {
+        calls: u32,
+        label: [u8; 8],
+    }
+
+    const ADD: u32 = 1;
+    const LABEL: u32 = 2;
+    const FAIL: u32 = 3;
+
+    fn add(d: &mut Device, args: &mut Args, out: &mut Values) -> Result<(), Code> {
+        d.calls += 1;
+        let (a, b): (i32, i32) = args.take()?;
+        a.checked_add(b).ok_or(Code::App(1))?.write(out)
+    }
+
+    // Prefixes the device's label to the name and says whether it fit.
+    fn label(d: &mut Device, args: &mut Args, out: &mut Values) -> Result<(), Code> {
+        d.calls += 1;
+        let (name,): (&str,) = args.take()?;
+        let n = name.len().min(d.label.len() - 4);
+        d.label[4..4 + n].copy_from_slice(&name.as_bytes()[..n]);
+        let label = ::core::str::from_utf8(&d.label[..4 + n]).map_err(|_| Code::BadArgs)?;
+        (label, n == name.len()).write(out)
+    }
+
+    fn fail(d: &mut Device, args: &mut Args, _: &mut Values) -> Result<(), Code> {
+        d.calls += 1;
+        args.end()?;
+        Err(Code::App(7))
+    }
+
+    static METHODS: [Method<Device>; 3] = [
+        Method { id: ADD, handler: add },
+        Method { id: LABEL, handler: label },
+        Method { id: FAIL, handler: fail },
+    ];
+
+    #[test]
+    fn test_rpc_loopback() {
+        let clock = Cell::new(0);
+        let mut pending = [None; 8];
+        let mut client = Client::new(&clock, 100, &mut pending);
+        let mut device = Device { calls: 0, label: *b"dev:____" };
+
+        let mut hbuf = [0u8; 512];
+        let mut h = Writer::new(&mut hbuf);
+        let calls = [
+            client.call(ADD, &(2, 40), &mut h).unwrap(),
+            client.call(LABEL, &("abc",), &mut h).unwrap(),
+            client.call(LABEL, &("toolong",), &mut h).unwrap(),
+            client.call(FAIL, &(), &mut h).unwrap(),
+            client.call(99, &(), &mut h).unwrap(),
+            client.call(ADD, &("x", 1), &mut h).unwrap(),
+            client.call(ADD, &(i32::MAX, 1), &mut h).unwrap(),
+        ];
+        assert_eq!(client.in_flight(), 7);
+
+        // The device answers every call; the returns come back in reverse.
+        let mut replies = Vec::new();
+        let mut r = Reader::new(h.as_ref());
+        let mut tmp = [0u8; 256];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            let mut dbuf = [0u8; 256];
+            let mut d = Writer::new(&mut dbuf);
+            assert!(serve(&mut device, &METHODS, &msg, r.seq(), &mut d).unwrap());
+            replies.push(d.as_ref().to_vec());
+        }
+        assert_eq!(device.calls, 6);
+
+        let mut results = Vec::new();
+        for reply in replies.iter().rev() {
+            let mut r = Reader::new(reply);
+            let msg = r.read(&mut tmp).unwrap().unwrap();
+            let done = client.receive(&msg, r.seq()).unwrap().unwrap();
+            let seq = done.seq;
+            let result = match seq {
+                s if s == calls[0] || s == calls[6] => format!("{:?}", done.decode::<i32>()),
+                s if s == calls[1] || s == calls[2] => format!("{:?}", done.decode::<(&str, bool)>()),
+                _ => format!("{:?}", done.decode::<()>()),
+            };
+            results.push((seq, result));
+        }
+        results.sort();
+        assert_eq!(client.in_flight(), 0);
+        let results: Vec<&str> = results.iter().map(|(_, r)| r.as_str()).collect();
+        assert_eq!(results, [
+            "Ok(42)",
+            "Ok((\"dev:abc\", true))",
+            "Ok((\"dev:tool\", false))",
+            "Err(Remote(App(7)))",
+            "Err(Remote(UnknownMethod))",
+            "Err(Remote(BadArgs))",
+            "Err(Remote(App(1)))",
+        ]);
+    }
+
+    #[test]
+    fn test_rpc_expire() {
+        let clock = Cell::new(0);
+        let mut pending = [None; 2];
+        let mut client = Client::new(&clock, 100, &mut pending);
+        let mut hbuf = [0u8; 64];
+        let mut h = Writer::new(&mut hbuf);
+        let first = client.call(ADD, &(1, 2), &mut h).unwrap();
+        client.call(ADD, &(3, 4), &mut h).unwrap();
+        assert_eq!(client.call(ADD, &(5, 6), &mut h), Err(Error::Busy));
+
+        clock.set(100);
+        let done = client.expire().unwrap();
+        assert_eq!((done.seq, done.result), (first, Err(Failure::Link(Error::Timeout))));
+        assert!(client.expire().is_some());
+        assert_eq!(client.expire(), None);
+
+        // A typed decode of the wrong shape fails without blaming the device.
+        let mut dbuf = [0u8; 64];
+        let mut d = Writer::new(&mut dbuf);
+        d.ret(&[0x03, 0x01, 0x2a]).unwrap();
+        let mut r = Reader::new(d.as_ref());
+        let mut tmp = [0u8; 64];
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        let args = decode_return(&msg).unwrap().unwrap().unwrap();
+        assert_eq!(Completion { seq: 1, result: Ok(args) }.decode::<i32>(), Ok(42));
+        assert_eq!(Completion { seq: 1, result: Ok(args) }.decode::<bool>(), Err(Failure::Link(Error::InvalidRecord)));
+    }
+
+    #[test]
+    fn test_rpc_without_seq() {
+        let clock = Cell::new(0);
+        let mut pending = [None; 4];
+        let mut client = Client::new(&clock, 100, &mut pending);
+        let mut hbuf = [0u8; 64];
+        let mut h = Writer::new(&mut hbuf);
+        let first = client.call(ADD, &(1, 2), &mut h).unwrap();
+        let second = client.call(ADD, &(3, 4), &mut h).unwrap();
+
+        // Both calls share a tick, so un-numbered returns complete them in
+        // the order they were made.
+        let mut dbuf = [0u8; 64];
+        let mut d = Writer::new(&mut dbuf);
+        d.ret(&[0x03, 0x01, 0x03]).unwrap();
+        d.ret(&[0x03, 0x01, 0x07]).unwrap();
+        let mut r = Reader::new(d.as_ref());
+        let mut tmp = [0u8; 64];
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        let done = client.receive(&msg, r.seq()).unwrap().unwrap();
+        assert_eq!((done.seq, done.decode::<i32>()), (first, Ok(3)));
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        let done = client.receive(&msg, r.seq()).unwrap().unwrap();
+        assert_eq!((done.seq, done.decode::<i32>()), (second, Ok(7)));
+        assert_eq!(client.in_flight(), 0);
+    }
+}
@@ -0,0 +1,318 @@
+//! Authenticated encryption of whole frames with a pre-shared key.
+//!
+//! Enabled with the `secure` feature. Each side advertises
+//! `Features::SECURE` and puts a fresh random `nonce` of `NONCE_SIZE` bytes
+//! in its `Hello`, and after the handshake both create a `Session` from the
+//! pre-shared key and the two `Hello` records. HKDF-SHA256 turns these into
+//! one ChaCha20-Poly1305 key per direction, so every boot gets new keys as
+//! long as either side's nonce is fresh.
+//!
+//! The `Hello` records travel in the clear, so both are hashed into the key
+//! derivation: if anyone on the link altered either one, the two ends
+//! derive different keys and the first sealed frame fails to open. A side
+//! with a pre-shared key must not fall back to plain frames, so
+//! `Session::new` refuses a handshake where either `Hello` lacks
+//! `Features::SECURE` rather than letting a stripped feature bit downgrade
+//! the link.
+//!
+//! From then on every frame is sealed:
+//!
+//! | bytes | contents                                  |
+//! |-------|-------------------------------------------|
+//! | 1     | `Tag::Sealed` (0x7E)                      |
+//! | 8     | frame counter, little-endian              |
+//! | n     | encrypted frame contents                  |
+//! | 16    | Poly1305 tag over all of the above        |
+//!
+//! The counter is the AEAD nonce and starts at 0 for each session. A
+//! receiver only accepts a counter above the last one it accepted, so
+//! replayed or reordered frames are rejected; lost frames are not a
+//! problem. Retransmissions must be sealed again rather than resent as
+//! they were.
+//!
+//! `Boot` and `Hello` themselves travel in plain frames, and once a session
+//! exists `open` rejects plain frames outright. Sealed frames aren't
+//! compressed, since compressed lengths leak information about the
+//! contents.
+
+use byteorder::{ByteOrder, LittleEndian};
+use chacha20poly1305::aead::AeadInPlace;
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+use cobs;
+use tlv;
+
+use hello::{Features, Hello};
+use {Error, Tag, Writer};
+
+/// Length of the `Hello` nonce each side contributes.
+pub const NONCE_SIZE: usize = 16;
+/// Length of the pre-shared key.
+pub const KEY_SIZE: usize = 32;
+
+const HEADER_SIZE: usize = 1 + 8;
+const TAG_SIZE: usize = 16;
+
+/// Bytes a sealed frame adds to its contents.
+pub const OVERHEAD: usize = HEADER_SIZE + TAG_SIZE;
+
+const INFO: &[u8] = b"sctl v1 session keys";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Device,
+    Host,
+}
+
+pub struct Session {
+    tx: ChaCha20Poly1305,
+    rx: ChaCha20Poly1305,
+    sent: u64,
+    received: Option<u64>,
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    LittleEndian::write_u64(&mut nonce[4..], counter);
+    nonce
+}
+
+impl Session {
+    /// Derives the session keys from the pre-shared key and the encoded
+    /// `Hello` records of the device and the host, exactly as exchanged.
+    /// Fails with `Error::Incompatible` if either `Hello` lacks
+    /// `Features::SECURE`, and with `Error::InvalidRecord` if either can't
+    /// be decoded or its nonce isn't `NONCE_SIZE` bytes long.
+    pub fn new(psk: &[u8; KEY_SIZE], role: Role, device_hello: &[u8], host_hello: &[u8]) -> Result<Session, Error> {
+        let device = Hello::decode(device_hello)?;
+        let host = Hello::decode(host_hello)?;
+        if !device.features.contains(Features::SECURE) || !host.features.contains(Features::SECURE) {
+            return Err(Error::Incompatible)
+        }
+        if device.nonce.len() != NONCE_SIZE || host.nonce.len() != NONCE_SIZE {
+            return Err(Error::InvalidRecord)
+        }
+        let mut salt = [0u8; 2 * NONCE_SIZE];
+        salt[..NONCE_SIZE].copy_from_slice(device.nonce);
+        salt[NONCE_SIZE..].copy_from_slice(host.nonce);
+        let device_hash = Sha256::digest(device_hello);
+        let host_hash = Sha256::digest(host_hello);
+        let transcript = [INFO, &device_hash[..], &host_hash[..]];
+        let mut keys = [0u8; 2 * KEY_SIZE];
+        Hkdf::<Sha256>::new(Some(&salt), psk).expand_multi_info(&transcript, &mut keys)
+            .map_err(|_| Error::InvalidRecord)?;
+        let (to_host, to_device) = keys.split_at(KEY_SIZE);
+        let (tx, rx) = match role {
+            Role::Device => (to_host, to_device),
+            Role::Host => (to_device, to_host),
+        };
+        Ok(Session {
+            tx: ChaCha20Poly1305::new(Key::from_slice(tx)),
+            rx: ChaCha20Poly1305::new(Key::from_slice(rx)),
+            sent: 0,
+            received: None,
+        })
+    }
+
+    /// Seals `contents` into `out`, returning the length of the sealed
+    /// frame contents.
+    pub fn seal(&mut self, contents: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        let len = contents.len() + OVERHEAD;
+        if out.len() < len {
+            return Err(Error::TlvError(tlv::Error::BufferTooShort))
+        }
+        out[0] = Tag::Sealed as u8;
+        LittleEndian::write_u64(&mut out[1..HEADER_SIZE], self.sent);
+        let (header, rest) = out[..len].split_at_mut(HEADER_SIZE);
+        let (body, tag) = rest.split_at_mut(contents.len());
+        body.copy_from_slice(contents);
+        let mac = self.tx.encrypt_in_place_detached(&nonce(self.sent), header, body)
+            .map_err(|_| Error::TlvError(tlv::Error::OutOfRange))?;
+        tag.copy_from_slice(&mac);
+        self.sent += 1;
+        Ok(len)
+    }
+
+    /// Authenticates and decrypts a decoded frame in place, returning its
+    /// contents. Fails with `Error::Unauthenticated` if the frame is plain,
+    /// truncated or was tampered with, and with `Error::Replayed` if its
+    /// counter isn't above the last accepted one.
+    pub fn open<'b>(&mut self, frame: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        if frame.len() < OVERHEAD || frame[0] != Tag::Sealed as u8 {
+            return Err(Error::Unauthenticated)
+        }
+        let counter = LittleEndian::read_u64(&frame[1..HEADER_SIZE]);
+        if self.received.is_some_and(|last| counter <= last) {
+            return Err(Error::Replayed)
+        }
+        let (header, rest) = frame.split_at_mut(HEADER_SIZE);
+        let (body, tag) = rest.split_at_mut(rest.len() - TAG_SIZE);
+        self.rx.decrypt_in_place_detached(&nonce(counter), header, body, chacha20poly1305::Tag::from_slice(tag))
+            .map_err(|_| Error::Unauthenticated)?;
+        self.received = Some(counter);
+        Ok(body)
+    }
+
+    /// Encodes the contents of `w` as a sealed COBS frame in `dst`, using
+    /// `scratch` as working space. `scratch` needs room for the contents
+    /// plus `OVERHEAD`.
+    pub fn encode<'b>(&mut self, w: &mut Writer, scratch: &mut [u8], dst: &'b mut [u8]) -> Result<&'b [u8], Error> {
+        let len = self.seal(&w.buf[..w.pos], scratch)?;
+        let n = cobs::Writer::new(dst).encode_packet(&scratch[..len])?;
+        w.pos = 0;
+        Ok(&dst[..n])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Message, Reader};
+
+    const PSK: [u8; KEY_SIZE] = [0x42; KEY_SIZE];
+    const DEVICE_NONCE: [u8; NONCE_SIZE] = [0x01; NONCE_SIZE];
+    const HOST_NONCE: [u8; NONCE_SIZE] = [0x02; NONCE_SIZE];
+
+    fn hello(firmware: &str, features: Features, nonce: &[u8]) -> ([u8; 64], usize) {
+        let hello = Hello {
+            version: 1,
+            device_id: &[],
+            firmware,
+            build: &[],
+            max_frame: 256,
+            features,
+            tick_rate: 0,
+            nonce,
+        };
+        let mut buf = [0u8; 64];
+        let len = hello.encode(&mut buf).unwrap().len();
+        (buf, len)
+    }
+
+    fn session(psk: &[u8; KEY_SIZE], role: Role, device_nonce: &[u8], host_nonce: &[u8]) -> Result<Session, Error> {
+        let (device, dn) = hello("device", Features::SECURE, device_nonce);
+        let (host, hn) = hello("host", Features::SECURE, host_nonce);
+        Session::new(psk, role, &device[..dn], &host[..hn])
+    }
+
+    fn pair() -> (Session, Session) {
+        let device = session(&PSK, Role::Device, &DEVICE_NONCE, &HOST_NONCE).unwrap();
+        let host = session(&PSK, Role::Host, &DEVICE_NONCE, &HOST_NONCE).unwrap();
+        (device, host)
+    }
+
+    fn seal(session: &mut Session, text: &[u8]) -> ([u8; 128], usize) {
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        w.stdout(text).unwrap();
+        let mut out = [0u8; 128];
+        let n = session.seal(w.as_ref(), &mut out).unwrap();
+        (out, n)
+    }
+
+    // Opens a copy of `frame`, since `open` works in place.
+    fn open(session: &mut Session, mut frame: [u8; 128], len: usize) -> Result<(), Error> {
+        session.open(&mut frame[..len]).map(|_| ())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let (mut device, mut host) = pair();
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        w.stdout(b"secret").unwrap();
+        let mut scratch = [0u8; 128];
+        let mut dst = [0u8; 140];
+        let frame = device.encode(&mut w, &mut scratch, &mut dst).unwrap().to_vec();
+        assert!(!frame.windows(6).any(|s| s == b"secret"));
+
+        let mut decoded = [0u8; 128];
+        let n = cobs::decode(&frame[..frame.len() - 1], &mut decoded).unwrap();
+        assert_eq!(n, 2 + 6 + OVERHEAD);
+        let contents = host.open(&mut decoded[..n]).unwrap();
+        let mut tmp = [0u8; 64];
+        assert_eq!(Reader::new(contents).read(&mut tmp).unwrap(), Some(Message::Stdout(b"secret")));
+
+        // The other direction uses the other key.
+        let (mut frame, n) = seal(&mut host, b"to device");
+        assert!(device.open(&mut frame[..n]).is_ok());
+        let (mut frame, n) = seal(&mut host, b"to device");
+        assert_eq!(host.open(&mut frame[..n]), Err(Error::Unauthenticated));
+    }
+
+    #[test]
+    fn test_tampered() {
+        let (mut device, mut host) = pair();
+        let (frame, n) = seal(&mut device, b"hello");
+        for i in 0..n {
+            let mut copy = frame;
+            copy[i] ^= 0x01;
+            assert!(host.open(&mut copy[..n]).is_err(), "byte {}", i);
+        }
+        // A different key or nonce doesn't verify either.
+        let mut other = session(&[0x43; KEY_SIZE], Role::Host, &DEVICE_NONCE, &HOST_NONCE).unwrap();
+        assert_eq!(open(&mut other, frame, n), Err(Error::Unauthenticated));
+        let mut other = session(&PSK, Role::Host, &DEVICE_NONCE, &[0x03; NONCE_SIZE]).unwrap();
+        assert_eq!(open(&mut other, frame, n), Err(Error::Unauthenticated));
+        // The untouched frame still opens.
+        assert!(open(&mut host, frame, n).is_ok());
+        assert_eq!(session(&PSK, Role::Host, &DEVICE_NONCE[..8], &HOST_NONCE).err(), Some(Error::InvalidRecord));
+    }
+
+    #[test]
+    fn test_replayed() {
+        let (mut device, mut host) = pair();
+        let (first, n1) = seal(&mut device, b"one");
+        let (second, n2) = seal(&mut device, b"two");
+        let (third, n3) = seal(&mut device, b"three");
+        assert!(open(&mut host, second, n2).is_ok());
+        assert_eq!(open(&mut host, second, n2), Err(Error::Replayed));
+        assert_eq!(open(&mut host, first, n1), Err(Error::Replayed));
+        // Skipping a lost frame is fine.
+        assert!(open(&mut host, third, n3).is_ok());
+
+        // A forged counter neither opens nor moves the window.
+        let (mut fourth, n4) = seal(&mut device, b"four");
+        let mut forged = fourth;
+        forged[8] = 0x80;
+        assert_eq!(host.open(&mut forged[..n4]), Err(Error::Unauthenticated));
+        assert!(host.open(&mut fourth[..n4]).is_ok());
+    }
+
+    #[test]
+    fn test_truncated() {
+        let (mut device, mut host) = pair();
+        let (frame, n) = seal(&mut device, b"hello");
+        for len in 0..n {
+            assert_eq!(open(&mut host, frame, len), Err(Error::Unauthenticated), "length {}", len);
+        }
+        // Plain frames are rejected too.
+        let mut plain = [Tag::Stdout as u8, 2, b'h', b'i'];
+        assert_eq!(host.open(&mut plain), Err(Error::Unauthenticated));
+        assert!(open(&mut host, frame, n).is_ok());
+    }
+
+    #[test]
+    fn test_handshake_tampered() {
+        let (device_hello, dn) = hello("device", Features::SECURE, &DEVICE_NONCE);
+        let (host_hello, hn) = hello("host", Features::SECURE, &HOST_NONCE);
+        let mut device = Session::new(&PSK, Role::Device, &device_hello[..dn], &host_hello[..hn]).unwrap();
+
+        // Stripping SECURE from the peer's Hello doesn't get a plain session.
+        let (stripped, sn) = hello("device", Features::NONE, &DEVICE_NONCE);
+        assert_eq!(Session::new(&PSK, Role::Host, &stripped[..sn], &host_hello[..hn]).err(), Some(Error::Incompatible));
+        let (stripped, sn) = hello("host", Features::HEARTBEAT, &HOST_NONCE);
+        assert_eq!(Session::new(&PSK, Role::Device, &device_hello[..dn], &stripped[..sn]).err(), Some(Error::Incompatible));
+
+        // Any other change to a Hello in transit leaves the ends with
+        // different keys.
+        let (altered, an) = hello("devicf", Features::SECURE, &DEVICE_NONCE);
+        let mut host = Session::new(&PSK, Role::Host, &altered[..an], &host_hello[..hn]).unwrap();
+        let (frame, n) = seal(&mut device, b"hello");
+        assert_eq!(open(&mut host, frame, n), Err(Error::Unauthenticated));
+        let (frame, n) = seal(&mut host, b"hello");
+        assert_eq!(open(&mut device, frame, n), Err(Error::Unauthenticated));
+    }
+}
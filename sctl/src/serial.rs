@@ -0,0 +1,96 @@
+//! Sending and receiving frames over `embedded_io` serial ports.
+//!
+//! Enabled with the `embedded-io` feature. `Writer::flush_to` encodes the
+//! messages written so far as a frame straight to the port, blocking until
+//! it's written, with no frame buffer needed.
+//!
+//! For a main loop that mustn't block, `Writer::encode_into` queues frames
+//! in a `cobs::Writer`, whose `poll_flush` sends what the port is ready
+//! for, and `FrameReader::poll` returns frames as they complete:
+//!
+//! ```ignore
+//! let mut tx = cobs::Writer::new(&mut tx_buf);
+//! let mut frames = FrameReader::new(&mut rx_buf);
+//! loop {
+//!     while let Some(frame) = frames.poll(&mut uart, &mut frame_buf)? {
+//!         let mut r = Reader::new(frame);
+//!         while let Some(msg) = r.read(&mut tmp)? {
+//!             // ... handle msg, writing replies to w
+//!         }
+//!         w.encode_into(&mut tx)?;
+//!     }
+//!     tx.poll_flush(&mut uart)?;
+//! }
+//! ```
+//!
+//! Frames are passed on as they are; compressed or sealed ones still need
+//! `compress::unpack` or `secure::Session::open`.
+
+use embedded_io::Write;
+
+use cobs;
+
+use Writer;
+
+pub use cobs::serial::FrameReader;
+
+impl<'a> Writer<'a> {
+    /// Encodes the contents as a frame directly to `serial` and starts
+    /// over, returning the number of bytes written.
+    pub fn flush_to<W: Write>(&mut self, serial: &mut W) -> Result<usize, W::Error> {
+        let len = cobs::serial::write_packet(serial, &self.buf[..self.pos])?;
+        self.pos = 0;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Message, Reader};
+
+    // The port and the frame queue are cobs' and tested there; only the
+    // handoff from the `Writer` is checked here.
+
+    #[test]
+    fn test_flush_to() {
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        w.stdout(b"hello\0").unwrap();
+        w.info(b"ready").unwrap();
+        let mut frame = [0u8; 64];
+        let expected = w.encode(&mut frame).unwrap().to_vec();
+
+        w.stdout(b"hello\0").unwrap();
+        w.info(b"ready").unwrap();
+        let mut port = [0u8; 64];
+        assert_eq!(w.flush_to(&mut &mut port[..]), Ok(expected.len()));
+        assert_eq!(w.pos, 0);
+        assert_eq!(port[..expected.len()], expected[..]);
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let mut tx_buf = [0u8; 64];
+        let mut tx = cobs::Writer::new(&mut tx_buf);
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        w.stdout(b"ok\n").unwrap();
+        let first = w.encode_into(&mut tx).unwrap();
+        assert_eq!(w.pos, 0);
+        w.exit(0).unwrap();
+        w.encode_into(&mut tx).unwrap();
+        assert_eq!(tx.pos(), first + 5);
+
+        let mut link = tx.as_ref();
+        let mut rx_buf = [0u8; 64];
+        let mut frames = FrameReader::new(&mut rx_buf);
+        let mut out = [0u8; 64];
+        let mut tmp = [0u8; 16];
+        let frame = frames.read(&mut link, &mut out).unwrap().unwrap();
+        assert_eq!(Reader::new(frame).read(&mut tmp).unwrap(), Some(Message::Stdout(b"ok\n")));
+        let frame = frames.read(&mut link, &mut out).unwrap().unwrap();
+        assert_eq!(Reader::new(frame).read(&mut tmp).unwrap(), Some(Message::Exit(0)));
+        assert_eq!(frames.read(&mut link, &mut out).unwrap(), None);
+    }
+}
@@ -0,0 +1,341 @@
+//! Tracking of the Boot/Run/Exit lifecycle.
+//!
+//! A device boots, runs and exits, in that order; it may also exit without
+//! running. `Session` follows the lifecycle from either end of the link:
+//! the host feeds it every message it reads with `receive`, and the device
+//! writes its lifecycle messages through `boot`, `run` and `exit`. Messages
+//! that don't fit the current state are reported to the `Observer` and
+//! rejected with `Error::Unexpected` without changing state. A `Boot` while
+//! the device is booted or running means it was reset.
+//!
+//! Link liveness is tracked separately by a `Heartbeat`; `check` moves the
+//! session to `Disconnected` once the peer has gone quiet for too long. If
+//! the device speaks again without booting, e.g. after a brief loss of the
+//! link, the session returns to the state it was in.
+//!
+//! Each end passes the peer's `Hello` record, carried by `Boot` from the
+//! device and by `Hello` from the host, to `negotiate`. The parameters it
+//! settles on are kept until the device boots again or the link is lost,
+//! and come back with the state if the device returns.
+
+use heartbeat::Heartbeat;
+use hello::{Hello, Params};
+
+use {Clock, Error, Message, Writer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Disconnected,
+    Booted,
+    Running,
+    /// The device exited with the given code.
+    Exited(u8),
+}
+
+/// Receives notifications from a `Session`. All methods default to doing
+/// nothing.
+pub trait Observer {
+    fn transition(&mut self, _from: State, _to: State) {}
+
+    /// The device booted again without exiting.
+    fn reset(&mut self) {}
+
+    /// A message arrived that isn't valid in `state`.
+    fn unexpected(&mut self, _state: State, _msg: &Message) {}
+
+    /// The peer stopped responding.
+    fn dead(&mut self) {}
+}
+
+impl Observer for () {}
+
+pub struct Session<O: Observer> {
+    state: State,
+    params: Option<Params>,
+    // The state and parameters from before `check` found the link dead.
+    lost: Option<(State, Option<Params>)>,
+    observer: O,
+}
+
+impl<O: Observer> Session<O> {
+    pub fn new(observer: O) -> Self {
+        Session { state: State::Disconnected, params: None, lost: None, observer }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Returns the parameters negotiated with the peer, if any.
+    pub fn params(&self) -> Option<Params> {
+        self.params
+    }
+
+    /// Settles on the session parameters from `local` and the peer's hello
+    /// record in `value`, keeping them until the next boot. Fails with
+    /// `Error::Incompatible` if the two ends share no protocol version.
+    pub fn negotiate(&mut self, local: &Hello, value: &[u8]) -> Result<Params, Error> {
+        let params = local.negotiate(&Hello::decode(value)?)?;
+        self.params = Some(params);
+        Ok(params)
+    }
+
+    pub fn observer(&self) -> &O {
+        &self.observer
+    }
+
+    pub fn observer_mut(&mut self) -> &mut O {
+        &mut self.observer
+    }
+
+    /// Returns to `Disconnected`, e.g. when the link is closed.
+    pub fn disconnect(&mut self) {
+        self.params = None;
+        self.lost = None;
+        self.set_state(State::Disconnected);
+    }
+
+    /// Disconnects, notifying the observer, once `heartbeat` has declared
+    /// the peer dead. Returns false if the link is dead. The session picks
+    /// up where it left off if the device speaks again without booting.
+    pub fn check<C: Clock>(&mut self, heartbeat: &Heartbeat<C>) -> bool {
+        if heartbeat.is_alive() {
+            return true
+        }
+        if self.state != State::Disconnected {
+            self.observer.dead();
+            let lost = (self.state, self.params);
+            self.disconnect();
+            self.lost = Some(lost);
+        }
+        false
+    }
+
+    /// Follows a message received from the device. Heartbeats are accepted
+    /// in every state.
+    pub fn receive(&mut self, msg: &Message) -> Result<(), Error> {
+        if let Some((state, params)) = self.lost.take() {
+            if !matches!(*msg, Message::Boot(_)) {
+                self.params = params;
+                self.set_state(state);
+            }
+        }
+        let next = match (self.state, msg) {
+            (_, &Message::Heartbeat(_)) => return Ok(()),
+            (State::Booted, &Message::Boot(_)) | (State::Running, &Message::Boot(_)) => {
+                self.observer.reset();
+                self.params = None;
+                State::Booted
+            }
+            (_, &Message::Boot(_)) => {
+                self.params = None;
+                State::Booted
+            }
+            (State::Booted, &Message::Run(_)) => State::Running,
+            (State::Booted, &Message::Exit(code)) | (State::Running, &Message::Exit(code)) => State::Exited(code),
+            (State::Disconnected, _) | (State::Exited(_), _) | (_, &Message::Run(_)) => {
+                self.observer.unexpected(self.state, msg);
+                return Err(Error::Unexpected)
+            }
+            _ => return Ok(()),
+        };
+        self.set_state(next);
+        Ok(())
+    }
+
+    /// Writes `Boot`. The device may boot in any state.
+    pub fn boot(&mut self, w: &mut Writer, value: &[u8]) -> Result<usize, Error> {
+        let len = w.boot(value)?;
+        self.params = None;
+        self.lost = None;
+        self.set_state(State::Booted);
+        Ok(len)
+    }
+
+    /// Writes `Run`. Fails with `Error::Unexpected` unless booted.
+    pub fn run(&mut self, w: &mut Writer, value: &[u8]) -> Result<usize, Error> {
+        if self.state != State::Booted {
+            return Err(Error::Unexpected)
+        }
+        let len = w.run(value)?;
+        self.set_state(State::Running);
+        Ok(len)
+    }
+
+    /// Writes `Exit`. Fails with `Error::Unexpected` unless booted or
+    /// running.
+    pub fn exit(&mut self, w: &mut Writer, code: u8) -> Result<usize, Error> {
+        if self.state != State::Booted && self.state != State::Running {
+            return Err(Error::Unexpected)
+        }
+        let len = w.exit(code)?;
+        self.set_state(State::Exited(code));
+        Ok(len)
+    }
+
+    fn set_state(&mut self, next: State) {
+        let prev = self.state;
+        self.state = next;
+        if prev != next {
+            self.observer.transition(prev, next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+    use std::string::String;
+    use hello::Features;
+    use std::vec::Vec;
+    use Reader;
+
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl Observer for Log {
+        fn transition(&mut self, from: State, to: State) {
+            self.0.push(format!("{:?} -> {:?}", from, to));
+        }
+
+        fn reset(&mut self) {
+            self.0.push(String::from("reset"));
+        }
+
+        fn unexpected(&mut self, state: State, msg: &Message) {
+            self.0.push(format!("unexpected {:?} in {:?}", msg, state));
+        }
+    }
+
+    fn replay(session: &mut Session<Log>, script: &[Message]) -> Vec<Result<(), Error>> {
+        script.iter().map(|msg| session.receive(msg)).collect()
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut session = Session::new(Log::default());
+        let results = replay(&mut session, &[
+            Message::Boot(b""),
+            Message::Stdout(b"hello"),
+            Message::Run(b"test"),
+            Message::Panic(b""),
+            Message::Exit(101),
+        ]);
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(session.state(), State::Exited(101));
+        assert_eq!(session.observer().0, vec![
+            "Disconnected -> Booted",
+            "Booted -> Running",
+            "Running -> Exited(101)",
+        ]);
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut session = Session::new(Log::default());
+        let results = replay(&mut session, &[
+            Message::Stdout(b"stale"),
+            Message::Run(b""),
+            Message::Boot(b""),
+            Message::Exit(0),
+            Message::Run(b""),
+            Message::Stdout(b"late"),
+        ]);
+        assert_eq!(results, vec![Err(Error::Unexpected), Err(Error::Unexpected), Ok(()), Ok(()), Err(Error::Unexpected), Err(Error::Unexpected)]);
+        assert_eq!(session.observer().0, vec![
+            "unexpected Stdout([115, 116, 97, 108, 101]) in Disconnected",
+            "unexpected Run([]) in Disconnected",
+            "Disconnected -> Booted",
+            "Booted -> Exited(0)",
+            "unexpected Run([]) in Exited(0)",
+            "unexpected Stdout([108, 97, 116, 101]) in Exited(0)",
+        ]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut session = Session::new(Log::default());
+        let results = replay(&mut session, &[
+            Message::Boot(b""),
+            Message::Run(b""),
+            Message::Boot(b""),
+            Message::Boot(b""),
+            Message::Run(b""),
+        ]);
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(session.state(), State::Running);
+        assert_eq!(session.observer().0, vec![
+            "Disconnected -> Booted",
+            "Booted -> Running",
+            "reset",
+            "Running -> Booted",
+            "reset",
+            "Booted -> Running",
+        ]);
+        session.disconnect();
+        assert_eq!(session.state(), State::Disconnected);
+    }
+
+    #[test]
+    fn test_device_session() {
+        let mut device = Session::new(());
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        assert_eq!(device.run(&mut w, b""), Err(Error::Unexpected));
+        assert_eq!(device.exit(&mut w, 0), Err(Error::Unexpected));
+        device.boot(&mut w, b"").unwrap();
+        device.run(&mut w, b"").unwrap();
+        assert_eq!(device.run(&mut w, b""), Err(Error::Unexpected));
+        device.exit(&mut w, 3).unwrap();
+        assert_eq!(device.state(), State::Exited(3));
+
+        // The host follows the device through the same states.
+        let mut host = Session::new(Log::default());
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 16];
+        while let Some(msg) = r.read(&mut tmp).unwrap() {
+            host.receive(&msg).unwrap();
+        }
+        assert_eq!(host.state(), device.state());
+        assert_eq!(host.observer().0.len(), 3);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let device = Hello {
+            version: 1,
+            device_id: &[0x42],
+            firmware: "0.1.0",
+            build: &[],
+            max_frame: 256,
+            features: Features::ARQ.union(Features::SEQ),
+            tick_rate: 0,
+            nonce: &[],
+        };
+        let host = Hello { firmware: "host", max_frame: 4096, features: Features::SEQ, ..device };
+        let (mut dbuf, mut hbuf) = ([0u8; 64], [0u8; 64]);
+        let (dvalue, hvalue) = (device.encode(&mut dbuf).unwrap(), host.encode(&mut hbuf).unwrap());
+
+        // Both ends settle on the same parameters.
+        let mut d = Session::new(());
+        let mut wbuf = [0u8; 64];
+        let mut w = Writer::new(&mut wbuf);
+        d.boot(&mut w, dvalue).unwrap();
+        let params = d.negotiate(&device, hvalue).unwrap();
+        assert_eq!(params, Params { version: 1, max_frame: 256, features: Features::SEQ });
+        assert_eq!(d.params(), Some(params));
+
+        let mut h = Session::new(());
+        h.receive(&Message::Boot(dvalue)).unwrap();
+        assert_eq!(h.params(), None);
+        assert_eq!(h.negotiate(&host, dvalue), Ok(params));
+        assert_eq!(h.params(), Some(params));
+
+        // A reset forgets them.
+        h.receive(&Message::Boot(dvalue)).unwrap();
+        assert_eq!(h.params(), None);
+        assert_eq!(h.negotiate(&host, b""), Err(Error::InvalidRecord));
+        assert_eq!(h.params(), None);
+    }
+}
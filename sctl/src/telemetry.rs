@@ -0,0 +1,290 @@
+//! Typed telemetry on top of `Signal` and `Sample`.
+//!
+//! Each signal is declared once with a `Signal` message giving its ID,
+//! name, type and unit. After that a `Sample` message carries one or more
+//! samples, each the signal's ID as LEB128 followed by the value:
+//!
+//! | kind   | encoding                 |
+//! |--------|--------------------------|
+//! | `U32`  | unsigned LEB128          |
+//! | `I32`  | signed LEB128            |
+//! | `F32`  | 4 bytes, little-endian   |
+//! | `Bool` | one byte, 0 or 1         |
+//!
+//! Samples don't say their own type, so a receiver has to have seen the
+//! declarations to decode them. Devices should resend their declarations
+//! after `Boot` and whenever the host asks, e.g. on reconnect.
+
+use byteorder::{ByteOrder, LittleEndian};
+use leb128;
+
+use record::{self, RecordReader, RecordWriter};
+use {Error, Message, Writer};
+
+enum Field {
+    Id = 0x1,
+    Name = 0x2,
+    Kind = 0x3,
+    Unit = 0x4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    U32 = 0x1,
+    I32 = 0x2,
+    F32 = 0x3,
+    Bool = 0x4,
+}
+
+impl Kind {
+    fn from_u32(value: u32) -> Result<Kind, Error> {
+        match value {
+            0x1 => Ok(Kind::U32),
+            0x2 => Ok(Kind::I32),
+            0x3 => Ok(Kind::F32),
+            0x4 => Ok(Kind::Bool),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+}
+
+impl Value {
+    pub fn kind(&self) -> Kind {
+        match *self {
+            Value::U32(_) => Kind::U32,
+            Value::I32(_) => Kind::I32,
+            Value::F32(_) => Kind::F32,
+            Value::Bool(_) => Kind::Bool,
+        }
+    }
+}
+
+/// The declaration of a signal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Signal<'a> {
+    pub id: u32,
+    pub name: &'a str,
+    pub kind: Kind,
+    pub unit: &'a str,
+}
+
+impl<'a> Signal<'a> {
+    pub fn write(&self, w: &mut Writer) -> Result<usize, Error> {
+        let mut buf = [0u8; 255];
+        let len = {
+            let mut rw = RecordWriter::new(&mut buf);
+            rw.write_u32(Field::Id as u32, self.id)?;
+            rw.write_str(Field::Name as u32, self.name)?;
+            rw.write_u32(Field::Kind as u32, self.kind as u32)?;
+            if !self.unit.is_empty() {
+                rw.write_str(Field::Unit as u32, self.unit)?;
+            }
+            rw.pos()
+        };
+        w.signal(&buf[..len])
+    }
+
+    /// Decodes a `Signal` message, returning `None` for any other message.
+    pub fn from_message(msg: &Message<'a>) -> Result<Option<Self>, Error> {
+        let buf = match *msg {
+            Message::Signal(buf) => buf,
+            _ => return Ok(None),
+        };
+        let (mut id, mut name, mut kind, mut unit) = (None, None, None, "");
+        let mut r = RecordReader::new(buf);
+        while let Some((tag, value)) = r.read()? {
+            match tag {
+                0x1 => id = Some(record::to_u32(value)?),
+                0x2 => name = Some(record::to_str(value)?),
+                0x3 => kind = Some(Kind::from_u32(record::to_u32(value)?)?),
+                0x4 => unit = record::to_str(value)?,
+                _ => {}
+            }
+        }
+        match (id, name, kind) {
+            (Some(id), Some(name), Some(kind)) => Ok(Some(Signal { id, name, kind, unit })),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+/// Builds the value of a `Sample` message.
+pub struct Samples<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Samples<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Samples { buf, pos: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+
+    /// Appends a sample. Nothing is appended if it doesn't fit.
+    pub fn push(&mut self, id: u32, value: Value) -> Result<(), Error> {
+        let buf = &mut self.buf[self.pos..];
+        let mut len = {
+            let mut w = leb128::Writer::new(buf);
+            w.write_u32(id)?;
+            match value {
+                Value::U32(v) => w.write_u32(v)?,
+                Value::I32(v) => w.write_i32(v)?,
+                _ => {}
+            }
+            w.pos()
+        };
+        let tail = match value {
+            Value::F32(_) => 4,
+            Value::Bool(_) => 1,
+            _ => 0,
+        };
+        if buf.len() < len + tail {
+            return Err(Error::Leb128Error(leb128::Error::BufferTooShort))
+        }
+        match value {
+            Value::F32(v) => LittleEndian::write_f32(&mut buf[len..], v),
+            Value::Bool(v) => buf[len] = v as u8,
+            _ => {}
+        }
+        len += tail;
+        self.pos += len;
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.pos]
+    }
+
+    /// Writes the samples as one `Sample` message and starts over.
+    pub fn flush(&mut self, w: &mut Writer) -> Result<usize, Error> {
+        let len = w.sample(&self.buf[..self.pos])?;
+        self.pos = 0;
+        Ok(len)
+    }
+}
+
+/// Reads the samples in the value of a `Sample` message.
+pub struct SampleReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SampleReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        SampleReader { buf, pos: 0 }
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let mut r = leb128::Reader::new(&self.buf[self.pos..]);
+        let value = r.read_u32()?.ok_or(Error::InvalidRecord)?;
+        self.pos += r.pos();
+        Ok(value)
+    }
+
+    fn read_i32(&mut self) -> Result<i32, Error> {
+        let mut r = leb128::Reader::new(&self.buf[self.pos..]);
+        let value = r.read_i32()?.ok_or(Error::InvalidRecord)?;
+        self.pos += r.pos();
+        Ok(value)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.buf.len() - self.pos < len {
+            return Err(Error::InvalidRecord)
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Returns the next sample, looking up the type of its signal with
+    /// `kind`. Fails with `Error::InvalidRecord` if the signal is unknown,
+    /// since the rest of the message can't be decoded without it.
+    pub fn next<F: Fn(u32) -> Option<Kind>>(&mut self, kind: F) -> Result<Option<(u32, Value)>, Error> {
+        if self.pos == self.buf.len() {
+            return Ok(None)
+        }
+        let id = self.read_u32()?;
+        let value = match kind(id) {
+            Some(Kind::U32) => Value::U32(self.read_u32()?),
+            Some(Kind::I32) => Value::I32(self.read_i32()?),
+            Some(Kind::F32) => Value::F32(LittleEndian::read_f32(self.read_bytes(4)?)),
+            Some(Kind::Bool) => match self.read_bytes(1)?[0] {
+                0 => Value::Bool(false),
+                1 => Value::Bool(true),
+                _ => return Err(Error::InvalidRecord),
+            },
+            None => return Err(Error::InvalidRecord),
+        };
+        Ok(Some((id, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+    use Reader;
+
+    const SIGNALS: [Signal<'static>; 4] = [
+        Signal { id: 1, name: "temp", kind: Kind::F32, unit: "degC" },
+        Signal { id: 2, name: "count", kind: Kind::U32, unit: "" },
+        Signal { id: 300, name: "offset", kind: Kind::I32, unit: "mV" },
+        Signal { id: 4, name: "fan", kind: Kind::Bool, unit: "" },
+    ];
+
+    #[test]
+    fn test_telemetry() {
+        let mut wbuf = [0u8; 256];
+        let mut w = Writer::new(&mut wbuf);
+        for signal in SIGNALS.iter() {
+            signal.write(&mut w).unwrap();
+        }
+        let mut sbuf = [0u8; 64];
+        let mut samples = Samples::new(&mut sbuf);
+        samples.push(1, Value::F32(21.5)).unwrap();
+        samples.push(2, Value::U32(1000)).unwrap();
+        samples.push(300, Value::I32(-5)).unwrap();
+        samples.push(4, Value::Bool(true)).unwrap();
+        assert_eq!(samples.as_bytes(), &[0x01, 0x00, 0x00, 0xac, 0x41, 0x02, 0xe8, 0x07, 0xac, 0x02, 0x7b, 0x04, 0x01]);
+        samples.flush(&mut w).unwrap();
+        assert!(samples.is_empty());
+
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 256];
+        let mut declared = Vec::new();
+        for _ in 0..4 {
+            let msg = r.read(&mut tmp).unwrap().unwrap();
+            let signal = Signal::from_message(&msg).unwrap().unwrap();
+            declared.push((signal.id, signal.kind));
+            assert_eq!(signal, SIGNALS[declared.len() - 1]);
+        }
+        let kind = |id| declared.iter().find(|&&(i, _)| i == id).map(|&(_, k)| k);
+
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        let value = match msg {
+            Message::Sample(value) => value,
+            other => panic!("unexpected {:?}", other),
+        };
+        let mut sr = SampleReader::new(value);
+        let mut read = Vec::new();
+        while let Some(sample) = sr.next(kind).unwrap() {
+            read.push(sample);
+        }
+        assert_eq!(read, [(1, Value::F32(21.5)), (2, Value::U32(1000)), (300, Value::I32(-5)), (4, Value::Bool(true))]);
+
+        let mut sr = SampleReader::new(value);
+        assert_eq!(sr.next(|_| None), Err(Error::InvalidRecord));
+    }
+}
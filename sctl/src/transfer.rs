@@ -0,0 +1,678 @@
+//! Chunked transfer of firmware images and other blobs.
+//!
+//! The host sends `Transfer` messages and the device answers each with a
+//! `TransferAck`. Both are records whose `Op` field says which step they
+//! belong to:
+//!
+//! - `Open` names the target, e.g. a flash slot, with the image's size and
+//!   CRC-32. The device answers with the offset to continue from, which is
+//!   nonzero if an identical transfer was already partly done.
+//! - `Data` carries a chunk and the offset it belongs at. The device writes
+//!   chunks that arrive in order and answers every chunk with the offset it
+//!   expects next, so lost or repeated chunks are simply resent.
+//! - `Verify` asks the device to check the CRC of everything written and to
+//!   commit the image if it matches.
+//! - `Abort` drops the transfer in progress.
+//!
+//! `Sender` sends one step at a time and resends it after a timeout;
+//! `Receiver` writes through a `Storage`.
+
+use record::{self, RecordReader, RecordWriter};
+use {Clock, Error, Message, Writer};
+
+/// The largest chunk that fits in a single `Transfer` message.
+pub const MAX_CHUNK: usize = 240;
+
+/// Incremental CRC-32 (IEEE 802.3, as used by zlib and PNG).
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Crc32(!0)
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &b in data {
+            self.0 ^= b as u32;
+            for _ in 0..8 {
+                self.0 = (self.0 >> 1) ^ (0xedb8_8320 & (self.0 & 1).wrapping_neg());
+            }
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+enum Field {
+    Op = 0x1,
+    Target = 0x2,
+    Size = 0x3,
+    Crc = 0x4,
+    Offset = 0x5,
+    Data = 0x6,
+    Status = 0x7,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Open = 0x1,
+    Data = 0x2,
+    Verify = 0x3,
+    Abort = 0x4,
+}
+
+impl Op {
+    fn from_u32(value: u32) -> Result<Op, Error> {
+        match value {
+            0x1 => Ok(Op::Open),
+            0x2 => Ok(Op::Data),
+            0x3 => Ok(Op::Verify),
+            0x4 => Ok(Op::Abort),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    NotOpen = 0x1,
+    TooLarge = 0x2,
+    Storage = 0x3,
+    Incomplete = 0x4,
+    CrcMismatch = 0x5,
+}
+
+impl Status {
+    fn from_u32(value: u32) -> Result<Status, Error> {
+        match value {
+            0x1 => Ok(Status::NotOpen),
+            0x2 => Ok(Status::TooLarge),
+            0x3 => Ok(Status::Storage),
+            0x4 => Ok(Status::Incomplete),
+            0x5 => Ok(Status::CrcMismatch),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+/// Where the device puts received images, e.g. a flash partition.
+pub trait Storage {
+    /// Prepares `target` to receive `size` bytes, e.g. by erasing it.
+    fn begin(&mut self, target: u32, size: u32) -> Result<(), Status>;
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Status>;
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Status>;
+    /// Called once the whole image has been written and verified.
+    fn commit(&mut self) -> Result<(), Status>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Request<'a> {
+    Open { target: u32, size: u32, crc: u32 },
+    Data { offset: u32, data: &'a [u8] },
+    Verify,
+    Abort,
+}
+
+impl<'a> Request<'a> {
+    pub fn op(&self) -> Op {
+        match *self {
+            Request::Open { .. } => Op::Open,
+            Request::Data { .. } => Op::Data,
+            Request::Verify => Op::Verify,
+            Request::Abort => Op::Abort,
+        }
+    }
+
+    pub fn write(&self, w: &mut Writer) -> Result<usize, Error> {
+        let mut buf = [0u8; 255];
+        let len = {
+            let mut rw = RecordWriter::new(&mut buf);
+            rw.write_u32(Field::Op as u32, self.op() as u32)?;
+            match *self {
+                Request::Open { target, size, crc } => {
+                    rw.write_u32(Field::Target as u32, target)?;
+                    rw.write_u32(Field::Size as u32, size)?;
+                    rw.write_u32(Field::Crc as u32, crc)?;
+                }
+                Request::Data { offset, data } => {
+                    rw.write_u32(Field::Offset as u32, offset)?;
+                    rw.write_bytes(Field::Data as u32, data)?;
+                }
+                Request::Verify | Request::Abort => {}
+            }
+            rw.pos()
+        };
+        w.transfer(&buf[..len])
+    }
+
+    /// Decodes a `Transfer` message, returning `None` for any other message.
+    pub fn from_message(msg: &Message<'a>) -> Result<Option<Self>, Error> {
+        let f = match *msg {
+            Message::Transfer(buf) => read_record(buf)?,
+            _ => return Ok(None),
+        };
+        let req = match f.op.map(Op::from_u32) {
+            Some(Ok(Op::Open)) => match (f.target, f.size, f.crc) {
+                (Some(target), Some(size), Some(crc)) => Request::Open { target, size, crc },
+                _ => return Err(Error::InvalidRecord),
+            },
+            Some(Ok(Op::Data)) => match (f.offset, f.data) {
+                (Some(offset), Some(data)) => Request::Data { offset, data },
+                _ => return Err(Error::InvalidRecord),
+            },
+            Some(Ok(Op::Verify)) => Request::Verify,
+            Some(Ok(Op::Abort)) => Request::Abort,
+            Some(Err(e)) => return Err(e),
+            None => return Err(Error::InvalidRecord),
+        };
+        Ok(Some(req))
+    }
+}
+
+/// The device's answer to a `Request`: the offset it expects next, or why
+/// the step failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ack {
+    pub op: Op,
+    pub result: Result<u32, Status>,
+}
+
+impl Ack {
+    pub fn write(&self, w: &mut Writer) -> Result<usize, Error> {
+        let mut buf = [0u8; 32];
+        let len = {
+            let mut rw = RecordWriter::new(&mut buf);
+            rw.write_u32(Field::Op as u32, self.op as u32)?;
+            match self.result {
+                Ok(offset) => rw.write_u32(Field::Offset as u32, offset)?,
+                Err(status) => rw.write_u32(Field::Status as u32, status as u32)?,
+            };
+            rw.pos()
+        };
+        w.transfer_ack(&buf[..len])
+    }
+
+    /// Decodes a `TransferAck` message, returning `None` for any other
+    /// message.
+    pub fn from_message(msg: &Message) -> Result<Option<Self>, Error> {
+        let f = match *msg {
+            Message::TransferAck(buf) => read_record(buf)?,
+            _ => return Ok(None),
+        };
+        let op = Op::from_u32(f.op.ok_or(Error::InvalidRecord)?)?;
+        match (f.offset, f.status) {
+            (Some(offset), None) => Ok(Some(Ack { op, result: Ok(offset) })),
+            (None, Some(status)) => Ok(Some(Ack { op, result: Err(Status::from_u32(status)?) })),
+            _ => Err(Error::InvalidRecord),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Fields<'a> {
+    op: Option<u32>,
+    target: Option<u32>,
+    size: Option<u32>,
+    crc: Option<u32>,
+    offset: Option<u32>,
+    data: Option<&'a [u8]>,
+    status: Option<u32>,
+}
+
+fn read_record(buf: &[u8]) -> Result<Fields<'_>, Error> {
+    let mut f = Fields::default();
+    let mut r = RecordReader::new(buf);
+    while let Some((tag, value)) = r.read()? {
+        match tag {
+            0x1 => f.op = Some(record::to_u32(value)?),
+            0x2 => f.target = Some(record::to_u32(value)?),
+            0x3 => f.size = Some(record::to_u32(value)?),
+            0x4 => f.crc = Some(record::to_u32(value)?),
+            0x5 => f.offset = Some(record::to_u32(value)?),
+            0x6 => f.data = Some(value),
+            0x7 => f.status = Some(record::to_u32(value)?),
+            _ => {}
+        }
+    }
+    Ok(f)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Open {
+    target: u32,
+    size: u32,
+    crc: u32,
+}
+
+/// Device side of the transfer protocol.
+///
+/// An `Open` identical to the transfer in progress resumes it, so the host
+/// can pick up where it left off after a dropped link or a restart of the
+/// host tool.
+pub struct Receiver<S: Storage> {
+    storage: S,
+    open: Option<Open>,
+    offset: u32,
+    verified: bool,
+}
+
+impl<S: Storage> Receiver<S> {
+    pub fn new(storage: S) -> Self {
+        Receiver { storage, open: None, offset: 0, verified: false }
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    pub fn storage_mut(&mut self) -> &mut S {
+        &mut self.storage
+    }
+
+    /// Returns the number of bytes received and the size of the transfer in
+    /// progress.
+    pub fn progress(&self) -> Option<(u32, u32)> {
+        self.open.map(|open| (self.offset, open.size))
+    }
+
+    fn open(&mut self, target: u32, size: u32, crc: u32) -> Result<u32, Status> {
+        let open = Open { target, size, crc };
+        if self.open != Some(open) {
+            self.open = None;
+            self.storage.begin(target, size)?;
+            self.open = Some(open);
+            self.offset = 0;
+            self.verified = false;
+        }
+        Ok(self.offset)
+    }
+
+    fn data(&mut self, offset: u32, data: &[u8]) -> Result<u32, Status> {
+        let open = self.open.ok_or(Status::NotOpen)?;
+        if offset == self.offset && !self.verified {
+            if (open.size - offset) < data.len() as u32 {
+                return Err(Status::TooLarge)
+            }
+            self.storage.write(offset, data)?;
+            self.offset += data.len() as u32;
+        }
+        Ok(self.offset)
+    }
+
+    fn verify(&mut self) -> Result<u32, Status> {
+        let open = self.open.ok_or(Status::NotOpen)?;
+        if self.verified {
+            return Ok(self.offset)
+        }
+        if self.offset != open.size {
+            return Err(Status::Incomplete)
+        }
+        let mut crc = Crc32::new();
+        let mut buf = [0u8; 64];
+        let mut pos = 0;
+        while pos < open.size {
+            let n = buf.len().min((open.size - pos) as usize);
+            self.storage.read(pos, &mut buf[..n])?;
+            crc.update(&buf[..n]);
+            pos += n as u32;
+        }
+        if crc.finish() != open.crc {
+            self.open = None;
+            return Err(Status::CrcMismatch)
+        }
+        self.storage.commit()?;
+        self.verified = true;
+        Ok(self.offset)
+    }
+
+    /// Answers a `Transfer` message, writing the `TransferAck` to `w`.
+    /// Returns `false` if `msg` is not a transfer request.
+    pub fn serve(&mut self, msg: &Message, w: &mut Writer) -> Result<bool, Error> {
+        let req = match Request::from_message(msg)? {
+            Some(req) => req,
+            None => return Ok(false),
+        };
+        let result = match req {
+            Request::Open { target, size, crc } => self.open(target, size, crc),
+            Request::Data { offset, data } => self.data(offset, data),
+            Request::Verify => self.verify(),
+            Request::Abort => {
+                self.open = None;
+                Ok(0)
+            }
+        };
+        Ack { op: req.op(), result }.write(w)?;
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Opening,
+    Sending,
+    Verifying,
+    Done,
+    Failed(Status),
+    TimedOut,
+}
+
+/// Host side of the transfer protocol.
+///
+/// Call `poll` regularly and feed every received message to `receive`
+/// until the phase is `Done`, `Failed` or `TimedOut`. A step is resent
+/// every `timeout` ticks until it is acknowledged, and the transfer gives
+/// up after `max_retries` resends of the same step.
+pub struct Sender<'a, C: Clock> {
+    clock: C,
+    timeout: u32,
+    max_retries: u32,
+    target: u32,
+    image: &'a [u8],
+    crc: u32,
+    chunk: usize,
+    phase: Phase,
+    offset: u32,
+    sent: Option<u32>,
+    retries: u32,
+}
+
+impl<'a, C: Clock> Sender<'a, C> {
+    pub fn new(clock: C, timeout: u32, target: u32, image: &'a [u8], chunk: usize) -> Self {
+        Sender {
+            clock,
+            timeout,
+            max_retries: 8,
+            target,
+            image,
+            crc: crc32(image),
+            chunk: chunk.clamp(1, MAX_CHUNK),
+            phase: Phase::Opening,
+            offset: 0,
+            sent: None,
+            retries: 0,
+        }
+    }
+
+    pub fn with_retries(self, max_retries: u32) -> Self {
+        Sender { max_retries, ..self }
+    }
+
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Returns the number of bytes the device has acknowledged.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn size(&self) -> u32 {
+        self.image.len() as u32
+    }
+
+    /// Sends the current step if it hasn't been sent yet or its ack is
+    /// overdue. Fails with `Error::Timeout` once the retries are used up.
+    pub fn poll(&mut self, w: &mut Writer) -> Result<(), Error> {
+        let now = self.clock.now();
+        if let Some(sent) = self.sent {
+            if now.wrapping_sub(sent) < self.timeout {
+                return Ok(())
+            }
+            if self.retries == self.max_retries {
+                self.phase = Phase::TimedOut;
+                self.sent = None;
+                return Err(Error::Timeout)
+            }
+            self.retries += 1;
+        }
+        let req = match self.phase {
+            Phase::Opening => Request::Open { target: self.target, size: self.size(), crc: self.crc },
+            Phase::Sending => {
+                let start = self.offset as usize;
+                let end = self.image.len().min(start + self.chunk);
+                Request::Data { offset: self.offset, data: &self.image[start..end] }
+            }
+            Phase::Verifying => Request::Verify,
+            Phase::Done | Phase::Failed(_) | Phase::TimedOut => return Ok(()),
+        };
+        req.write(w)?;
+        self.sent = Some(now);
+        Ok(())
+    }
+
+    /// Handles a received message. Returns `true` if it was a `TransferAck`
+    /// for the current step.
+    pub fn receive(&mut self, msg: &Message) -> Result<bool, Error> {
+        let ack = match Ack::from_message(msg)? {
+            Some(ack) => ack,
+            None => return Ok(false),
+        };
+        let offset = match (self.phase, ack.op) {
+            (Phase::Opening, Op::Open) | (Phase::Sending, Op::Data) | (Phase::Verifying, Op::Verify) => {
+                match ack.result {
+                    Ok(offset) => offset,
+                    Err(status) => {
+                        self.phase = Phase::Failed(status);
+                        self.sent = None;
+                        return Ok(true)
+                    }
+                }
+            }
+            _ => return Ok(false),
+        };
+        if offset > self.size() {
+            return Err(Error::InvalidRecord)
+        }
+        if self.phase == Phase::Sending && offset < self.offset {
+            // A late ack for a chunk that was resent.
+            return Ok(false)
+        }
+        self.retries = 0;
+        self.offset = offset;
+        self.sent = None;
+        self.phase = match self.phase {
+            Phase::Verifying => Phase::Done,
+            _ if offset == self.size() => Phase::Verifying,
+            _ => Phase::Sending,
+        };
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+    use std::vec::Vec;
+    use Reader;
+
+    // 1 KiB of fake flash that records commits and can corrupt a write.
+    struct Flash {
+        data: [u8; 1024],
+        size: u32,
+        commits: usize,
+        corrupt: Option<u32>,
+    }
+
+    impl Flash {
+        fn new() -> Flash {
+            Flash { data: [0xff; 1024], size: 0, commits: 0, corrupt: None }
+        }
+    }
+
+    impl Storage for Flash {
+        fn begin(&mut self, _: u32, size: u32) -> Result<(), Status> {
+            if size as usize > self.data.len() {
+                return Err(Status::TooLarge)
+            }
+            self.data = [0xff; 1024];
+            self.size = size;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), Status> {
+            let start = offset as usize;
+            self.data[start..start + data.len()].copy_from_slice(data);
+            if let Some(at) = self.corrupt.take() {
+                self.data[at as usize] ^= 1;
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), Status> {
+            let start = offset as usize;
+            buf.copy_from_slice(&self.data[start..start + buf.len()]);
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<(), Status> {
+            self.commits += 1;
+            Ok(())
+        }
+    }
+
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+    }
+
+    // Runs `sender` against `receiver` until the transfer ends or `steps`
+    // ticks pass. `drop` decides from a running message count whether a
+    // message is lost; it sees host and device messages alike. Returns the
+    // number of `Data` requests the device saw.
+    fn run(sender: &mut Sender<&Cell<u32>>, receiver: &mut Receiver<Flash>, clock: &Cell<u32>, steps: u32, drop: &dyn Fn(u32) -> bool) -> usize {
+        let mut count = 0;
+        let mut chunks = 0;
+        let mut tmp = [0u8; 256];
+        for _ in 0..steps {
+            match sender.phase() {
+                Phase::Done | Phase::Failed(_) | Phase::TimedOut => break,
+                _ => {}
+            }
+            let mut wbuf = [0u8; 512];
+            let mut w = Writer::new(&mut wbuf);
+            let _ = sender.poll(&mut w);
+
+            let mut abuf = [0u8; 512];
+            let mut acks = Writer::new(&mut abuf);
+            let mut r = Reader::new(w.as_ref());
+            while let Some(msg) = r.read(&mut tmp).unwrap() {
+                count += 1;
+                if drop(count) {
+                    continue
+                }
+                if let Ok(Some(Request::Data { .. })) = Request::from_message(&msg) {
+                    chunks += 1;
+                }
+                assert!(receiver.serve(&msg, &mut acks).unwrap());
+            }
+
+            let mut r = Reader::new(acks.as_ref());
+            while let Some(msg) = r.read(&mut tmp).unwrap() {
+                count += 1;
+                if !drop(count) {
+                    sender.receive(&msg).unwrap();
+                }
+            }
+            clock.set(clock.get() + 1);
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_lossy() {
+        let clock = Cell::new(0);
+        let data = image(1000);
+        let mut receiver = Receiver::new(Flash::new());
+        let mut sender = Sender::new(&clock, 5, 1, &data, 100);
+        let chunks = run(&mut sender, &mut receiver, &clock, 1000, &|n| n % 3 == 0 || n % 7 == 0);
+        assert_eq!(sender.phase(), Phase::Done);
+        assert_eq!(sender.offset(), 1000);
+        assert!(chunks > 10);
+        assert_eq!(&receiver.storage().data[..1000], &data[..]);
+        assert_eq!(receiver.storage().commits, 1);
+        assert_eq!(receiver.progress(), Some((1000, 1000)));
+    }
+
+    #[test]
+    fn test_resume() {
+        let clock = Cell::new(0);
+        let data = image(1000);
+        let mut receiver = Receiver::new(Flash::new());
+
+        // The link goes down for good after the first few chunks.
+        let mut sender = Sender::new(&clock, 5, 1, &data, 200).with_retries(2);
+        run(&mut sender, &mut receiver, &clock, 1000, &|n| n > 6);
+        assert_eq!(sender.phase(), Phase::TimedOut);
+        assert_eq!(receiver.progress(), Some((400, 1000)));
+
+        let mut sender = Sender::new(&clock, 5, 1, &data, 200);
+        let chunks = run(&mut sender, &mut receiver, &clock, 1000, &|_| false);
+        assert_eq!(sender.phase(), Phase::Done);
+        assert_eq!(chunks, 3);
+        assert_eq!(&receiver.storage().data[..1000], &data[..]);
+
+        // A different image starts over.
+        let other = image(300);
+        let mut sender = Sender::new(&clock, 5, 1, &other, 200);
+        assert_eq!(run(&mut sender, &mut receiver, &clock, 1000, &|_| false), 2);
+        assert_eq!(sender.phase(), Phase::Done);
+        assert_eq!(receiver.storage().commits, 2);
+    }
+
+    #[test]
+    fn test_failures() {
+        let clock = Cell::new(0);
+        let data = image(500);
+        let mut flash = Flash::new();
+        flash.corrupt = Some(123);
+        let mut receiver = Receiver::new(flash);
+        let mut sender = Sender::new(&clock, 5, 1, &data, 240);
+        run(&mut sender, &mut receiver, &clock, 1000, &|_| false);
+        assert_eq!(sender.phase(), Phase::Failed(Status::CrcMismatch));
+        assert_eq!(receiver.storage().commits, 0);
+        assert_eq!(receiver.progress(), None);
+
+        let big = image(2000);
+        let mut sender = Sender::new(&clock, 5, 1, &big, 240);
+        run(&mut sender, &mut receiver, &clock, 1000, &|_| false);
+        assert_eq!(sender.phase(), Phase::Failed(Status::TooLarge));
+
+        let mut abuf = [0u8; 64];
+        let mut w = Writer::new(&mut abuf);
+        Request::Verify.write(&mut w).unwrap();
+        let mut r = Reader::new(w.as_ref());
+        let mut tmp = [0u8; 64];
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        let mut rbuf = [0u8; 64];
+        let mut resp = Writer::new(&mut rbuf);
+        receiver.serve(&msg, &mut resp).unwrap();
+        let mut r = Reader::new(resp.as_ref());
+        let msg = r.read(&mut tmp).unwrap().unwrap();
+        assert_eq!(Ack::from_message(&msg), Ok(Some(Ack { op: Op::Verify, result: Err(Status::NotOpen) })));
+    }
+}
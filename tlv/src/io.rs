@@ -0,0 +1,181 @@
+//! Reading and writing TLV records over `std::io` streams.
+//!
+//! Enabled with the `std` feature. Records use the same encoding as the
+//! slice `Reader` and `Writer`: a LEB128 tag, a big-endian length of 8, 16
+//! or 32 bits and the value.
+//!
+//! A stream that ends before a record starts returns `Ok(None)`, while one
+//! that ends partway through a record fails with `UnexpectedEof`. The tag
+//! is read a byte at a time, so wrap unbuffered sources in a `BufReader`.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::vec::Vec;
+
+use byteorder::{ByteOrder, BigEndian};
+use leb128;
+
+use Error;
+
+pub struct Reader<R: Read> {
+    inner: leb128::io::Reader<R>,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(inner: R) -> Self {
+        Reader { inner: leb128::io::Reader::new(inner) }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
+
+    pub fn read_tag(&mut self) -> io::Result<Option<u32>> {
+        self.inner.read_u32()
+    }
+
+    fn read_len<F: Fn(&[u8]) -> usize>(&mut self, size: usize, decode: F) -> io::Result<usize> {
+        let mut buf = [0u8; 4];
+        self.get_mut().read_exact(&mut buf[..size])?;
+        Ok(decode(&buf[..size]))
+    }
+
+    // Reads `len` bytes, growing the value as it arrives rather than
+    // trusting the length up front.
+    fn read_value(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut value = Vec::new();
+        self.get_mut().take(len as u64).read_to_end(&mut value)?;
+        if value.len() < len {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "truncated TLV value"))
+        }
+        Ok(value)
+    }
+
+    fn read_tlv<F: Fn(&[u8]) -> usize>(&mut self, size: usize, decode: F) -> io::Result<Option<(u32, Vec<u8>)>> {
+        if let Some(tag) = self.read_tag()? {
+            let len = self.read_len(size, decode)?;
+            Ok(Some((tag, self.read_value(len)?)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn read_tlv8(&mut self) -> io::Result<Option<(u32, Vec<u8>)>> {
+        self.read_tlv(1, |b| b[0] as usize)
+    }
+
+    pub fn read_tlv16(&mut self) -> io::Result<Option<(u32, Vec<u8>)>> {
+        self.read_tlv(2, |b| BigEndian::read_u16(b) as usize)
+    }
+
+    pub fn read_tlv32(&mut self) -> io::Result<Option<(u32, Vec<u8>)>> {
+        self.read_tlv(4, |b| BigEndian::read_u32(b) as usize)
+    }
+}
+
+pub struct Writer<W: Write> {
+    inner: leb128::io::Writer<W>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W) -> Self {
+        Writer { inner: leb128::io::Writer::new(inner) }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.get_mut()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner.into_inner()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    pub fn write_tag(&mut self, tag: u32) -> io::Result<usize> {
+        self.inner.write_u32(tag)
+    }
+
+    // Writes a record with a length of `size` bytes. Fails with
+    // `InvalidInput`, writing nothing, if the value is too long for it.
+    fn write_tlv(&mut self, size: usize, tag: u32, value: &[u8]) -> io::Result<usize> {
+        let len = value.len() as u64;
+        if len >> (size * 8) != 0 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, Error::OutOfRange))
+        }
+        let mut buf = [0u8; 4];
+        BigEndian::write_u32(&mut buf, len as u32);
+        let n = self.write_tag(tag)?;
+        self.get_mut().write_all(&buf[4 - size..])?;
+        self.get_mut().write_all(value)?;
+        Ok(n + size + value.len())
+    }
+
+    /// Writes a record, returning the number of bytes written.
+    pub fn write_tlv8(&mut self, tag: u32, value: &[u8]) -> io::Result<usize> {
+        self.write_tlv(1, tag, value)
+    }
+
+    /// Writes a record, returning the number of bytes written.
+    pub fn write_tlv16(&mut self, tag: u32, value: &[u8]) -> io::Result<usize> {
+        self.write_tlv(2, tag, value)
+    }
+
+    /// Writes a record, returning the number of bytes written.
+    pub fn write_tlv32(&mut self, tag: u32, value: &[u8]) -> io::Result<usize> {
+        self.write_tlv(4, tag, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let value = b"Hello, World";
+        let mut w = Writer::new(Vec::new());
+        assert_eq!(w.write_tlv8(0x1234, value).unwrap(), 2 + 1 + value.len());
+        w.write_tlv16(0x01, &[0x5a; 300]).unwrap();
+        w.write_tlv32(0x02, b"").unwrap();
+        let buf = w.into_inner();
+
+        // The slice reader agrees on the encoding.
+        let mut out = [0u8; 256];
+        assert_eq!(::Reader::new(&buf).read_tlv8(&mut out), Ok(Some((0x1234, &value[..]))));
+
+        let mut r = Reader::new(&buf[..]);
+        assert_eq!(r.read_tlv8().unwrap(), Some((0x1234, value.to_vec())));
+        assert_eq!(r.read_tlv16().unwrap(), Some((0x01, [0x5a; 300].to_vec())));
+        assert_eq!(r.read_tlv32().unwrap(), Some((0x02, Vec::new())));
+        assert_eq!(r.read_tlv8().unwrap(), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut w = Writer::new(Vec::new());
+        assert_eq!(w.write_tlv8(0x01, &[0; 256]).unwrap_err().kind(), ErrorKind::InvalidInput);
+        assert!(w.get_ref().is_empty());
+        w.write_tlv16(0x1234, b"Hello").unwrap();
+        let buf = w.into_inner();
+
+        // Cut off in the tag, the length and the value.
+        for &len in [1, 3, 5].iter() {
+            let mut r = Reader::new(&buf[..len]);
+            assert_eq!(r.read_tlv16().unwrap_err().kind(), ErrorKind::UnexpectedEof, "length {}", len);
+        }
+    }
+}
@@ -2,8 +2,14 @@
 
 extern crate byteorder;
 extern crate leb128;
+#[cfg(feature = "std")]
+extern crate std;
 
 use byteorder::{ByteOrder, BigEndian};
+use core::fmt;
+
+#[cfg(feature = "std")]
+pub mod io;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
@@ -11,6 +17,25 @@ pub enum Error {
     OutOfRange,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BufferTooShort => write!(f, "buffer too short"),
+            Error::OutOfRange => write!(f, "value out of range"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+#[cfg(feature = "std")]
+impl From<Error> for std::io::Error {
+    fn from(other: Error) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, other)
+    }
+}
+
 impl From<leb128::Error> for Error {
     fn from(other: leb128::Error) -> Error {
         match other {
@@ -90,6 +115,13 @@ impl<'a> Reader<'a> {
         Ok(Some(len))
     }
 
+    pub fn read_ref(&mut self, len: usize) -> Result<Option<&'a [u8]>, Error> {
+        if len > self.remaining() { return Ok(None) }
+        let value = &self.buf[self.pos..(self.pos + len)];
+        self.pos += len;
+        Ok(Some(value))
+    }
+
     pub fn read_lv8<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<&'b [u8]>, Error> {
         if let Some(len) = self.read_u8()? {
             let len = len as usize;
@@ -141,6 +173,26 @@ impl<'a> Reader<'a> {
         }        
     }
 
+    pub fn read_lv8_ref(&mut self) -> Result<Option<&'a [u8]>, Error> {
+        if let Some(len) = self.read_u8()? {
+            self.read_ref(len as usize)
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn read_tlv8_ref(&mut self) -> Result<Option<(u32, &'a [u8])>, Error> {
+        if let Some(tag) = self.read_tag()? {
+            if let Some(msg) = self.read_lv8_ref()? {
+                Ok(Some((tag, msg)))
+            } else {
+                Ok(None)
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn read_tlv16<'b>(&mut self, buf: &'b mut [u8]) -> Result<Option<(u32, &'b [u8])>, Error> {
        if let Some(tag) = self.read_tag()? {
             if let Some(msg) = self.read_lv16(buf)? {
@@ -340,6 +392,20 @@ mod tests {
         assert_eq!(msg, value);
     }
 
+    #[test]
+    fn test_tlv8_ref() {
+        let value = b"Hello, World";
+        let mut buf = [0u8; 256];
+        let mut w = Writer::new(&mut buf);
+        w.write_tlv8(0x1234, value).unwrap();
+        w.write_tlv8(0x01, b"").unwrap();
+        let mut r = Reader::new(w.as_ref());
+        assert_eq!(r.read_tlv8_ref(), Ok(Some((0x1234, &value[..]))));
+        assert_eq!(r.read_tlv8_ref(), Ok(Some((0x01, &b""[..]))));
+        assert_eq!(r.read_tlv8_ref(), Ok(None));
+        assert_eq!(r.remaining(), 0);
+    }
+
     #[test]
     fn test_tlv16() {
         let value = b"Hello, World";